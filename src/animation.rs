@@ -0,0 +1,357 @@
+use {
+    anyhow::{bail, Context, Result},
+    std::{f32::consts::PI, path::Path},
+};
+
+use crate::{algebra::Vec3, camera::Camera};
+
+// The shortest distance between a camera and its target that still defines a view direction.
+const MIN_VIEW_DISTANCE: f32 = 1e-4;
+
+/// A camera state pinned to a point in time along a `CameraPath`.
+#[derive(Debug, Copy, Clone)]
+pub struct Keyframe {
+    pub time: f32,
+    pub position: Vec3,
+    pub target: Vec3,
+    /// Vertical field of view in radians.
+    pub fov: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interpolation {
+    /// A uniform Catmull-Rom spline that passes through every keyframe with continuous velocity.
+    CatmullRom,
+    /// Cubic Bézier segments whose handles follow the neighbouring keyframes, scaled by the
+    /// keyframe times so that the speed stays continuous where keyframes are unevenly spaced.
+    Bezier,
+}
+
+pub enum CameraPath {
+    Keyframes {
+        keyframes: Vec<Keyframe>,
+        interpolation: Interpolation,
+    },
+    /// A full revolution around `center` at a fixed distance and altitude.
+    Turntable {
+        center: Vec3,
+        up: Vec3,
+        distance: f32,
+        azimuth: f32,
+        altitude: f32,
+        fov: f32,
+    },
+}
+
+impl CameraPath {
+    /// Creates a turntable that starts at the current state of `camera` and orbits around its
+    /// center.
+    pub fn turntable(camera: &Camera) -> CameraPath {
+        CameraPath::Turntable {
+            center: camera.center(),
+            up: camera.up(),
+            distance: camera.distance(),
+            azimuth: camera.azimuth(),
+            altitude: camera.altitude(),
+            fov: camera.fov(),
+        }
+    }
+
+    /// Loads a keyframe file. Every non-empty line that isn't a comment (`#`) holds a keyframe:
+    ///
+    ///     time  position.x position.y position.z  target.x target.y target.z  fov_degrees
+    ///
+    /// An optional `interpolation catmull-rom|bezier` line selects the spline type. Keyframes
+    /// are sorted by time.
+    pub fn load_keyframes(path: &Path) -> Result<CameraPath> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read keyframes from {}", path.display()))?;
+
+        let mut keyframes = Vec::new();
+        let mut interpolation = Interpolation::CatmullRom;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let context = || format!("{}:{}", path.display(), number + 1);
+            if let Some(kind) = line.strip_prefix("interpolation") {
+                interpolation = match kind.trim() {
+                    "catmull-rom" => Interpolation::CatmullRom,
+                    "bezier" => Interpolation::Bezier,
+                    other => bail!("{}: unknown interpolation \"{}\"", context(), other),
+                };
+                continue;
+            }
+            let values = line
+                .split_whitespace()
+                .map(str::parse::<f32>)
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(context)?;
+            if values.len() != 8 {
+                bail!("{}: expected 8 values, found {}", context(), values.len());
+            }
+            let keyframe = Keyframe {
+                time: values[0],
+                position: Vec3::new(values[1], values[2], values[3]),
+                target: Vec3::new(values[4], values[5], values[6]),
+                fov: values[7].to_radians(),
+            };
+            if (keyframe.target - keyframe.position).length() < MIN_VIEW_DISTANCE {
+                bail!("{}: the position and the target must differ", context());
+            }
+            keyframes.push(keyframe);
+        }
+        if keyframes.is_empty() {
+            bail!("{} contains no keyframes", path.display());
+        }
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+        Ok(CameraPath::Keyframes {
+            keyframes,
+            interpolation,
+        })
+    }
+
+    /// Returns the camera at normalized time `t` in [0, 1]. Keyframe times are remapped so that
+    /// the first keyframe is at 0 and the last one at 1.
+    pub fn camera_at(&self, t: f32) -> Camera {
+        match self {
+            CameraPath::Keyframes {
+                keyframes,
+                interpolation,
+            } => {
+                let key = sample_keyframes(keyframes, *interpolation, t);
+                let up = up_vector(key.target - key.position);
+                let mut camera = Camera::look_at(key.position, key.target, up);
+                camera.set_fov(key.fov);
+                camera
+            }
+            CameraPath::Turntable {
+                center,
+                up,
+                distance,
+                azimuth,
+                altitude,
+                fov,
+            } => {
+                let mut camera = Camera::with_spherical_coords(
+                    *center,
+                    *up,
+                    *distance,
+                    azimuth + 2. * PI * t,
+                    *altitude,
+                );
+                camera.set_fov(*fov);
+                camera
+            }
+        }
+    }
+}
+
+/// Returns the world up vector for a camera looking along `forward`, unless it looks (nearly)
+/// straight up or down, which would leave the camera basis undefined. The top of the image then
+/// faces -Z, like the default view.
+fn up_vector(forward: Vec3) -> Vec3 {
+    let up = Vec3::new(0., 1., 0.);
+    if forward.normalized().dot(&up).abs() > 0.999 {
+        Vec3::new(0., 0., -1.)
+    } else {
+        up
+    }
+}
+
+fn sample_keyframes(keyframes: &[Keyframe], interpolation: Interpolation, t: f32) -> Keyframe {
+    let first = keyframes[0].time;
+    let last = keyframes[keyframes.len() - 1].time;
+    if keyframes.len() == 1 || last <= first {
+        return keyframes[0];
+    }
+    let time = first + t.clamp(0., 1.) * (last - first);
+
+    // Find the segment [i, i + 1] that contains `time`.
+    let i = keyframes
+        .iter()
+        .rposition(|key| key.time <= time)
+        .unwrap_or(0)
+        .min(keyframes.len() - 2);
+    let (k1, k2) = (&keyframes[i], &keyframes[i + 1]);
+    let span = k2.time - k1.time;
+    let s = if span > 0. {
+        (time - k1.time) / span
+    } else {
+        0.
+    };
+
+    // The outer control points are duplicated at the ends of the path.
+    let k0 = &keyframes[i.saturating_sub(1)];
+    let k3 = &keyframes[(i + 2).min(keyframes.len() - 1)];
+
+    let w = match interpolation {
+        Interpolation::CatmullRom => catmull_rom_weights(s),
+        Interpolation::Bezier => bezier_weights(k0.time, k1.time, k2.time, k3.time, s),
+    };
+    let position =
+        w[0] * k0.position + w[1] * k1.position + w[2] * k2.position + w[3] * k3.position;
+    let mut target = w[0] * k0.target + w[1] * k1.target + w[2] * k2.target + w[3] * k3.target;
+    let fov = w[0] * k0.fov + w[1] * k1.fov + w[2] * k2.fov + w[3] * k3.fov;
+
+    // A spline that overshoots can bring the position onto the target, leaving no view direction.
+    // Aim along the view directions of the segment's keyframes instead.
+    if (target - position).length() < MIN_VIEW_DISTANCE {
+        let mut view = (1. - s) * (k1.target - k1.position) + s * (k2.target - k2.position);
+        if view.length() < MIN_VIEW_DISTANCE {
+            view = k1.target - k1.position;
+        }
+        target = position + view;
+    }
+    Keyframe {
+        time,
+        position,
+        target,
+        fov,
+    }
+}
+
+// The weights of the four control points of a uniform Catmull-Rom segment.
+fn catmull_rom_weights(t: f32) -> [f32; 4] {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    [
+        0.5 * (-t3 + 2. * t2 - t),
+        0.5 * (3. * t3 - 5. * t2 + 2.),
+        0.5 * (-3. * t3 + 4. * t2 + t),
+        0.5 * (t3 - t2),
+    ]
+}
+
+// The weights of the four keyframes around the segment [t1, t2] for a cubic Bézier segment. The
+// velocity at each end of the segment is the slope between its neighbours, (p2 - p0) / (t2 - t0)
+// at p1 and (p3 - p1) / (t3 - t1) at p2, and the handles lie a third of the segment along it.
+fn bezier_weights(t0: f32, t1: f32, t2: f32, t3: f32, t: f32) -> [f32; 4] {
+    let span = t2 - t1;
+    let handle_scale = |dt: f32| if dt > 0. { span / (3. * dt) } else { 0. };
+    let (c1, c2) = (handle_scale(t2 - t0), handle_scale(t3 - t1));
+    let [b0, b1, b2, b3] = bernstein_weights(t);
+    // The control points are p1, p1 + c1 (p2 - p0), p2 - c2 (p3 - p1) and p2.
+    [-b1 * c1, b0 + b1 + b2 * c2, b1 * c1 + b2 + b3, -b2 * c2]
+}
+
+// Cubic Bernstein basis polynomials.
+fn bernstein_weights(t: f32) -> [f32; 4] {
+    let s = 1. - t;
+    [s * s * s, 3. * s * s * t, 3. * s * t * t, t * t * t]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframe(time: f32, x: f32) -> Keyframe {
+        Keyframe {
+            time,
+            position: Vec3::new(x, 1., 2.),
+            target: Vec3::new(x, 0., 0.),
+            fov: 0.5 + x,
+        }
+    }
+
+    // Four keyframes that move one unit along X per unit of time, from time 2 to 5.
+    fn line() -> Vec<Keyframe> {
+        (0..4).map(|i| keyframe(2. + i as f32, i as f32)).collect()
+    }
+
+    #[test]
+    fn passes_through_keyframes_at_their_times() {
+        for interpolation in [Interpolation::CatmullRom, Interpolation::Bezier] {
+            for (i, t) in [0., 1. / 3., 2. / 3., 1.].into_iter().enumerate() {
+                let key = sample_keyframes(&line(), interpolation, t);
+                assert!((key.time - (2. + i as f32)).abs() < 1e-5);
+                assert!((key.position.x() - i as f32).abs() < 1e-5);
+                assert!((key.fov - (0.5 + i as f32)).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn interpolates_between_keyframes() {
+        // Catmull-Rom reproduces uniform motion away from the ends of the path.
+        let key = sample_keyframes(&line(), Interpolation::CatmullRom, 0.5);
+        assert!((key.time - 3.5).abs() < 1e-5);
+        assert!((key.position.x() - 1.5).abs() < 1e-5);
+
+        // So do Bézier handles that follow the neighbouring keyframes, even at the ends.
+        for t in [0.05, 0.4, 0.5, 0.95] {
+            let key = sample_keyframes(&line(), Interpolation::Bezier, t);
+            assert!((key.position.x() - 3. * t).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn bezier_keeps_the_speed_across_uneven_keyframes() {
+        // Keyframes that move one unit along X per unit of time, but at uneven times.
+        let keyframes = [0., 1., 3., 3.5].map(|x| keyframe(x, x));
+        for t in [0.1, 0.25, 0.5, 0.75, 0.9] {
+            let key = sample_keyframes(&keyframes, Interpolation::Bezier, t);
+            assert!((key.position.x() - key.time).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn clamps_outside_the_path() {
+        let key = sample_keyframes(&line(), Interpolation::CatmullRom, 2.);
+        assert!((key.position.x() - 3.).abs() < 1e-5);
+        let single = [keyframe(1., 4.)];
+        let key = sample_keyframes(&single, Interpolation::CatmullRom, 0.5);
+        assert_eq!(key.position.x(), 4.);
+    }
+
+    #[test]
+    fn rejects_keyframes_that_look_at_their_position() {
+        let path = std::env::temp_dir().join(format!("keyframes-{}.txt", std::process::id()));
+        std::fs::write(&path, "0  0 1 2  0 0 0  45\n1  1 1 1  1 1 1  45\n").unwrap();
+        assert!(CameraPath::load_keyframes(&path).is_err());
+    }
+
+    fn assert_finite(camera: &Camera) {
+        let uniforms: &[f32] = bytemuck::cast_slice(bytemuck::bytes_of(camera.uniforms()));
+        assert!(uniforms.iter().all(|value| value.is_finite()));
+    }
+
+    #[test]
+    fn keeps_a_view_direction_when_the_path_reaches_the_target() {
+        // Both keyframes look at the point halfway between them, which the path passes through.
+        let keyframe = |time: f32, x: f32| Keyframe {
+            time,
+            position: Vec3::new(x, 0., 0.),
+            target: Vec3::new(1., 0., 0.),
+            fov: 1.,
+        };
+        for interpolation in [Interpolation::CatmullRom, Interpolation::Bezier] {
+            let keyframes = vec![keyframe(0., 0.), keyframe(1., 2.)];
+            let key = sample_keyframes(&keyframes, interpolation, 0.5);
+            assert!((key.position.x() - 1.).abs() < 1e-5);
+            let path = CameraPath::Keyframes {
+                keyframes,
+                interpolation,
+            };
+            assert_finite(&path.camera_at(0.5));
+        }
+    }
+
+    #[test]
+    fn looks_straight_down_and_up() {
+        for y in [3., -3.] {
+            let path = CameraPath::Keyframes {
+                keyframes: vec![Keyframe {
+                    time: 0.,
+                    position: Vec3::new(0., y, 0.),
+                    target: Vec3::zero(),
+                    fov: 1.,
+                }],
+                interpolation: Interpolation::CatmullRom,
+            };
+            assert_finite(&path.camera_at(0.));
+        }
+    }
+}
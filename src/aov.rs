@@ -0,0 +1,89 @@
+use {
+    anyhow::{bail, Result},
+    bytemuck::{Pod, Zeroable},
+};
+
+/// An image that the renderer can show or write besides the beauty pass. The values must match
+/// the `AOV_*` constants in display.wgsl.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Aov {
+    /// The rendered image.
+    Beauty = 0,
+    /// World-space normals of the first hit.
+    Normal = 1,
+    /// Distance of the first hit along the viewing direction.
+    Depth = 2,
+    Albedo = 3,
+    MaterialId = 4,
+    ObjectId = 5,
+    /// Light that reached the camera straight from the sky or after a single bounce.
+    Direct = 6,
+    /// Light that reached the camera after two or more bounces.
+    Indirect = 7,
+    /// Samples traced for each pixel, relative to the number of frames, shown as a heatmap.
+    SampleCount = 8,
+}
+
+pub const AOVS: [Aov; 9] = [
+    Aov::Beauty,
+    Aov::Normal,
+    Aov::Depth,
+    Aov::Albedo,
+    Aov::MaterialId,
+    Aov::ObjectId,
+    Aov::Direct,
+    Aov::Indirect,
+    Aov::SampleCount,
+];
+
+impl Aov {
+    pub fn from_name(name: &str) -> Result<Aov> {
+        match AOVS.iter().find(|aov| aov.name() == name) {
+            Some(&aov) => Ok(aov),
+            None => bail!("unknown AOV \"{}\"", name),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Beauty => "beauty",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Albedo => "albedo",
+            Aov::MaterialId => "material-id",
+            Aov::ObjectId => "object-id",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::SampleCount => "sample-count",
+        }
+    }
+
+    /// The AOV that follows this one, wrapping around after the last.
+    pub fn next(self) -> Aov {
+        AOVS[(self as usize + 1) % AOVS.len()]
+    }
+
+    /// The AOV that precedes this one, wrapping around before the first.
+    pub fn previous(self) -> Aov {
+        AOVS[(self as usize + AOVS.len() - 1) % AOVS.len()]
+    }
+}
+
+/// The AOVs of one pixel, summed over its samples. Matches `PixelAovs` in shaders.wgsl.
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct PixelAovs {
+    pub normal: [f32; 3],
+    pub depth: f32,
+    pub albedo: [f32; 3],
+    /// The material ID in the low 16 bits and the object ID in the high 16 bits, all set where
+    /// the camera ray missed the scene.
+    pub ids: u32,
+    pub indirect: [f32; 3],
+    /// The number of samples whose camera ray hit the scene.
+    pub hit_count: f32,
+    /// The sum of the squared luminance of the radiance samples.
+    pub luminance_squared: f32,
+    pub _pad: [f32; 3],
+}
@@ -0,0 +1,91 @@
+use crate::wavefront::read_write_storage_entry;
+
+/// The kernels of the bidirectional path tracer (see bdpt.wgsl) and the buffer that the light of
+/// each frame is splatted into before it is added to the accumulated radiance.
+pub struct Bdpt {
+    trace: wgpu::ComputePipeline,
+    accumulate: wgpu::ComputePipeline,
+
+    // Like the megakernel's bind groups, these alternate between the two sample textures.
+    bind_groups: [wgpu::BindGroup; 2],
+    workgroup_size: [u32; 2],
+}
+
+impl Bdpt {
+    /// `trace_entries` are the bindings shared with the megakernel, and `create_bind_groups`
+    /// builds the two alternating bind groups for a layout that adds the given entries to them.
+    pub fn new(
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        pixel_count: u32,
+        workgroup_size: [u32; 2],
+        trace_entries: &[wgpu::BindGroupLayoutEntry],
+        create_bind_groups: impl FnOnce(
+            &wgpu::BindGroupLayout,
+            &[wgpu::BindGroupEntry],
+        ) -> [wgpu::BindGroup; 2],
+    ) -> Bdpt {
+        // One fixed-point value per color channel, which starts out cleared.
+        let splats = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("bdpt splats"),
+            size: pixel_count as u64 * 3 * 4,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let layout_entries = [trace_entries, &[read_write_storage_entry(16)]].concat();
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("bdpt"),
+            entries: &layout_entries,
+        });
+        let bind_groups = create_bind_groups(
+            &layout,
+            &[wgpu::BindGroupEntry {
+                binding: 16,
+                resource: splats.as_entire_binding(),
+            }],
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&layout],
+            ..Default::default()
+        });
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: shader_module,
+                entry_point,
+            })
+        };
+
+        Bdpt {
+            trace: pipeline("bdpt_cs"),
+            accumulate: pipeline("bdpt_accumulate_cs"),
+            bind_groups,
+            workgroup_size,
+        }
+    }
+
+    /// Records the kernels that trace and connect one pair of subpaths per pixel for frame
+    /// `frame_count`, and add the light they splat to the accumulated radiance.
+    pub fn encode(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        frame_count: u32,
+        width: u32,
+        height: u32,
+    ) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("bdpt pass"),
+            timestamp_writes: None,
+        });
+        pass.set_bind_group(0, &self.bind_groups[(frame_count % 2) as usize], &[]);
+        let [x, y] = self.workgroup_size;
+        // Every splat of the frame has landed once the first dispatch is done.
+        for pipeline in [&self.trace, &self.accumulate] {
+            pass.set_pipeline(pipeline);
+            pass.dispatch_workgroups(width.div_ceil(x), height.div_ceil(y), 1);
+        }
+    }
+}
@@ -0,0 +1,516 @@
+// Bidirectional path tracing (Veach, "Robust Monte Carlo Methods for Light Transport Simulation",
+// 1997, chapter 10, following pbrt-v3's implementation). Every pixel traces a subpath from the
+// camera and another from a light, then connects each prefix of one to each prefix of the other.
+// Every full path can be built in several such ways (strategies), and multiple importance
+// sampling weights each by how likely it is to find that path. Paths that the camera subpaths
+// alone hardly ever find, like caustics seen through glass, come from the light side instead:
+// light subpath vertices connect straight to the camera and are splatted onto the pixel they
+// project to, which may be any pixel of the image.
+//
+// Unlike the other integrators, the transport here is physically based: diffuse surfaces are
+// Lambertian, the point lights fall off with the square of the distance and there is no ambient
+// term. The sky is only reached by the camera subpaths. This module is compiled together with
+// shaders.wgsl and shares its intersection and sampling functions.
+
+// A vertex of a subpath. Its densities are per unit area (or per unit solid angle for the first
+// vertex past the camera or a light, seen from there), and its `beta` is the light or importance
+// that the subpath carries up to it, over the density with which it was sampled.
+struct Vertex {
+  point: vec3f,
+  kind: u32,
+  // The geometric normal of a surface vertex.
+  normal: vec3f,
+  material_index: u32,
+  beta: vec3f,
+  // The density of the vertex when sampled from the previous vertex of its subpath, and when
+  // sampled the other way, from the next vertex.
+  pdf_fwd: f32,
+  pdf_rev: f32,
+  // Set for mirrors and glass, which scatter in a single direction and can't be connected to.
+  delta: bool,
+  // Whether the subpath had dropped its secondary wavelengths (see `Wavelengths`) by the time it
+  // reached the vertex.
+  terminated: bool,
+}
+
+const VERTEX_CAMERA: u32 = 0u;
+const VERTEX_LIGHT: u32 = 1u;
+const VERTEX_SURFACE: u32 = 2u;
+
+// The subpaths are cut short past this many vertices, whatever the maximum path length.
+const MAX_SUBPATH_VERTICES: u32 = 10u;
+alias Subpath = array<Vertex, MAX_SUBPATH_VERTICES>;
+var<private> camera_path: Subpath;
+var<private> light_path: Subpath;
+// The shutter time of the paths of the invocation.
+var<private> path_time: f32;
+
+const UNIFORM_SPHERE_PDF: f32 = 0.07957747;
+
+fn sample_uniform_sphere(u: vec2f) -> vec3f {
+  let z = 1. - 2. * u.x;
+  let r = sqrt(max(1. - z * z, 0.));
+  let phi = TWO_PI * u.y;
+  return vec3(r * cos(phi), r * sin(phi), z);
+}
+
+// The light that reaches each pixel in the current frame, in fixed point so that it can be added
+// up atomically: the connections of every pixel may land on any other.
+@group(0) @binding(16) var<storage, read_write> splats: array<atomic<i32>>;
+const SPLAT_SCALE: f32 = 4096.;
+
+// Adds `rgb` to the light that reaches `pixel` in this frame. Stochastic rounding keeps the
+// fixed-point sum unbiased.
+fn splat(pixel: vec2u, rgb: vec3f) {
+  let index = 3u * (pixel.x + pixel.y * uniforms.width);
+  for (var c = 0u; c < 3u; c += 1u) {
+    let value = clamp(floor(rgb[c] * SPLAT_SCALE + rand_f32()), -2e9, 2e9);
+    if value != 0. {
+      atomicAdd(&splats[index + c], i32(value));
+    }
+  }
+}
+
+// The area that the image covers on the plane at distance 1 in front of the camera.
+fn image_area() -> f32 {
+  let size = vec2f(f32(uniforms.width), f32(uniforms.height));
+  let extent = 2. * uniforms.camera.tan_half_fov * vec2(size.x / size.y, 1.);
+  let pixel_extent = extent / (size - vec2(1.));
+  return size.x * size.y * pixel_extent.x * pixel_extent.y;
+}
+
+// The density per unit solid angle with which the camera samples `direction`: uniform over the
+// image plane.
+fn camera_pdf(direction: vec3f) -> f32 {
+  let cos_theta = image_point(uniforms.camera, direction).cos_theta;
+  if cos_theta <= 0. {
+    return 0.;
+  }
+  return 1. / (image_area() * cos_theta * cos_theta * cos_theta);
+}
+
+// The radiant intensity of a point light at the wavelengths of the path. In spectral mode its
+// color tints D65, like the sky.
+fn light_intensity(light: Light) -> vec3f {
+  let intensity = spectrum(light.color) * light.intensity;
+  if uniforms.spectral == 0u {
+    return intensity;
+  }
+  return intensity * d65(wavelengths.lambda);
+}
+
+fn is_delta(material: Material) -> bool {
+  return material.specular == 1u;
+}
+
+// The BSDF of the surface at `vertex` between the directions `wo` and `wi`, which both point away
+// from it. Only diffuse surfaces reflect light in more than one direction, and only on the side
+// it comes from.
+fn vertex_bsdf(vertex: Vertex, wo: vec3f, wi: vec3f) -> vec3f {
+  let material = materials[vertex.material_index];
+  if is_delta(material) || dot(vertex.normal, wo) * dot(vertex.normal, wi) <= 0. {
+    return vec3(0.);
+  }
+  return spectrum(material.color) / PI;
+}
+
+// The density per unit solid angle with which the surface at `vertex` samples `wi` when reached
+// from `wo`.
+fn vertex_bsdf_pdf(vertex: Vertex, wo: vec3f, wi: vec3f) -> f32 {
+  let material = materials[vertex.material_index];
+  if is_delta(material) || dot(vertex.normal, wo) * dot(vertex.normal, wi) <= 0. {
+    return 0.;
+  }
+  return abs(dot(vertex.normal, wi)) / PI;
+}
+
+struct VertexSample {
+  direction: vec3f,
+  // The BSDF times the cosine of `direction`, over `pdf_fwd`.
+  weight: vec3f,
+  // The densities per unit solid angle of sampling `direction`, and of sampling the incoming
+  // direction the other way. Both are 0 for mirrors and glass.
+  pdf_fwd: f32,
+  pdf_rev: f32,
+}
+
+// Samples the direction in which a subpath that arrived along `input_dir` leaves `vertex`.
+fn sample_vertex(vertex: Vertex, input_dir: vec3f) -> VertexSample {
+  let material = materials[vertex.material_index];
+  if is_delta(material) {
+    let sample = sample_material(input_dir, vertex.normal, material, false);
+    let weight = spectrum(material.color) * sample.weight;
+    return VertexSample(normalize(sample.direction), weight, 0., 0.);
+  }
+  let wo = -input_dir;
+  let normal = select(-vertex.normal, vertex.normal, dot(vertex.normal, wo) > 0.);
+  let sample = sample_cosine_hemisphere(normal);
+  if sample.pdf <= 0. {
+    return VertexSample(sample.direction, vec3(0.), 0., 0.);
+  }
+  let pdf_rev = abs(dot(normal, wo)) / PI;
+  return VertexSample(sample.direction, spectrum(material.color), sample.pdf, pdf_rev);
+}
+
+// Turns a density per unit solid angle at `origin` into one per unit area at `destination`.
+fn convert_density(pdf: f32, origin: vec3f, destination: Vertex) -> f32 {
+  let offset = destination.point - origin;
+  let distance_squared = dot(offset, offset);
+  if distance_squared == 0. {
+    return 0.;
+  }
+  var density = pdf / distance_squared;
+  if destination.kind == VERTEX_SURFACE {
+    density *= abs(dot(destination.normal, offset)) * inverseSqrt(distance_squared);
+  }
+  return density;
+}
+
+// The density per unit area with which `vertex`, reached from the point `previous`, samples
+// `next`. `previous` is ignored for the camera and the lights.
+fn vertex_pdf(vertex: Vertex, previous: vec3f, next: Vertex) -> f32 {
+  let to_next = next.point - vertex.point;
+  if dot(to_next, to_next) == 0. {
+    return 0.;
+  }
+  let wi = normalize(to_next);
+  var pdf: f32;
+  switch vertex.kind {
+    case VERTEX_CAMERA: {
+      pdf = camera_pdf(wi);
+    }
+    case VERTEX_LIGHT: {
+      pdf = UNIFORM_SPHERE_PDF;
+    }
+    default: {
+      pdf = vertex_bsdf_pdf(vertex, normalize(previous - vertex.point), wi);
+    }
+  }
+  return convert_density(pdf, vertex.point, next);
+}
+
+// Whether nothing lies between the points `origin` and `destination`. Glass blocks the way, as
+// connections can't pass through it.
+fn visible(origin: vec3f, destination: vec3f) -> bool {
+  let offset = destination - origin;
+  let distance = length(offset);
+  let hit = intersect_scene(Ray(origin, offset / distance, path_time));
+  return !is_intersection_valid(hit) || hit.t >= distance - EPSILON;
+}
+
+struct WalkEnd {
+  // The number of vertices of the subpath.
+  count: u32,
+  // The first intersection of the walk, for the first hit of the camera subpaths.
+  first_hit: Intersection,
+  // Whether the walk escaped to the sky, and the ray and throughput with which it did.
+  escaped: bool,
+  escaped_ray: Ray,
+  escaped_beta: vec3f,
+}
+
+// Extends the subpath `path` from its first vertex along `ray`, which that vertex sampled with
+// density `pdf` per unit solid angle, until it has `max_vertices` vertices, escapes or is
+// terminated by Russian roulette. Its bounces draw from the sample dimensions of the bounces
+// that follow `first_sample_bounce`.
+fn random_walk(
+  path: ptr<private, Subpath>,
+  first_ray: Ray,
+  beta: vec3f,
+  pdf: f32,
+  max_vertices: u32,
+  first_sample_bounce: u32,
+) -> WalkEnd {
+  var end = WalkEnd(1u, no_intersection(), false, first_ray, vec3(0.));
+  var ray = first_ray;
+  var throughput = beta;
+  var pdf_fwd = pdf;
+  while end.count < max_vertices {
+    let hit = intersect_scene(ray);
+    if end.count == 1u {
+      end.first_hit = hit;
+    }
+    if !is_intersection_valid(hit) {
+      end.escaped = true;
+      end.escaped_ray = ray;
+      end.escaped_beta = throughput;
+      break;
+    }
+    let material = materials[hit.material_index];
+    var vertex = Vertex(
+      point_on_ray(ray, hit.t), VERTEX_SURFACE, normalize(hit.normal), hit.material_index,
+      throughput, 0., 0., is_delta(material), wavelengths.secondary_terminated,
+    );
+    vertex.pdf_fwd = convert_density(pdf_fwd, ray.origin, vertex);
+    (*path)[end.count] = vertex;
+    end.count += 1u;
+    if end.count == max_vertices {
+      break;
+    }
+
+    let bounce = end.count - 2u;
+    start_bounce(first_sample_bounce + bounce);
+    let sample = sample_vertex(vertex, ray.direction);
+    throughput *= sample.weight;
+    pdf_fwd = sample.pdf_fwd;
+    let previous = end.count - 2u;
+    (*path)[previous].pdf_rev = convert_density(sample.pdf_rev, vertex.point, (*path)[previous]);
+    let survival_weight = russian_roulette_at(bounce, first_sample_bounce + bounce, throughput);
+    if survival_weight == 0. || all(throughput == vec3(0.)) {
+      break;
+    }
+    throughput *= survival_weight;
+    ray = Ray(vertex.point, sample.direction, ray.time);
+  }
+  return end;
+}
+
+// Traces the light subpath of the invocation from a light picked uniformly, which emits uniformly
+// in all directions. Returns its number of vertices. Its sample dimensions come after those of
+// the longest camera subpath.
+fn trace_light_subpath() -> u32 {
+  let light_count = uniforms.light_count;
+  if light_count == 0u {
+    return 0u;
+  }
+  start_bounce(uniforms.max_path_length);
+  let choice = min(u32(sample_1d() * f32(light_count)), light_count - 1u);
+  let choice_pdf = 1. / f32(light_count);
+  let light = lights[choice];
+  let direction = sample_uniform_sphere(sample_2d());
+
+  let intensity = light_intensity(light);
+  light_path[0] = Vertex(
+    light.position, VERTEX_LIGHT, vec3(0.), 0u, intensity / choice_pdf, choice_pdf, 0., false,
+    false,
+  );
+  let end = random_walk(
+    &light_path,
+    Ray(light.position, direction, path_time),
+    intensity / (choice_pdf * UNIFORM_SPHERE_PDF),
+    UNIFORM_SPHERE_PDF,
+    min(uniforms.max_path_length, MAX_SUBPATH_VERTICES),
+    uniforms.max_path_length + 1u,
+  );
+  return end.count;
+}
+
+fn remap0(pdf: f32) -> f32 {
+  return select(pdf, 1., pdf == 0.);
+}
+
+// The balance heuristic weight of the path made of the first `s` vertices of the light subpath
+// and the first `t` of the camera subpath. Each other strategy that could have built the path
+// moves the connection along it, so its density relative to this one is a product of ratios of
+// the reverse and forward densities of the vertices in between. Mirrors and glass can't be on
+// either side of a connection, and their densities stand for 1 in the products.
+fn mis_weight(s: u32, t: u32) -> f32 {
+  let qs = light_path[s - 1u];
+  let pt = camera_path[t - 1u];
+  let pt_minus = camera_path[max(t, 2u) - 2u];
+  let qs_minus = light_path[max(s, 2u) - 2u];
+
+  // The reverse densities of the vertices next to the connection depend on the other side, so
+  // they are set for this path and restored at the end.
+  camera_path[t - 1u].delta = false;
+  light_path[s - 1u].delta = false;
+  camera_path[t - 1u].pdf_rev = vertex_pdf(qs, qs_minus.point, pt);
+  if t > 1u {
+    camera_path[t - 2u].pdf_rev = vertex_pdf(pt, qs.point, pt_minus);
+  }
+  light_path[s - 1u].pdf_rev = vertex_pdf(pt, pt_minus.point, qs);
+  if s > 1u {
+    light_path[s - 2u].pdf_rev = vertex_pdf(qs, pt.point, qs_minus);
+  }
+
+  var sum = 0.;
+  var ratio = 1.;
+  for (var i = t - 1u; i > 0u; i -= 1u) {
+    ratio *= remap0(camera_path[i].pdf_rev) / remap0(camera_path[i].pdf_fwd);
+    if !camera_path[i].delta && !camera_path[i - 1u].delta {
+      sum += ratio;
+    }
+  }
+  // The point lights can't be hit, so no strategy starts the path from the camera alone.
+  ratio = 1.;
+  for (var i = s - 1u; i > 0u; i -= 1u) {
+    ratio *= remap0(light_path[i].pdf_rev) / remap0(light_path[i].pdf_fwd);
+    if !light_path[i].delta && !light_path[i - 1u].delta {
+      sum += ratio;
+    }
+  }
+
+  if t > 1u {
+    camera_path[t - 2u] = pt_minus;
+  }
+  if s > 1u {
+    light_path[s - 2u] = qs_minus;
+  }
+  camera_path[t - 1u] = pt;
+  light_path[s - 1u] = qs;
+  return 1. / (1. + sum);
+}
+
+// The light that a light picked uniformly sends to the first `t` vertices of the camera subpath
+// (s = 1). The light replaces the first vertex of the light subpath for the weight.
+fn connect_to_light(t: u32) -> vec3f {
+  let pt = camera_path[t - 1u];
+  if pt.delta {
+    return vec3(0.);
+  }
+  let light_count = uniforms.light_count;
+  let choice = min(u32(rand_f32() * f32(light_count)), light_count - 1u);
+  let choice_pdf = 1. / f32(light_count);
+  let light = lights[choice];
+  let to_light = light.position - pt.point;
+  let distance_squared = dot(to_light, to_light);
+  let wi = to_light * inverseSqrt(distance_squared);
+  let wo = normalize(camera_path[t - 2u].point - pt.point);
+  let intensity = light_intensity(light);
+  let irradiance = intensity * abs(dot(pt.normal, wi)) / (distance_squared * choice_pdf);
+  let contribution = pt.beta * vertex_bsdf(pt, wo, wi) * irradiance;
+  if all(contribution == vec3(0.)) || !visible(pt.point, light.position) {
+    return vec3(0.);
+  }
+
+  let light_vertex = light_path[0];
+  light_path[0] = Vertex(
+    light.position, VERTEX_LIGHT, vec3(0.), 0u, intensity / choice_pdf, choice_pdf, 0., false,
+    false,
+  );
+  let weight = mis_weight(1u, t);
+  light_path[0] = light_vertex;
+  return radiance_to_rgb(weight * contribution);
+}
+
+// The light carried by the first `s` vertices of the light subpath to the first `t` of the
+// camera subpath, for s, t >= 2.
+fn connect_vertices(s: u32, t: u32) -> vec3f {
+  let qs = light_path[s - 1u];
+  let pt = camera_path[t - 1u];
+  if qs.delta || pt.delta {
+    return vec3(0.);
+  }
+  let offset = qs.point - pt.point;
+  let distance_squared = dot(offset, offset);
+  let w = offset * inverseSqrt(distance_squared);
+  let camera_bsdf = vertex_bsdf(pt, normalize(camera_path[t - 2u].point - pt.point), w);
+  let light_bsdf = vertex_bsdf(qs, normalize(light_path[s - 2u].point - qs.point), -w);
+  let geometry = abs(dot(pt.normal, w) * dot(qs.normal, w)) / distance_squared;
+  var contribution = pt.beta * camera_bsdf * geometry * light_bsdf * qs.beta;
+  if pt.terminated && qs.terminated {
+    // Both subpaths made the hero wavelength stand for all three.
+    contribution /= 3.;
+  }
+  if all(contribution == vec3(0.)) || !visible(pt.point, qs.point) {
+    return vec3(0.);
+  }
+  return radiance_to_rgb(mis_weight(s, t) * contribution);
+}
+
+// Connects the first `s` vertices of the light subpath straight to the camera (t = 1) and
+// splats their light onto the pixel they are seen through.
+fn splat_light_subpath(s: u32) {
+  let qs = light_path[s - 1u];
+  if qs.delta {
+    return;
+  }
+  let camera = camera_path[0].point;
+  let to_camera = camera - qs.point;
+  let distance_squared = dot(to_camera, to_camera);
+  let wi = to_camera * inverseSqrt(distance_squared);
+  let image = image_point(uniforms.camera, -to_camera);
+  if image.cos_theta <= 0. {
+    return;
+  }
+  let wo = normalize(light_path[s - 2u].point - qs.point);
+  // The importance that the camera gives to the direction, over the density of the camera
+  // vertex per unit area at `qs`.
+  let cos_theta = image.cos_theta;
+  let importance = 1. / (image_area() * cos_theta * cos_theta * cos_theta * distance_squared);
+  let reflected = vertex_bsdf(qs, wo, wi) * abs(dot(qs.normal, wi));
+  let contribution = qs.beta * reflected * importance;
+  if all(contribution == vec3(0.)) || !visible(qs.point, camera) {
+    return;
+  }
+  let rgb = radiance_to_rgb(mis_weight(s, 1u) * contribution);
+  splat(vec2u(image.position), clamp_escaped_light(s - 1u, rgb));
+}
+
+// Clamps light that reached `pixel` through its own camera subpath after `bounces` surface hits
+// and records it in the AOVs. Splats from other pixels only count towards the beauty image.
+fn pixel_light(pixel: vec2u, bounces: u32, rgb: vec3f) -> vec3f {
+  let clamped = clamp_escaped_light(bounces, rgb);
+  add_escaped_light(pixel.x + pixel.y * uniforms.width, bounces, clamped);
+  return clamped;
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE_X, WORKGROUP_SIZE_Y)
+fn bdpt_cs(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= uniforms.width || id.y >= uniforms.height {
+    return;
+  }
+  let pixel = id.xy;
+  init_sampler(pixel);
+
+  // The camera subpath takes the sample dimensions of the other integrators.
+  let offset = sample_2d() - 0.5;
+  path_time = mix(uniforms.camera.shutter_open, uniforms.camera.shutter_close, sample_1d());
+  wavelengths = path_wavelengths(sample_1d(), false);
+  let camera_ray = generate_camera_ray(vec2f(pixel) + 0.5 + offset, path_time);
+  let ray = Ray(camera_ray.origin, normalize(camera_ray.direction), path_time);
+  camera_path[0] = Vertex(
+    ray.origin, VERTEX_CAMERA, vec3(0.), 0u, vec3(1.), 1., 0., false, false,
+  );
+  let camera_end = random_walk(
+    &camera_path,
+    ray,
+    vec3(1.),
+    camera_pdf(ray.direction),
+    min(uniforms.max_path_length + 1u, MAX_SUBPATH_VERTICES),
+    0u,
+  );
+  store_first_hit(pixel, ray, camera_end.first_hit);
+
+  var radiance = vec3(0.);
+  if camera_end.escaped {
+    // Only the camera subpaths reach the sky (s = 0), so they take all of its light.
+    let sky = camera_end.escaped_beta * sky_radiance(camera_end.escaped_ray);
+    radiance += pixel_light(pixel, camera_end.count - 1u, radiance_to_rgb(sky));
+  }
+
+  wavelengths.secondary_terminated = false;
+  let light_count = trace_light_subpath();
+  for (var t = 1u; t <= camera_end.count; t += 1u) {
+    for (var s = 1u; s <= light_count; s += 1u) {
+      // The point lights can't be seen by the camera.
+      if (s == 1u && t == 1u) || s + t - 1u > uniforms.max_path_length {
+        continue;
+      }
+      if t == 1u {
+        splat_light_subpath(s);
+      } else if s == 1u {
+        radiance += pixel_light(pixel, t - 1u, connect_to_light(t));
+      } else {
+        radiance += pixel_light(pixel, s + t - 2u, connect_vertices(s, t));
+      }
+    }
+  }
+  splat(pixel, radiance);
+}
+
+// Adds the light splatted onto every pixel in this frame to its sum, and clears it for the next
+// frame.
+@compute @workgroup_size(WORKGROUP_SIZE_X, WORKGROUP_SIZE_Y)
+fn bdpt_accumulate_cs(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= uniforms.width || id.y >= uniforms.height {
+    return;
+  }
+  let pixel = id.xy;
+  let index = 3u * (pixel.x + pixel.y * uniforms.width);
+  var radiance = vec3(0.);
+  for (var c = 0u; c < 3u; c += 1u) {
+    radiance[c] = f32(atomicExchange(&splats[index + c], 0)) / SPLAT_SCALE;
+  }
+  add_sample(pixel, radiance);
+}
@@ -0,0 +1,240 @@
+use {
+    anyhow::{bail, Context, Result},
+    std::path::{Path, PathBuf},
+};
+
+use crate::{algebra::Vec3, camera::Camera};
+
+const SLOT_COUNT: usize = 10;
+
+/// The orbit state of a camera, as accepted by `Camera::with_spherical_coords`.
+#[derive(Debug, Copy, Clone)]
+pub struct Bookmark {
+    center: Vec3,
+    up: Vec3,
+    distance: f32,
+    azimuth: f32,
+    altitude: f32,
+    fov: f32,
+}
+
+impl Bookmark {
+    pub fn from_camera(camera: &Camera) -> Bookmark {
+        Bookmark {
+            center: camera.center(),
+            up: camera.up(),
+            distance: camera.distance(),
+            azimuth: camera.azimuth(),
+            altitude: camera.altitude(),
+            fov: camera.fov(),
+        }
+    }
+
+    pub fn to_camera(self) -> Camera {
+        let mut camera = Camera::with_spherical_coords(
+            self.center,
+            self.up,
+            self.distance,
+            self.azimuth,
+            self.altitude,
+        );
+        camera.set_fov(self.fov);
+        camera
+    }
+}
+
+/// Formats the bookmark as it appears in a bookmarks file, without the slot number.
+impl std::fmt::Display for Bookmark {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {} {} {} {} {}",
+            self.center.x(),
+            self.center.y(),
+            self.center.z(),
+            self.up.x(),
+            self.up.y(),
+            self.up.z(),
+            self.distance,
+            self.azimuth,
+            self.altitude,
+            self.fov,
+        )
+    }
+}
+
+/// Numbered camera bookmarks that persist in a text file next to the scene.
+pub struct Bookmarks {
+    path: PathBuf,
+    slots: [Option<Bookmark>; SLOT_COUNT],
+}
+
+impl Bookmarks {
+    /// Returns the bookmarks stored next to `scene` (e.g. `models/cube.bookmarks` for
+    /// `models/cube.obj`). The file doesn't need to exist yet. Lines that can't be parsed are
+    /// reported and skipped, so storing a bookmark afterwards keeps the ones that could.
+    pub fn for_scene(scene: &Path) -> Bookmarks {
+        let mut bookmarks = Bookmarks {
+            path: scene.with_extension("bookmarks"),
+            slots: [None; SLOT_COUNT],
+        };
+        if bookmarks.path.exists() {
+            if let Err(error) = bookmarks.load() {
+                eprintln!("ignoring the bookmarks: {:#}", error);
+            }
+        }
+        bookmarks
+    }
+
+    pub fn get(&self, slot: usize) -> Option<&Bookmark> {
+        self.slots.get(slot)?.as_ref()
+    }
+
+    /// Stores `camera` in `slot` and writes all bookmarks back to disk.
+    pub fn store(&mut self, slot: usize, camera: &Camera) -> Result<()> {
+        self.slots[slot] = Some(Bookmark::from_camera(camera));
+        self.save()
+    }
+
+    /// Each line holds one bookmark:
+    ///
+    ///     slot  center.x center.y center.z  up.x up.y up.z  distance azimuth altitude fov
+    ///
+    /// Angles are in radians.
+    fn load(&mut self) -> Result<()> {
+        let text = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read {}", self.path.display()))?;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            match parse_bookmark(line) {
+                Ok((slot, bookmark)) => self.slots[slot] = Some(bookmark),
+                Err(error) => eprintln!(
+                    "ignoring {}:{}: {:#}",
+                    self.path.display(),
+                    number + 1,
+                    error
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut text = String::from(
+            "# slot  center.xyz  up.xyz  distance azimuth altitude fov (radians)\n",
+        );
+        for (slot, bookmark) in self.slots.iter().enumerate() {
+            let Some(bookmark) = bookmark else { continue };
+            text += &format!("{} {}\n", slot, bookmark);
+        }
+        std::fs::write(&self.path, text)
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+}
+
+fn parse_bookmark(line: &str) -> Result<(usize, Bookmark)> {
+    let (slot, values) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let slot = match slot.parse::<usize>() {
+        Ok(slot) if slot < SLOT_COUNT => slot,
+        _ => bail!("expected a slot from 0 to 9, found \"{}\"", slot),
+    };
+    let values = values
+        .split_whitespace()
+        .map(str::parse::<f32>)
+        .collect::<Result<Vec<_>, _>>()?;
+    if values.len() != 10 {
+        bail!("expected 10 values after the slot");
+    }
+    let bookmark = Bookmark {
+        center: Vec3::new(values[0], values[1], values[2]),
+        up: Vec3::new(values[3], values[4], values[5]),
+        distance: values[6],
+        azimuth: values[7],
+        altitude: values[8],
+        fov: values[9],
+    };
+    Ok((slot, bookmark))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A scene path in a directory of its own, so that tests running in parallel don't share
+    // bookmarks files.
+    fn scene_in_temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bookmarks-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("scene.obj")
+    }
+
+    fn load(name: &str, text: &str) -> Bookmarks {
+        let scene = scene_in_temp_dir(name);
+        std::fs::write(scene.with_extension("bookmarks"), text).unwrap();
+        Bookmarks::for_scene(&scene)
+    }
+
+    #[test]
+    fn round_trip() {
+        let scene = scene_in_temp_dir("round-trip");
+        let camera = Camera::with_spherical_coords(
+            Vec3::new(0.5, -1., 2.),
+            Vec3::new(0., 1., 0.),
+            3.25,
+            0.75,
+            -0.2,
+        );
+        let mut bookmarks = Bookmarks::for_scene(&scene);
+        bookmarks.store(7, &camera).unwrap();
+
+        let loaded = Bookmarks::for_scene(&scene);
+        let expected = Bookmark::from_camera(&camera).to_string();
+        assert_eq!(loaded.get(7).map(Bookmark::to_string), Some(expected));
+        assert!((0..SLOT_COUNT)
+            .filter(|&slot| slot != 7)
+            .all(|slot| loaded.get(slot).is_none()));
+    }
+
+    #[test]
+    fn rejects_bad_slots() {
+        let values = "0 0 0 0 1 0 2 0 0 1";
+        for slot in ["-1", "1.5", "10", "x"] {
+            let bookmarks = load("bad-slot", &format!("{} {}\n", slot, values));
+            assert!((0..SLOT_COUNT).all(|slot| bookmarks.get(slot).is_none()));
+        }
+        let bookmarks = load("good-slot", &format!("9 {}\n", values));
+        assert!(bookmarks.get(9).is_some());
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let bookmarks = load("malformed", "1 0 0 0 0 1 0 2 0 0 1\n2 0 0 0\n");
+        assert!(bookmarks.get(1).is_some());
+        assert!(bookmarks.get(2).is_none());
+    }
+
+    #[test]
+    fn store_keeps_the_valid_lines_of_a_malformed_file() {
+        let values = "0 0 0 0 1 0 2 0 0 1";
+        let text = format!("1 {}\n2 0 0 0\n3 {}\n", values, values);
+        let mut bookmarks = load("store-malformed", &text);
+        let camera = Camera::with_spherical_coords(
+            Vec3::new(0., 0., 0.),
+            Vec3::new(0., 1., 0.),
+            2.,
+            0.5,
+            0.25,
+        );
+        bookmarks.store(5, &camera).unwrap();
+
+        let loaded = Bookmarks::for_scene(&scene_in_temp_dir("store-malformed"));
+        for slot in [1, 3, 5] {
+            assert!(loaded.get(slot).is_some(), "slot {} was lost", slot);
+        }
+        assert!(loaded.get(2).is_none());
+    }
+}
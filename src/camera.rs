@@ -8,16 +8,36 @@ use {
 
 use crate::algebra::Vec3;
 
+/// The vertical field of view used when none is specified. A 90 degree FOV maps the image plane
+/// to [-1, 1] at unit focus distance.
+pub const DEFAULT_FOV: f32 = FRAC_PI_2;
+
+/// How the two eyes of a stereo camera share the image. The left eye always occupies the left or
+/// the top half.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum StereoMode {
+    Mono = 0,
+    SideBySide = 1,
+    OverUnder = 2,
+    /// Omni-directional stereo: an over-under pair of 360° equirectangular panoramas.
+    OmniDirectional = 3,
+}
+
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
 #[repr(C)]
 pub struct CameraUniforms {
     origin: Vec3,
-    _pad0: u32,
+    shutter_open: f32,
     u: Vec3,
-    _pad1: u32,
+    shutter_close: f32,
     v: Vec3,
     _pad2: u32,
     w: Vec3,
+    tan_half_fov: f32,
+    stereo_mode: u32,
+    eye_separation: f32,
+    convergence: f32,
     _pad3: u32,
 }
 
@@ -28,6 +48,9 @@ pub struct Camera {
     distance: f32,
     azimuth: f32,
     altitude: f32,
+    fov: f32,
+    // The distance at which the two eyes converge. Follows the orbit distance when unset.
+    convergence: Option<f32>,
 }
 
 impl Camera {
@@ -54,6 +77,8 @@ impl Camera {
             distance,
             azimuth,
             altitude,
+            fov: DEFAULT_FOV,
+            convergence: None,
         };
         camera.calculate_uniforms();
         camera
@@ -63,9 +88,57 @@ impl Camera {
         &self.uniforms
     }
 
+    pub fn center(&self) -> Vec3 {
+        self.center
+    }
+
+    pub fn up(&self) -> Vec3 {
+        self.up
+    }
+
+    pub fn distance(&self) -> f32 {
+        self.distance
+    }
+
+    pub fn azimuth(&self) -> f32 {
+        self.azimuth
+    }
+
+    pub fn altitude(&self) -> f32 {
+        self.altitude
+    }
+
+    /// The vertical field of view in radians.
+    pub fn fov(&self) -> f32 {
+        self.fov
+    }
+
+    /// Sets the interval, as fractions of the frame duration in [0, 1], during which the shutter
+    /// is open. Moving objects are blurred over this interval.
+    pub fn set_shutter(&mut self, open: f32, close: f32) {
+        self.uniforms.shutter_open = open.clamp(0., 1.);
+        self.uniforms.shutter_close = close.clamp(open, 1.);
+    }
+
+    /// Renders both eyes into the image according to `mode`. The eyes are `eye_separation` apart
+    /// (the interpupillary distance, in scene units) and converge at `convergence`, or at the
+    /// center of the orbit if that's `None`. Omni-directional stereo ignores the convergence.
+    pub fn set_stereo(&mut self, mode: StereoMode, eye_separation: f32, convergence: Option<f32>) {
+        self.uniforms.stereo_mode = mode as u32;
+        self.uniforms.eye_separation = eye_separation.max(0.);
+        self.convergence = convergence;
+        self.update_convergence();
+    }
+
+    pub fn set_fov(&mut self, fov: f32) {
+        self.fov = fov.clamp(1e-3, PI - 1e-3);
+        self.uniforms.tan_half_fov = (0.5 * self.fov).tan();
+    }
+
     pub fn zoom(&mut self, displacement: f32) {
         self.distance = (self.distance - displacement).max(0.0);  // Prevent negative distance
         self.uniforms.origin = self.center - self.distance * self.uniforms.w;
+        self.update_convergence();
     }
 
     pub fn pan(&mut self, du: f32, dv: f32) {
@@ -95,5 +168,11 @@ impl Camera {
         self.uniforms.u = u;
         self.uniforms.v = v;
         self.uniforms.w = w;
+        self.uniforms.tan_half_fov = (0.5 * self.fov).tan();
+        self.update_convergence();
+    }
+
+    fn update_convergence(&mut self) {
+        self.uniforms.convergence = self.convergence.unwrap_or(self.distance).max(1e-3);
     }
 }
@@ -0,0 +1,285 @@
+use {
+    bytemuck::{Pod, Zeroable},
+    std::time::{Duration, Instant},
+};
+
+use crate::render::PathTracer;
+
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+struct NoiseUniforms {
+    threshold: f32,
+    _pad: [u32; 3],
+}
+
+/// Counts the pixels of the accumulated image whose noise is above a threshold (see
+/// convergence.wgsl).
+pub struct NoiseEstimator {
+    pipeline: wgpu::ComputePipeline,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    // Index N % 2 reads the samples of frame N, like the trace bind groups.
+    sample_bind_groups: [wgpu::BindGroup; 2],
+    count_buffer: wgpu::Buffer,
+    size: [u32; 2],
+}
+
+impl NoiseEstimator {
+    /// Creates an estimator for the sums in `radiance_samples` and the squared luminance that
+    /// the trace pass accumulates in `aovs`.
+    pub fn new(
+        device: &wgpu::Device,
+        radiance_samples: &[wgpu::Texture; 2],
+        aovs: &wgpu::Buffer,
+    ) -> NoiseEstimator {
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("noise uniforms"),
+            size: std::mem::size_of::<NoiseUniforms>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let count_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("noisy pixel count"),
+            size: 4,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let buffer_entry = |binding, ty| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("noise"),
+            entries: &[
+                buffer_entry(0, wgpu::BufferBindingType::Uniform),
+                buffer_entry(1, wgpu::BufferBindingType::Storage { read_only: true }),
+                buffer_entry(2, wgpu::BufferBindingType::Storage { read_only: false }),
+            ],
+        });
+        let sample_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("noise samples"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }],
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("noise"),
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: aovs.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: count_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        let sample_bind_group = |samples: &wgpu::Texture| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("noise samples"),
+                layout: &sample_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(
+                        &samples.create_view(&Default::default()),
+                    ),
+                }],
+            })
+        };
+        let sample_bind_groups = [
+            sample_bind_group(&radiance_samples[1]),
+            sample_bind_group(&radiance_samples[0]),
+        ];
+
+        let shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("convergence"),
+            source: wgpu::ShaderSource::Wgsl(
+                include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/convergence.wgsl"))
+                    .into(),
+            ),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&layout, &sample_layout],
+            ..Default::default()
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("count noisy pixels"),
+            layout: Some(&pipeline_layout),
+            module: &shader_module,
+            entry_point: "count_noisy_pixels_cs",
+        });
+
+        NoiseEstimator {
+            pipeline,
+            uniform_buffer,
+            bind_group,
+            sample_bind_groups,
+            count_buffer,
+            size: [radiance_samples[0].width(), radiance_samples[0].height()],
+        }
+    }
+
+    /// Records the pass that counts the pixels of frame `frame_count` whose relative standard
+    /// error is above `threshold`. The count ends up in `count_buffer` as a single `u32`.
+    pub fn encode(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        frame_count: u32,
+        threshold: f32,
+    ) {
+        let uniforms = NoiseUniforms {
+            threshold,
+            _pad: [0; 3],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        encoder.clear_buffer(&self.count_buffer, 0, None);
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("noise pass"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.set_bind_group(1, &self.sample_bind_groups[(frame_count % 2) as usize], &[]);
+        pass.dispatch_workgroups(self.size[0].div_ceil(8), self.size[1].div_ceil(8), 1);
+    }
+
+    pub fn count_buffer(&self) -> &wgpu::Buffer {
+        &self.count_buffer
+    }
+}
+
+// Pixels need this many samples before their variance says anything about their noise.
+const MIN_SAMPLES_FOR_NOISE: u32 = 16;
+// Reading the noise back stalls the GPU, so it is only measured every few frames.
+const NOISE_CHECK_INTERVAL: u32 = 8;
+// The image has converged once this fraction of its pixels or fewer is still noisy, so that a
+// few fireflies don't keep it rendering forever.
+const NOISY_PIXEL_TOLERANCE: f32 = 0.001;
+
+/// When progressive rendering stops. It stops as soon as any of the conditions is met, and never
+/// without any.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SampleBudget {
+    pub samples_per_pixel: Option<u32>,
+    pub time: Option<Duration>,
+    /// The relative standard error of the mean below which a pixel counts as converged.
+    pub noise_threshold: Option<f32>,
+}
+
+/// Why progressive rendering stopped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    SampleCount,
+    TimeLimit,
+    Converged,
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            StopReason::SampleCount => "reached the sample count",
+            StopReason::TimeLimit => "ran out of time",
+            StopReason::Converged => "converged",
+        })
+    }
+}
+
+/// Tracks the rendering of one image against a sample budget.
+pub struct Progress {
+    budget: SampleBudget,
+    started: Instant,
+    paused_at: Option<Instant>,
+    stopped: Option<StopReason>,
+}
+
+impl Progress {
+    pub fn new(budget: SampleBudget) -> Progress {
+        Progress {
+            budget,
+            started: Instant::now(),
+            paused_at: None,
+            stopped: None,
+        }
+    }
+
+    /// Starts over with a new image, e.g. after the samples were reset.
+    pub fn restart(&mut self) {
+        self.started = self.paused_at.unwrap_or_else(Instant::now);
+        self.stopped = None;
+    }
+
+    /// Stops or resumes the clock of the time limit.
+    pub fn set_paused(&mut self, paused: bool) {
+        match (paused, self.paused_at) {
+            (true, None) => self.paused_at = Some(Instant::now()),
+            (false, Some(paused_at)) => {
+                self.started += paused_at.elapsed();
+                self.paused_at = None;
+            }
+            _ => (),
+        }
+    }
+
+    /// The time spent rendering the current image, not counting pauses.
+    pub fn elapsed(&self) -> Duration {
+        self.paused_at.unwrap_or_else(Instant::now) - self.started
+    }
+
+    pub fn stopped(&self) -> Option<StopReason> {
+        self.stopped
+    }
+
+    /// Checks the budget after `renderer` has traced a frame, and returns why rendering should
+    /// stop if it just ran out. With a time limit, this waits for the frame to finish so that
+    /// queued frames can't overrun it.
+    pub fn update(&mut self, renderer: &PathTracer) -> Option<StopReason> {
+        if self.stopped.is_some() {
+            return None;
+        }
+        let samples = renderer.samples_per_pixel();
+        let budget = self.budget;
+        if budget
+            .samples_per_pixel
+            .is_some_and(|target| samples >= target)
+        {
+            self.stopped = Some(StopReason::SampleCount);
+        } else if budget.time.is_some_and(|time| {
+            renderer.wait_for_gpu();
+            self.elapsed() >= time
+        }) {
+            self.stopped = Some(StopReason::TimeLimit);
+        } else if budget.noise_threshold.is_some_and(|threshold| {
+            samples >= MIN_SAMPLES_FOR_NOISE
+                && samples.is_multiple_of(NOISE_CHECK_INTERVAL)
+                && renderer.noisy_pixel_fraction(threshold) <= NOISY_PIXEL_TOLERANCE
+        }) {
+            self.stopped = Some(StopReason::Converged);
+        }
+        self.stopped
+    }
+}
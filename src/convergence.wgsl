@@ -0,0 +1,67 @@
+// Estimates how far the accumulated image is from converging, by counting the pixels whose noise
+// is still above a threshold.
+//
+// The noise of a pixel is the standard error of the mean of its radiance samples, estimated from
+// the running sums of their luminance and squared luminance, relative to that mean.
+
+struct NoiseUniforms {
+  // The relative standard error above which a pixel counts as noisy.
+  threshold: f32,
+}
+@group(0) @binding(0) var<uniform> noise: NoiseUniforms;
+
+// Must match `PixelAovs` in shaders.wgsl.
+struct PixelAovs {
+  normal: vec3f,
+  depth: f32,
+  albedo: vec3f,
+  ids: u32,
+  indirect: vec3f,
+  hit_count: f32,
+  luminance_squared: f32,
+}
+@group(0) @binding(1) var<storage> aovs: array<PixelAovs>;
+@group(0) @binding(2) var<storage, read_write> noisy_pixel_count: atomic<u32>;
+
+// The latest sum of radiance samples, with the sample count in alpha.
+@group(1) @binding(0) var radiance_samples: texture_2d<f32>;
+
+// Keeps the error of nearly black pixels from blowing up, as their noise is hardly visible.
+const DARK_LUMINANCE: f32 = 0.01;
+
+fn luminance(color: vec3f) -> f32 {
+  return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+fn relative_error(sum: vec4f, luminance_squared: f32) -> f32 {
+  let n = sum.a;
+  let mean = luminance(sum.rgb) / n;
+  // The unbiased variance of the samples, and from it the variance of their mean.
+  let variance = max(luminance_squared / n - mean * mean, 0.) * n / max(n - 1., 1.);
+  return sqrt(variance / n) / (mean + DARK_LUMINANCE);
+}
+
+var<workgroup> workgroup_count: atomic<u32>;
+
+@compute @workgroup_size(8, 8)
+fn count_noisy_pixels_cs(
+  @builtin(global_invocation_id) id: vec3u,
+  @builtin(local_invocation_index) local_index: u32,
+) {
+  // Count within the workgroup first, so that the global counter sees one atomic per workgroup.
+  let size = textureDimensions(radiance_samples);
+  if all(id.xy < size) {
+    let sum = textureLoad(radiance_samples, id.xy, 0);
+    let index = id.x + id.y * size.x;
+    // The noise of a pixel can't be estimated from fewer than two samples, and with none the error
+    // is NaN, which compares as below any threshold.
+    let noisy = sum.a < 2. || relative_error(sum, aovs[index].luminance_squared) > noise.threshold;
+    if noisy {
+      atomicAdd(&workgroup_count, 1u);
+    }
+  }
+  workgroupBarrier();
+  if local_index == 0u {
+    atomicAdd(&noisy_pixel_count, atomicLoad(&workgroup_count));
+  }
+}
@@ -0,0 +1,344 @@
+use bytemuck::{Pod, Zeroable};
+use wgpu::util::DeviceExt;
+
+use crate::camera::CameraUniforms;
+
+// Each à-trous iteration spreads the 5x5 kernel twice as far as the previous one.
+const ATROUS_STEP_SIZES: [u32; 5] = [1, 2, 4, 8, 16];
+
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+struct DenoiseUniforms {
+    camera: CameraUniforms,
+    previous_camera: CameraUniforms,
+    width: u32,
+    height: u32,
+    frame_count: u32,
+    camera_moved: u32,
+    discard_history: u32,
+    _pad: [u32; 3],
+}
+
+/// A spatio-temporal denoiser for the traced image (see denoise.wgsl). It keeps its own history of
+/// the radiance, which it reprojects when the camera moves instead of starting over like the
+/// accumulated samples do.
+pub struct Denoiser {
+    temporal: wgpu::ComputePipeline,
+    atrous: wgpu::ComputePipeline,
+    uniforms: DenoiseUniforms,
+    uniform_buffer: wgpu::Buffer,
+    // The step sizes live in one buffer, each at an offset allowed for dynamic uniform bindings.
+    step_stride: u32,
+
+    bind_group: wgpu::BindGroup,
+    // Index N % 2 reads the samples of frame N, like the trace bind groups.
+    sample_bind_groups: [wgpu::BindGroup; 2],
+    // Index N filters `filtered[N]` into the other texture.
+    filter_bind_groups: [wgpu::BindGroup; 2],
+
+    // The integrated textures are written every frame and copied to the history for the next.
+    integrated_color: wgpu::Texture,
+    integrated_moments: wgpu::Texture,
+    history_color: wgpu::Texture,
+    history_moments: wgpu::Texture,
+    previous_normal_depth: wgpu::Texture,
+    filtered: [wgpu::Texture; 2],
+}
+
+impl Denoiser {
+    /// Creates a denoiser for the sums in `radiance_samples`, guided by the textures that the
+    /// trace pass fills with the albedo, normal and depth of the first hit.
+    pub fn new(
+        device: &wgpu::Device,
+        radiance_samples: &[wgpu::Texture; 2],
+        albedo_guide: &wgpu::Texture,
+        normal_depth_guide: &wgpu::Texture,
+    ) -> Denoiser {
+        let (width, height) = (albedo_guide.width(), albedo_guide.height());
+        let texture = |label, format, usage| {
+            device.create_texture(&wgpu::TextureDescriptor {
+                label: Some(label),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage,
+                view_formats: &[],
+            })
+        };
+        let integrated = wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC;
+        let history = wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST;
+        let rgba32 = wgpu::TextureFormat::Rgba32Float;
+        let integrated_color = texture("integrated color", rgba32, integrated);
+        let integrated_moments = texture("integrated moments", rgba32, integrated);
+        let history_color = texture("color history", rgba32, history);
+        let history_moments = texture("moment history", rgba32, history);
+        let previous_normal_depth = texture("previous normal and depth", rgba32, history);
+        let filtered = [0, 1].map(|_| {
+            texture(
+                "filtered radiance",
+                wgpu::TextureFormat::Rgba16Float,
+                wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+            )
+        });
+
+        let uniforms = DenoiseUniforms {
+            width,
+            height,
+            discard_history: 1,
+            ..Zeroable::zeroed()
+        };
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("denoise uniforms"),
+            size: std::mem::size_of::<DenoiseUniforms>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let step_stride = device.limits().min_uniform_buffer_offset_alignment;
+        let mut steps = vec![0u8; step_stride as usize * ATROUS_STEP_SIZES.len()];
+        for (i, step) in ATROUS_STEP_SIZES.iter().enumerate() {
+            let offset = i * step_stride as usize;
+            steps[offset..offset + 4].copy_from_slice(bytemuck::bytes_of(step));
+        }
+        let step_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("a-trous step sizes"),
+            contents: &steps,
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("denoise"),
+            entries: &[
+                uniform_entry(0, false),
+                texture_entry(1),
+                texture_entry(2),
+                texture_entry(3),
+                texture_entry(4),
+                texture_entry(5),
+                storage_texture_entry(6, rgba32),
+                storage_texture_entry(7, rgba32),
+            ],
+        });
+        let sample_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("denoise samples"),
+            entries: &[texture_entry(0), texture_entry(1)],
+        });
+        let filter_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("denoise filter"),
+            entries: &[
+                uniform_entry(0, true),
+                texture_entry(1),
+                storage_texture_entry(2, wgpu::TextureFormat::Rgba16Float),
+            ],
+        });
+
+        let view = |texture: &wgpu::Texture| texture.create_view(&Default::default());
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("denoise"),
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                texture_binding(1, &view(albedo_guide)),
+                texture_binding(2, &view(normal_depth_guide)),
+                texture_binding(3, &view(&previous_normal_depth)),
+                texture_binding(4, &view(&history_color)),
+                texture_binding(5, &view(&history_moments)),
+                texture_binding(6, &view(&integrated_color)),
+                texture_binding(7, &view(&integrated_moments)),
+            ],
+        });
+        let sample_bind_group = |new_samples: &wgpu::Texture, old_samples: &wgpu::Texture| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("denoise samples"),
+                layout: &sample_layout,
+                entries: &[
+                    texture_binding(0, &view(new_samples)),
+                    texture_binding(1, &view(old_samples)),
+                ],
+            })
+        };
+        let sample_bind_groups = [
+            sample_bind_group(&radiance_samples[1], &radiance_samples[0]),
+            sample_bind_group(&radiance_samples[0], &radiance_samples[1]),
+        ];
+        let filter_bind_group = |input: &wgpu::Texture, output: &wgpu::Texture| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("denoise filter"),
+                layout: &filter_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                            buffer: &step_buffer,
+                            offset: 0,
+                            size: wgpu::BufferSize::new(4),
+                        }),
+                    },
+                    texture_binding(1, &view(input)),
+                    texture_binding(2, &view(output)),
+                ],
+            })
+        };
+        let filter_bind_groups = [
+            filter_bind_group(&filtered[0], &filtered[1]),
+            filter_bind_group(&filtered[1], &filtered[0]),
+        ];
+
+        let shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("denoise"),
+            source: wgpu::ShaderSource::Wgsl(
+                include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/denoise.wgsl")).into(),
+            ),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&layout, &sample_layout, &filter_layout],
+            ..Default::default()
+        });
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &shader_module,
+                entry_point,
+            })
+        };
+
+        Denoiser {
+            temporal: pipeline("temporal_cs"),
+            atrous: pipeline("atrous_cs"),
+            uniforms,
+            uniform_buffer,
+            step_stride,
+            bind_group,
+            sample_bind_groups,
+            filter_bind_groups,
+            integrated_color,
+            integrated_moments,
+            history_color,
+            history_moments,
+            previous_normal_depth,
+            filtered,
+        }
+    }
+
+    /// The denoised radiance of the latest frame, as an average rather than a sum of samples.
+    pub fn output(&self) -> &wgpu::Texture {
+        &self.filtered[ATROUS_STEP_SIZES.len() % 2]
+    }
+
+    /// Makes the next frame start from the accumulated samples alone. The history is only
+    /// maintained while the denoiser runs, so it is stale after it has been switched off.
+    pub fn discard_history(&mut self) {
+        self.uniforms.discard_history = 1;
+    }
+
+    /// Records the passes that denoise frame `frame_count`, traced with `camera`.
+    /// `normal_depth_guide` must be the guide texture the denoiser was created with.
+    pub fn encode(
+        &mut self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        camera: &CameraUniforms,
+        frame_count: u32,
+        normal_depth_guide: &wgpu::Texture,
+    ) {
+        let moved = bytemuck::bytes_of(camera) != bytemuck::bytes_of(&self.uniforms.camera);
+        self.uniforms.camera_moved = moved as u32;
+        self.uniforms.previous_camera = self.uniforms.camera;
+        self.uniforms.camera = *camera;
+        self.uniforms.frame_count = frame_count;
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&self.uniforms));
+        self.uniforms.discard_history = 0;
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("denoise pass"),
+            timestamp_writes: None,
+        });
+        let workgroups = [self.uniforms.width, self.uniforms.height].map(|n| n.div_ceil(8));
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.set_bind_group(1, &self.sample_bind_groups[(frame_count % 2) as usize], &[]);
+
+        // The temporal pass writes the input of the first iteration into `filtered[0]`.
+        pass.set_pipeline(&self.temporal);
+        pass.set_bind_group(2, &self.filter_bind_groups[1], &[0]);
+        pass.dispatch_workgroups(workgroups[0], workgroups[1], 1);
+
+        pass.set_pipeline(&self.atrous);
+        for i in 0..ATROUS_STEP_SIZES.len() {
+            let offset = i as u32 * self.step_stride;
+            pass.set_bind_group(2, &self.filter_bind_groups[i % 2], &[offset]);
+            pass.dispatch_workgroups(workgroups[0], workgroups[1], 1);
+        }
+        drop(pass);
+
+        let size = self.integrated_color.size();
+        for (source, destination) in [
+            (&self.integrated_color, &self.history_color),
+            (&self.integrated_moments, &self.history_moments),
+            (normal_depth_guide, &self.previous_normal_depth),
+        ] {
+            encoder.copy_texture_to_texture(
+                source.as_image_copy(),
+                destination.as_image_copy(),
+                size,
+            );
+        }
+    }
+}
+
+fn uniform_entry(binding: u32, has_dynamic_offset: bool) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn texture_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            view_dimension: wgpu::TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+fn storage_texture_entry(
+    binding: u32,
+    format: wgpu::TextureFormat,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::StorageTexture {
+            access: wgpu::StorageTextureAccess::WriteOnly,
+            format,
+            view_dimension: wgpu::TextureViewDimension::D2,
+        },
+        count: None,
+    }
+}
+
+fn texture_binding(binding: u32, view: &wgpu::TextureView) -> wgpu::BindGroupEntry<'_> {
+    wgpu::BindGroupEntry {
+        binding,
+        resource: wgpu::BindingResource::TextureView(view),
+    }
+}
@@ -0,0 +1,338 @@
+// Spatio-temporal denoising of the traced image, after "Spatiotemporal Variance-Guided Filtering"
+// (Schied et al. 2017):
+//
+//   temporal  reprojects the history of the previous frame, adds the latest sample to it and
+//             estimates the variance of the luminance
+//   atrous    an edge-avoiding à-trous wavelet filter, run several times with growing step sizes
+//
+// The edges are found with the albedo, normal and depth guides written by the trace pass. The
+// variance steers how strongly luminance differences are smoothed, so the filter backs off as the
+// samples converge.
+
+// Must match `CameraUniforms` in shaders.wgsl.
+struct CameraUniforms {
+  origin: vec3f,
+  shutter_open: f32,
+  u: vec3f,
+  shutter_close: f32,
+  v: vec3f,
+  w: vec3f,
+  tan_half_fov: f32,
+  stereo_mode: u32,
+  eye_separation: f32,
+  convergence: f32,
+}
+
+const STEREO_MONO: u32 = 0u;
+
+struct DenoiseUniforms {
+  // The cameras that traced the latest and the previously denoised frame.
+  camera: CameraUniforms,
+  previous_camera: CameraUniforms,
+  width: u32,
+  height: u32,
+  frame_count: u32,
+  camera_moved: u32,
+  // Set when the history doesn't come from the previous frame, e.g. after enabling the denoiser.
+  discard_history: u32,
+  _pad0: u32,
+  _pad1: u32,
+  _pad2: u32,
+}
+@group(0) @binding(0) var<uniform> uniforms: DenoiseUniforms;
+@group(0) @binding(1) var albedo_guide: texture_2d<f32>;
+@group(0) @binding(2) var normal_depth_guide: texture_2d<f32>;
+@group(0) @binding(3) var previous_normal_depth: texture_2d<f32>;
+// The color history holds the integrated radiance and its sample count, the moment history the
+// first and second moments of the luminance and their sample count.
+@group(0) @binding(4) var history_color: texture_2d<f32>;
+@group(0) @binding(5) var history_moments: texture_2d<f32>;
+@group(0) @binding(6) var integrated_color: texture_storage_2d<rgba32float, write>;
+@group(0) @binding(7) var integrated_moments: texture_storage_2d<rgba32float, write>;
+
+@group(1) @binding(0) var radiance_samples_new: texture_2d<f32>;
+@group(1) @binding(1) var radiance_samples_old: texture_2d<f32>;
+
+// The filtered radiance in rgb and its variance in a.
+@group(2) @binding(0) var<uniform> step_size: u32;
+@group(2) @binding(1) var filter_input: texture_2d<f32>;
+@group(2) @binding(2) var filter_output: texture_storage_2d<rgba16float, write>;
+
+// After a camera move the reprojected history is resampled, which blurs it a little. Capping its
+// weight lets the new samples take over quickly.
+const MAX_REPROJECTED_HISTORY: f32 = 32.;
+// Below this many samples, the moments are too noisy and the variance is estimated spatially.
+const MIN_TEMPORAL_MOMENTS: f32 = 4.;
+
+const SIGMA_LUMINANCE: f32 = 4.;
+const SIGMA_NORMAL: f32 = 128.;
+const SIGMA_ALBEDO: f32 = 0.1;
+const DEPTH_EPSILON: f32 = 1e-3;
+
+fn luminance(color: vec3f) -> f32 {
+  return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+fn image_size() -> vec2i {
+  return vec2i(i32(uniforms.width), i32(uniforms.height));
+}
+
+fn in_image(texel: vec2i) -> bool {
+  return all(texel >= vec2(0)) && all(texel < image_size());
+}
+
+// The radiance sample traced for `texel` in the latest frame. Pixels that adaptive sampling
+// skipped have converged, so the average of their samples stands in for a new one.
+fn frame_sample(texel: vec2i) -> vec3f {
+  let new_sum = textureLoad(radiance_samples_new, texel, 0);
+  var old_sum = vec4(0.);
+  if uniforms.frame_count > 1u {
+    old_sum = textureLoad(radiance_samples_old, texel, 0);
+  }
+  if new_sum.a == old_sum.a {
+    return new_sum.rgb / max(new_sum.a, 1.);
+  }
+  return new_sum.rgb - old_sum.rgb;
+}
+
+// The direction of the ray through the viewport position `pixel`, scaled to unit depth. This
+// matches the mono camera of `generate_camera_ray` in shaders.wgsl.
+fn view_direction(camera: CameraUniforms, pixel: vec2f) -> vec3f {
+  let size = vec2f(image_size());
+  var uv = pixel / (size - vec2(1.));
+  uv = (2. * uv - vec2(1.)) * vec2(size.x / size.y, -1.) * camera.tan_half_fov;
+  return mat3x3(camera.u, camera.v, camera.w) * vec3(uv, 1.);
+}
+
+// Projects the surface seen at `pixel` into the previous camera. Returns its viewport position
+// and the depth at which the previous frame should have seen it (0 for the sky), or a negative
+// depth if it was behind the previous camera.
+fn reproject(pixel: vec2f, depth: f32) -> vec3f {
+  let camera = uniforms.camera;
+  let previous = uniforms.previous_camera;
+  let direction = view_direction(camera, pixel);
+
+  // The sky is infinitely far away, so only the direction matters.
+  var local: vec3f;
+  let to_previous = transpose(mat3x3(previous.u, previous.v, previous.w));
+  if depth > 0. {
+    local = to_previous * (camera.origin + depth * direction - previous.origin);
+  } else {
+    local = to_previous * direction;
+  }
+  if local.z <= 0. {
+    return vec3(0., 0., -1.);
+  }
+
+  let size = vec2f(image_size());
+  let uv = local.xy / local.z / (vec2(size.x / size.y, -1.) * previous.tan_half_fov);
+  let previous_pixel = (0.5 * uv + 0.5) * (size - vec2(1.));
+  return vec3(previous_pixel, select(0., local.z, depth > 0.));
+}
+
+// Whether the history at `texel` shows the same surface as the current pixel.
+fn is_history_valid(texel: vec2i, depth: f32, normal: vec3f) -> bool {
+  if !in_image(texel) || textureLoad(history_color, texel, 0).a == 0. {
+    return false;
+  }
+  let previous = textureLoad(previous_normal_depth, texel, 0);
+  if depth == 0. {
+    return previous.w == 0.;
+  }
+  return previous.w > 0. && abs(previous.w - depth) < 0.05 * depth
+    && dot(previous.xyz, normal) > 0.9;
+}
+
+struct History {
+  color: vec4f,
+  moments: vec3f,
+}
+
+// Bilinearly interpolates the history at the previous viewport position, skipping the texels that
+// fail the consistency test. Returns an empty history if none passes.
+fn reprojected_history(pixel: vec2f, guide: vec4f) -> History {
+  var history = History(vec4(0.), vec3(0.));
+  let previous = reproject(pixel, guide.w);
+  if previous.z < 0. {
+    return history;
+  }
+
+  // Texel centers are at half-integer viewport positions.
+  let position = previous.xy - 0.5;
+  let base = vec2i(floor(position));
+  let f = fract(position);
+  var total_weight = 0.;
+  for (var i = 0; i < 4; i += 1) {
+    let offset = vec2(i & 1, i >> 1u);
+    let texel = base + offset;
+    if !is_history_valid(texel, previous.z, guide.xyz) {
+      continue;
+    }
+    let weights = select(1. - f, f, offset == vec2(1));
+    let weight = weights.x * weights.y;
+    history.color += weight * textureLoad(history_color, texel, 0);
+    history.moments += weight * textureLoad(history_moments, texel, 0).xyz;
+    total_weight += weight;
+  }
+  if total_weight < 1e-3 {
+    return History(vec4(0.), vec3(0.));
+  }
+  history.color /= total_weight;
+  history.moments /= total_weight;
+  history.color.a = min(history.color.a, MAX_REPROJECTED_HISTORY);
+  history.moments.z = min(history.moments.z, MAX_REPROJECTED_HISTORY);
+  return history;
+}
+
+fn previous_history(pixel: vec2u, guide: vec4f) -> History {
+  if uniforms.discard_history == 1u {
+    return History(vec4(0.), vec3(0.));
+  }
+  if uniforms.camera_moved == 0u {
+    return History(
+      textureLoad(history_color, pixel, 0),
+      textureLoad(history_moments, pixel, 0).xyz,
+    );
+  }
+  // Stereo images aren't reprojected, so their history starts over when the camera moves.
+  if uniforms.camera.stereo_mode != STEREO_MONO
+    || uniforms.previous_camera.stereo_mode != STEREO_MONO {
+    return History(vec4(0.), vec3(0.));
+  }
+  return reprojected_history(vec2f(pixel) + 0.5, guide);
+}
+
+// The variance of the luminance over the 3x3 neighborhood of the latest samples.
+fn spatial_variance(pixel: vec2i) -> f32 {
+  var sum = 0.;
+  var sum_of_squares = 0.;
+  var count = 0.;
+  for (var y = -1; y <= 1; y += 1) {
+    for (var x = -1; x <= 1; x += 1) {
+      let texel = pixel + vec2(x, y);
+      if in_image(texel) {
+        let l = luminance(frame_sample(texel));
+        sum += l;
+        sum_of_squares += l * l;
+        count += 1.;
+      }
+    }
+  }
+  let mean = sum / count;
+  return max(sum_of_squares / count - mean * mean, 0.);
+}
+
+@compute @workgroup_size(8, 8)
+fn temporal_cs(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= uniforms.width || id.y >= uniforms.height {
+    return;
+  }
+  let pixel = id.xy;
+  let guide = textureLoad(normal_depth_guide, pixel, 0);
+  let sample = frame_sample(vec2i(pixel));
+  let history = previous_history(pixel, guide);
+
+  // Integrate the sample as a running average over the history.
+  var count = history.color.a + 1.;
+  var color = mix(history.color.rgb, sample, 1. / count);
+  if f32(uniforms.frame_count) > count {
+    // More samples were accumulated since the camera last moved than the history holds (e.g. the
+    // denoiser was just enabled), so their average is the better estimate.
+    count = f32(uniforms.frame_count);
+    let sum = textureLoad(radiance_samples_new, pixel, 0);
+    color = sum.rgb / sum.a;
+  }
+  let l = luminance(sample);
+  let moment_count = history.moments.z + 1.;
+  let moments = mix(history.moments.xy, vec2(l, l * l), 1. / moment_count);
+  textureStore(integrated_color, pixel, vec4(color, count));
+  textureStore(integrated_moments, pixel, vec4(moments, moment_count, 0.));
+
+  var variance = max(moments.y - moments.x * moments.x, 0.);
+  if moment_count < MIN_TEMPORAL_MOMENTS {
+    variance = spatial_variance(vec2i(pixel));
+  }
+  // The filter works on the average, whose variance shrinks with the number of samples.
+  textureStore(filter_output, pixel, vec4(color, variance / count));
+}
+
+// The 1D weights of the B3 spline kernel of the à-trous transform.
+var<private> kernel: array<f32, 5> = array(1. / 16., 1. / 4., 3. / 8., 1. / 4., 1. / 16.);
+
+// Blurs the variance over a 3x3 neighborhood, which keeps the luminance weights from locking
+// onto the noise of single pixels.
+fn filtered_variance(pixel: vec2i) -> f32 {
+  var sum = 0.;
+  var total_weight = 0.;
+  for (var y = -1; y <= 1; y += 1) {
+    for (var x = -1; x <= 1; x += 1) {
+      let texel = pixel + vec2(x, y);
+      if in_image(texel) {
+        let weight = kernel[x + 2] * kernel[y + 2];
+        sum += weight * textureLoad(filter_input, texel, 0).a;
+        total_weight += weight;
+      }
+    }
+  }
+  return sum / total_weight;
+}
+
+fn guide_depth(texel: vec2i) -> f32 {
+  return textureLoad(normal_depth_guide, clamp(texel, vec2(0), image_size() - 1), 0).w;
+}
+
+@compute @workgroup_size(8, 8)
+fn atrous_cs(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= uniforms.width || id.y >= uniforms.height {
+    return;
+  }
+  let pixel = vec2i(id.xy);
+  let center = textureLoad(filter_input, pixel, 0);
+  let guide = textureLoad(normal_depth_guide, pixel, 0);
+  let albedo = textureLoad(albedo_guide, pixel, 0).rgb;
+  let l = luminance(center.rgb);
+  let sigma_l = SIGMA_LUMINANCE * sqrt(filtered_variance(pixel)) + 1e-6;
+
+  // Depth differences are measured against the local slope of the surface, so that planes seen at
+  // grazing angles are still smoothed.
+  let depth_gradient = 0.5 * vec2(
+    guide_depth(pixel + vec2(1, 0)) - guide_depth(pixel - vec2(1, 0)),
+    guide_depth(pixel + vec2(0, 1)) - guide_depth(pixel - vec2(0, 1)),
+  );
+
+  var color = vec3(0.);
+  var variance = 0.;
+  var total_weight = 0.;
+  for (var y = -2; y <= 2; y += 1) {
+    for (var x = -2; x <= 2; x += 1) {
+      let offset = vec2(x, y) * i32(step_size);
+      let texel = pixel + offset;
+      if !in_image(texel) {
+        continue;
+      }
+      let tap = textureLoad(filter_input, texel, 0);
+      let tap_guide = textureLoad(normal_depth_guide, texel, 0);
+
+      // The sky only blends with the sky, and surfaces only with surfaces.
+      var weight = kernel[x + 2] * kernel[y + 2];
+      if guide.w == 0. || tap_guide.w == 0. {
+        weight *= select(0., 1., guide.w == tap_guide.w);
+      } else {
+        let depth_difference = abs(guide.w - tap_guide.w);
+        let expected = abs(dot(depth_gradient, vec2f(offset)));
+        weight *= pow(max(dot(guide.xyz, tap_guide.xyz), 0.), SIGMA_NORMAL);
+        weight *= exp(-depth_difference / (expected + DEPTH_EPSILON));
+      }
+      let tap_albedo = textureLoad(albedo_guide, texel, 0).rgb;
+      weight *= exp(-length(albedo - tap_albedo) / SIGMA_ALBEDO);
+      weight *= exp(-abs(l - luminance(tap.rgb)) / sigma_l);
+
+      color += weight * tap.rgb;
+      variance += weight * weight * tap.a;
+      total_weight += weight;
+    }
+  }
+  // The center tap always has a positive weight.
+  let filtered = vec4(color / total_weight, variance / (total_weight * total_weight));
+  textureStore(filter_output, pixel, filtered);
+}
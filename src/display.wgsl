@@ -0,0 +1,231 @@
+// Turns the accumulated radiance into display values: averaging, exposure, tone mapping and the
+// sRGB transfer function. This pass only reads the samples, so none of its settings require the
+// image to be traced again.
+
+struct DisplayUniforms {
+  frame_count: u32,
+  exposure: f32,
+  tonemap: u32,
+  white_point: f32,
+  zoom_center: vec2f,
+  zoom: f32,
+  // 1 if `radiance_samples` holds the denoised average rather than a sum of samples.
+  denoised: u32,
+  aov: u32,
+  _pad0: u32,
+  _pad1: u32,
+  _pad2: u32,
+}
+@group(0) @binding(0) var<uniform> display: DisplayUniforms;
+// The sum of the radiance samples of each pixel, with the sample count in alpha.
+@group(0) @binding(1) var radiance_samples: texture_2d<f32>;
+
+// Must match `PixelAovs` in shaders.wgsl.
+struct PixelAovs {
+  normal: vec3f,
+  depth: f32,
+  albedo: vec3f,
+  ids: u32,
+  indirect: vec3f,
+  hit_count: f32,
+  luminance_squared: f32,
+}
+@group(0) @binding(3) var<storage> aovs: array<PixelAovs>;
+
+// Output variables, must match `Aov` in aov.rs.
+const AOV_BEAUTY: u32 = 0u;
+const AOV_NORMAL: u32 = 1u;
+const AOV_DEPTH: u32 = 2u;
+const AOV_ALBEDO: u32 = 3u;
+const AOV_MATERIAL_ID: u32 = 4u;
+const AOV_OBJECT_ID: u32 = 5u;
+const AOV_DIRECT: u32 = 6u;
+const AOV_INDIRECT: u32 = 7u;
+const AOV_SAMPLE_COUNT: u32 = 8u;
+
+// Tone mapping operators, must match `Tonemap` in tonemap.rs.
+const TONEMAP_CLAMP: u32 = 0u;
+const TONEMAP_REINHARD: u32 = 1u;
+const TONEMAP_FILMIC: u32 = 2u;
+const TONEMAP_ACES: u32 = 3u;
+const TONEMAP_AGX: u32 = 4u;
+
+fn luminance(color: vec3f) -> f32 {
+  return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// Extended Reinhard applied to the luminance, so that hues are preserved.
+fn reinhard(color: vec3f, white: f32) -> vec3f {
+  let l = luminance(color);
+  if l <= 0. {
+    return vec3(0.);
+  }
+  let mapped = l * (1. + l / (white * white)) / (1. + l);
+  return color * (mapped / l);
+}
+
+// John Hable's filmic curve from Uncharted 2.
+fn hable(x: vec3f) -> vec3f {
+  let a = 0.15;
+  let b = 0.50;
+  let c = 0.10;
+  let d = 0.20;
+  let e = 0.02;
+  let f = 0.30;
+  return (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f;
+}
+
+fn filmic(color: vec3f, white: f32) -> vec3f {
+  return hable(color) / hable(vec3(white));
+}
+
+// Krzysztof Narkowicz's fit of the ACES reference rendering transform. The fit reaches 1 at
+// ACES_WHITE, so the input is scaled to land the white point there.
+const ACES_WHITE: f32 = 7.2408;
+
+fn aces(color: vec3f, white: f32) -> vec3f {
+  let x = color * (ACES_WHITE / white);
+  return saturate((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14));
+}
+
+// Troy Sobotka's AgX, following Benjamin Wrensch's polynomial approximation of the default
+// contrast curve. The log encoding spans 16.5 stops below the white point.
+fn agx(color: vec3f, white: f32) -> vec3f {
+  let inset = mat3x3f(
+    0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+    0.0784335999999992, 0.878468636469772, 0.0784336,
+    0.0792237451477643, 0.0791661274605434, 0.879142973793104,
+  );
+  let outset = mat3x3f(
+    1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+    -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+    -0.0990297440797205, -0.0989611768448433, 1.15107367264116,
+  );
+  let max_ev = log2(white);
+  let min_ev = max_ev - 16.5;
+  var x = inset * max(color, vec3(0.));
+  x = (clamp(log2(max(x, vec3(1e-10))), vec3(min_ev), vec3(max_ev)) - min_ev) / (max_ev - min_ev);
+
+  let x2 = x * x;
+  let x4 = x2 * x2;
+  x = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2
+    + 0.1191 * x - 0.00232;
+
+  // The curve produces display-encoded values. Decode them so that every operator hands linear
+  // values to the sRGB transfer function.
+  return pow(saturate(outset * x), vec3(2.2));
+}
+
+fn tonemap(color: vec3f) -> vec3f {
+  let white = display.white_point;
+  switch display.tonemap {
+    case TONEMAP_REINHARD: { return reinhard(color, white); }
+    case TONEMAP_FILMIC: { return filmic(color, white); }
+    case TONEMAP_ACES: { return aces(color, white); }
+    case TONEMAP_AGX: { return agx(color, white); }
+    default: { return color; }
+  }
+}
+
+// The sRGB opto-electronic transfer function (IEC 61966-2-1).
+fn srgb_oetf(linear: vec3f) -> vec3f {
+  let c = saturate(linear);
+  return select(1.055 * pow(c, vec3(1. / 2.4)) - 0.055, 12.92 * c, c <= vec3(0.0031308));
+}
+
+alias TriangleVertices = array<vec2f, 6>;
+var<private> vertices: TriangleVertices = TriangleVertices(
+  vec2f(-1.0,  1.0),
+  vec2f(-1.0, -1.0),
+  vec2f( 1.0,  1.0),
+  vec2f( 1.0,  1.0),
+  vec2f(-1.0, -1.0),
+  vec2f( 1.0, -1.0),
+);
+
+@vertex fn display_vs(@builtin(vertex_index) vid: u32) -> @builtin(position) vec4f {
+  return vec4f(vertices[vid], 0.0, 1.0);
+}
+
+// Exposure is applied to linear radiance, before the display curve.
+fn display_radiance(radiance: vec3f) -> vec3f {
+  return srgb_oetf(tonemap(radiance * display.exposure));
+}
+
+// Colors the sample count map, from dark blue for pixels that converged early to red.
+fn heatmap(t: f32) -> vec3f {
+  var colors = array(
+    vec3(0.05, 0.05, 0.35),
+    vec3(0., 0.7, 0.9),
+    vec3(0.1, 0.8, 0.2),
+    vec3(1., 0.9, 0.1),
+    vec3(0.9, 0.1, 0.05),
+  );
+  let x = clamp(t, 0., 1.) * 4.;
+  let i = min(u32(x), 3u);
+  return mix(colors[i], colors[i + 1u], x - f32(i));
+}
+
+// A distinct color for each ID, black for the sky (whose IDs are all ones).
+fn id_color(id: u32) -> vec3f {
+  if id == 0xffffu {
+    return vec3(0.);
+  }
+  var x = id + 1u;
+  x = (x ^ (x >> 16u)) * 0x45d9f3bu;
+  x = (x ^ (x >> 16u)) * 0x45d9f3bu;
+  return vec3f(vec3(x, x >> 8u, x >> 16u) & vec3(0xffu)) / 255.;
+}
+
+// Visualizes an AOV of `texel`. Normals map to RGB, depth fades to black with distance and the
+// sample count is relative to the number of frames.
+fn aov_color(texel: vec2u, value: vec4f) -> vec3f {
+  let aov = aovs[texel.x + texel.y * textureDimensions(radiance_samples).x];
+  let sample_count = max(value.a, 1.);
+  let hit_count = max(aov.hit_count, 1.);
+  switch display.aov {
+    case AOV_NORMAL: {
+      return select(0.5 * normalize(aov.normal) + 0.5, vec3(0.), aov.hit_count == 0.);
+    }
+    case AOV_DEPTH: {
+      return vec3(select(1. / (1. + aov.depth / hit_count), 0., aov.hit_count == 0.));
+    }
+    case AOV_ALBEDO: { return srgb_oetf(aov.albedo / hit_count); }
+    case AOV_MATERIAL_ID: { return id_color(aov.ids & 0xffffu); }
+    case AOV_OBJECT_ID: { return id_color(aov.ids >> 16u); }
+    case AOV_DIRECT: { return display_radiance((value.rgb - aov.indirect) / sample_count); }
+    case AOV_INDIRECT: { return display_radiance(aov.indirect / sample_count); }
+    case AOV_SAMPLE_COUNT: { return heatmap(value.a / f32(max(display.frame_count, 1u))); }
+    case AOV_BEAUTY, default: { return display_radiance(value.rgb / sample_count); }
+  }
+}
+
+// Maps a pixel of the output image to its display value.
+fn display_color(pixel: vec2f) -> vec4f {
+  // Zooming in repeats image pixels without filtering, so that each one can be inspected.
+  let size = vec2f(textureDimensions(radiance_samples));
+  let texel = display.zoom_center + (pixel - 0.5 * size) / display.zoom;
+  if any(texel < vec2(0.)) || any(texel >= size) {
+    return vec4(0., 0., 0., 1.);
+  }
+  let value = textureLoad(radiance_samples, vec2u(texel), 0);
+  if display.denoised == 1u {
+    return vec4(display_radiance(value.rgb), 1.);
+  }
+  return vec4(aov_color(vec2u(texel), value), 1.);
+}
+
+@fragment fn display_fs(@builtin(position) pos: vec4f) -> @location(0) vec4f {
+  return display_color(pos.xy);
+}
+
+// Writes the displayed image to a texture without a render target, for headless rendering.
+@group(0) @binding(2) var display_image: texture_storage_2d<rgba8unorm, write>;
+
+@compute @workgroup_size(8, 8)
+fn display_cs(@builtin(global_invocation_id) id: vec3u) {
+  if any(id.xy >= textureDimensions(display_image)) {
+    return;
+  }
+  textureStore(display_image, id.xy, display_color(vec2f(id.xy) + 0.5));
+}
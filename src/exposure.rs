@@ -0,0 +1,144 @@
+/// How the exposure of the final image is chosen.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExposureMode {
+    /// Radiance is displayed as is, scaled only by the exposure compensation.
+    Manual,
+    /// The exposure follows from the ISO sensitivity, shutter speed and aperture of a physical
+    /// camera, so that physically-scaled light intensities produce sensible images.
+    Physical,
+}
+
+/// A camera exposure model. The resulting scale is applied to linear radiance before display, so
+/// changing it doesn't invalidate the accumulated samples.
+#[derive(Debug, Copy, Clone)]
+pub struct Exposure {
+    pub mode: ExposureMode,
+    pub iso: f32,
+    /// Exposure time in seconds.
+    pub shutter_speed: f32,
+    /// Aperture as an f-number.
+    pub f_stop: f32,
+    /// Exposure compensation in stops (EV).
+    pub compensation: f32,
+}
+
+// Keyboard adjustments move every setting in thirds of a stop, like most cameras do.
+const THIRD_STOP: f32 = 1. / 3.;
+
+impl Default for Exposure {
+    fn default() -> Self {
+        // The "sunny 16" rule: ISO 100 at 1/125 s and f/16 for a subject in direct sunlight.
+        Exposure {
+            mode: ExposureMode::Manual,
+            iso: 100.,
+            shutter_speed: 1. / 125.,
+            f_stop: 16.,
+            compensation: 0.,
+        }
+    }
+}
+
+impl Exposure {
+    /// The exposure value at ISO 100 that corresponds to the camera settings.
+    pub fn ev100(&self) -> f32 {
+        (self.f_stop * self.f_stop / self.shutter_speed * 100. / self.iso).log2()
+    }
+
+    /// The factor applied to linear radiance before display.
+    pub fn scale(&self) -> f32 {
+        let compensation = self.compensation.exp2();
+        match self.mode {
+            ExposureMode::Manual => compensation,
+            // Saturation-based sensitivity: the luminance that saturates the sensor is
+            // 78 / (ISO * S) * N^2 / t with S = 0.65, i.e. 1.2 * 2^EV100. It maps to 1.
+            ExposureMode::Physical => compensation / (1.2 * self.ev100().exp2()),
+        }
+    }
+
+    pub fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            ExposureMode::Manual => ExposureMode::Physical,
+            ExposureMode::Physical => ExposureMode::Manual,
+        };
+    }
+
+    /// Adjusts the compensation by `steps` thirds of a stop.
+    pub fn step_compensation(&mut self, steps: f32) {
+        self.compensation += steps * THIRD_STOP;
+    }
+
+    /// Adjusts the ISO sensitivity by `steps` thirds of a stop.
+    pub fn step_iso(&mut self, steps: f32) {
+        self.iso = (self.iso * (steps * THIRD_STOP).exp2()).clamp(25., 409600.);
+    }
+
+    /// Adjusts the exposure time by `steps` thirds of a stop.
+    pub fn step_shutter_speed(&mut self, steps: f32) {
+        self.shutter_speed =
+            (self.shutter_speed * (steps * THIRD_STOP).exp2()).clamp(1e-5, 60.);
+    }
+
+    /// Adjusts the aperture by `steps` thirds of a stop. Since the light reaching the sensor is
+    /// proportional to 1/N^2, a stop is a factor of sqrt(2) in the f-number.
+    pub fn step_f_stop(&mut self, steps: f32) {
+        self.f_stop = (self.f_stop * (0.5 * steps * THIRD_STOP).exp2()).clamp(0.5, 64.);
+    }
+
+    /// A short human readable summary, e.g. "ISO 100  1/125 s  f/16  EV100 15.0  +0.3 EV".
+    pub fn describe(&self) -> String {
+        let shutter = if self.shutter_speed < 1. {
+            format!("1/{:.0} s", 1. / self.shutter_speed)
+        } else {
+            format!("{:.1} s", self.shutter_speed)
+        };
+        let compensation = format!("{:+.1} EV", self.compensation);
+        match self.mode {
+            ExposureMode::Manual => format!("manual exposure  {}", compensation),
+            ExposureMode::Physical => format!(
+                "ISO {:.0}  {}  f/{:.1}  EV100 {:.1}  {}",
+                self.iso,
+                shutter,
+                self.f_stop,
+                self.ev100(),
+                compensation
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ev100_of_known_settings() {
+        // f/1 for one second at ISO 100 is EV 0 by definition.
+        let exposure = Exposure {
+            iso: 100.,
+            shutter_speed: 1.,
+            f_stop: 1.,
+            ..Default::default()
+        };
+        assert!(exposure.ev100().abs() < 1e-6);
+
+        // Sunny 16: log2(16² × 125) ≈ 14.97.
+        assert!((Exposure::default().ev100() - 32000f32.log2()).abs() < 1e-4);
+
+        // Doubling the sensitivity takes one stop off EV100.
+        let faster = Exposure {
+            iso: 200.,
+            ..Default::default()
+        };
+        assert!((Exposure::default().ev100() - faster.ev100() - 1.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn physical_scale_maps_saturation_to_one() {
+        let exposure = Exposure {
+            mode: ExposureMode::Physical,
+            ..Default::default()
+        };
+        let saturation = 1.2 * exposure.ev100().exp2();
+        assert!((exposure.scale() * saturation - 1.).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,137 @@
+use {
+    anyhow::{Context, Result},
+    std::path::Path,
+};
+
+use crate::{
+    animation::CameraPath,
+    aov::Aov,
+    camera::Camera,
+    convergence::Progress,
+    load,
+    options::{CameraPathSource, Options, SequenceOptions},
+    output,
+    render::PathTracer,
+};
+
+/// Connects to a GPU without a window and creates a renderer. Headless rendering runs entirely in
+/// compute passes, so no render target is needed.
+pub async fn create_renderer(options: &Options) -> Result<PathTracer> {
+    let (device, queue) = connect_to_gpu().await?;
+    let scene = options.load_scene()?;
+    let mesh = load::load_mesh(&device, &options.scene);
+    let mut renderer = PathTracer::new(
+        device,
+        queue,
+        options.width,
+        options.height,
+        mesh,
+        &scene,
+        options.trace_config(),
+    );
+    renderer.set_exposure(&options.exposure);
+    renderer.set_tonemap(options.tonemap, options.white_point);
+    renderer.set_denoise(options.denoise);
+    renderer.set_adaptive_threshold(options.adaptive_threshold);
+    renderer.set_sampler(options.sampler);
+    renderer.set_path_length(options.max_path_length, options.roulette_depth);
+    renderer.set_sample_clamp(options.clamp_direct, options.clamp_indirect);
+    renderer.set_regularization(options.regularization);
+    renderer.set_photon_radius(options.photon_radius);
+    renderer.set_spectral(options.spectral);
+    renderer.set_restir(options.restir);
+    Ok(renderer)
+}
+
+async fn connect_to_gpu() -> Result<(wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::default();
+    let adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: false,
+            compatible_surface: None,
+        })
+        .await
+        .context("failed to find an adapter")?;
+    adapter
+        .request_device(&wgpu::DeviceDescriptor::default(), None)
+        .await
+        .context("failed to connect to the GPU")
+}
+
+/// Renders a single image until it runs out of the budget of `options` and writes it to `file`.
+/// An OpenEXR file holds the requested AOVs as layers, while a PNG gets a file of its own for each
+/// of them next to it, e.g. `still.normal.png`.
+pub fn render_still(
+    renderer: &mut PathTracer,
+    camera: &Camera,
+    options: &Options,
+    file: &Path,
+) -> Result<()> {
+    renderer.reset_samples();
+    let mut progress = Progress::new(options.headless_budget());
+    loop {
+        renderer.trace(camera);
+        if progress.update(renderer).is_some() {
+            break;
+        }
+    }
+    if output::ImageFormat::from_path(file) == output::ImageFormat::Exr {
+        return output::write_exr(file, renderer, &options.aovs, &options.exr);
+    }
+
+    let pixels = renderer.read_image();
+    output::write_png(file, renderer.width(), renderer.height(), &pixels)?;
+
+    for &aov in &options.aovs {
+        renderer.set_aov(aov);
+        let pixels = renderer.read_image();
+        let layer_file = output::layer_path(file, aov.name());
+        output::write_png(&layer_file, renderer.width(), renderer.height(), &pixels)?;
+    }
+    renderer.set_aov(Aov::Beauty);
+    Ok(())
+}
+
+/// Renders every frame of a camera path with the budget of a still and writes them to
+/// `frame_0000.png`, `frame_0001.png`, ... (or `.exr`) in the output directory.
+pub fn render_sequence(
+    renderer: &mut PathTracer,
+    initial_camera: &Camera,
+    options: &Options,
+    sequence: &SequenceOptions,
+) -> Result<()> {
+    let path = match &sequence.camera_path {
+        CameraPathSource::Turntable => CameraPath::turntable(initial_camera),
+        CameraPathSource::Keyframes(file) => CameraPath::load_keyframes(file)?,
+    };
+    std::fs::create_dir_all(&sequence.output_dir)
+        .with_context(|| format!("failed to create {}", sequence.output_dir.display()))?;
+
+    for frame in 0..sequence.frames {
+        // A turntable wraps around, so its last frame must stop short of the first one. A
+        // keyframed path should land exactly on its last keyframe.
+        let t = match sequence.camera_path {
+            CameraPathSource::Turntable => frame as f32 / sequence.frames as f32,
+            CameraPathSource::Keyframes(_) => {
+                frame as f32 / (sequence.frames - 1).max(1) as f32
+            }
+        };
+        let mut camera = path.camera_at(t);
+        options.configure_camera(&mut camera);
+
+        let file = sequence.output_dir.join(format!(
+            "frame_{:04}.{}",
+            frame,
+            sequence.format.extension()
+        ));
+        render_still(renderer, &camera, options, &file)?;
+        println!(
+            "wrote {} ({}/{})",
+            file.display(),
+            frame + 1,
+            sequence.frames
+        );
+    }
+    Ok(())
+}
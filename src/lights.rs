@@ -0,0 +1,150 @@
+use {
+    anyhow::{bail, Context, Result},
+    std::path::Path,
+};
+
+use crate::types::Light;
+
+/// Loads a lights file, whose lights replace the default one. Every non-empty line that isn't a
+/// comment (`#`) describes a point light:
+///
+///     light position <x y z> color <r g b | value> intensity <value>
+///
+/// The position is required, while the color defaults to white and the intensity to 1. Scenes
+/// with many lights are best rendered with ReSTIR (`--restir`), as the other modes trace a
+/// shadow ray towards every light at every bounce.
+pub fn load(path: &Path) -> Result<Vec<Light>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read lights from {}", path.display()))?;
+
+    let mut lights = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let location = || format!("{}:{}", path.display(), number + 1);
+        lights.push(parse_light(line).with_context(location)?);
+    }
+    if lights.is_empty() {
+        bail!("{} describes no lights", path.display());
+    }
+    Ok(lights)
+}
+
+fn parse_light(line: &str) -> Result<Light> {
+    let tokens = line.split_whitespace().collect::<Vec<_>>();
+    // The numbers that follow the token at `index`.
+    let numbers = |index: usize| {
+        tokens[index + 1..]
+            .iter()
+            .map_while(|token| token.parse::<f32>().ok())
+            .collect::<Vec<_>>()
+    };
+
+    if tokens[0] != "light" {
+        bail!("expected light, found \"{}\"", tokens[0]);
+    }
+    let mut light = Light {
+        position: [0.; 3],
+        _pad0: 0,
+        color: [1.; 3],
+        intensity: 1.,
+    };
+    let mut position = None;
+    let mut index = 1;
+    while index < tokens.len() {
+        let consumed = match tokens[index] {
+            "position" => match numbers(index)[..] {
+                [x, y, z, ..] => {
+                    position = Some([x, y, z]);
+                    3
+                }
+                _ => bail!("position expects three numbers"),
+            },
+            "color" => {
+                let (color, count) = match numbers(index)[..] {
+                    [value] => ([value; 3], 1),
+                    [r, g, b, ..] => ([r, g, b], 3),
+                    _ => bail!("color expects one or three numbers"),
+                };
+                if color.iter().any(|&c| c < 0.) {
+                    bail!("a light's color can't be negative");
+                }
+                light.color = color;
+                count
+            }
+            "intensity" => match numbers(index)[..] {
+                [intensity, ..] if intensity >= 0. => {
+                    light.intensity = intensity;
+                    1
+                }
+                _ => bail!("intensity expects a non-negative number"),
+            },
+            other => bail!("unknown light property \"{}\"", other),
+        };
+        index += 1 + consumed;
+    }
+    let Some(position) = position else {
+        bail!("a light needs a position");
+    };
+    light.position = position;
+    Ok(light)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(line: &str) -> String {
+        parse_light(line).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_lights() {
+        let light = parse_light("light position 1 2 -3 color 0.5 intensity 4").unwrap();
+        assert_eq!(light.position, [1., 2., -3.]);
+        assert_eq!(light.color, [0.5; 3]);
+        assert_eq!(light.intensity, 4.);
+
+        let light = parse_light("light color 1 0.6 0.5 position 0 2.5 0").unwrap();
+        assert_eq!(light.position, [0., 2.5, 0.]);
+        assert_eq!(light.color, [1., 0.6, 0.5]);
+        assert_eq!(light.intensity, 1.);
+    }
+
+    #[test]
+    fn requires_a_position() {
+        assert_eq!(
+            error("light color 1 intensity 2"),
+            "a light needs a position"
+        );
+        assert_eq!(
+            error("light position 1 2"),
+            "position expects three numbers"
+        );
+    }
+
+    #[test]
+    fn rejects_negative_colors() {
+        let message = "a light's color can't be negative";
+        assert_eq!(error("light position 0 0 0 color -1"), message);
+        assert_eq!(error("light position 0 0 0 color 1 -0.5 1"), message);
+        assert_eq!(
+            error("light position 0 0 0 intensity -2"),
+            "intensity expects a non-negative number"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_properties() {
+        assert_eq!(
+            error("light position 0 0 0 radius 1"),
+            "unknown light property \"radius\""
+        );
+        assert_eq!(
+            error("lamp position 0 0 0"),
+            "expected light, found \"lamp\""
+        );
+    }
+}
@@ -1,9 +1,15 @@
 use std::path::Path;
 use wgpu::util::DeviceExt;
-use tobj;
 use crate::types::{BoundingBox, Triangle};
 
-pub fn load_mesh(device: &wgpu::Device, path: &Path) -> (wgpu::Buffer, usize, BoundingBox) {
+pub struct Mesh {
+    pub buffer: wgpu::Buffer,
+    pub triangle_count: usize,
+    /// The object-space bounds of the mesh.
+    pub bounds: BoundingBox,
+}
+
+pub fn load_mesh(device: &wgpu::Device, path: &Path) -> Mesh {
     let (models, _) = tobj::load_obj(
         path,
         &tobj::LoadOptions {
@@ -111,7 +117,11 @@ pub fn load_mesh(device: &wgpu::Device, path: &Path) -> (wgpu::Buffer, usize, Bo
         _pad1: 0,
     };
 
-    (buffer, triangle_count, bounds)
+    Mesh {
+        buffer,
+        triangle_count,
+        bounds,
+    }
 }
 
 fn normalize_vector(v: [f32; 3]) -> [f32; 3] {
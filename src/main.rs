@@ -3,66 +3,167 @@
 
 use {
     anyhow::{Context, Result},
+    std::{
+        path::PathBuf,
+        time::{Duration, Instant},
+    },
     winit::{
-        event::{DeviceEvent, ElementState, Event, MouseScrollDelta, WindowEvent},
+        event::{DeviceEvent, ElementState, Event, KeyEvent, MouseScrollDelta, WindowEvent},
         event_loop::{ControlFlow, EventLoop},
+        keyboard::{KeyCode, ModifiersState, PhysicalKey},
         window::{Window, WindowBuilder},
     },
 };
 
 mod algebra;
+mod animation;
+mod aov;
+mod bdpt;
+mod bookmarks;
 mod camera;
+mod convergence;
+mod denoise;
+mod exposure;
+mod headless;
+mod lights;
 mod load;
+mod media;
+mod options;
+mod output;
+mod probes;
 mod render;
+mod restir;
+mod sampler;
+mod scene;
+mod spectrum;
+mod sppm;
+mod tonemap;
 mod types;
+mod wavefront;
 
-use crate::{algebra::Vec3, camera::Camera};
-use std::path::Path;
+use crate::{
+    algebra::Vec3,
+    aov::Aov,
+    bookmarks::Bookmarks,
+    camera::Camera,
+    convergence::{Progress, StopReason},
+    exposure::Exposure,
+    options::{Mode, Options},
+    render::Integrator,
+    sampler::Sampler,
+    tonemap::Tonemap,
+};
 
-const WIDTH: u32 = 1600;
-const HEIGHT: u32 = 1200;
+/// How long the camera has to stay still before the probe preview gives way to path tracing.
+const CAMERA_SETTLE_TIME: Duration = Duration::from_millis(150);
 
 #[pollster::main]
 async fn main() -> Result<()> {
+    let options = Options::from_args()?;
+    let mut bookmarks = Bookmarks::for_scene(&options.scene);
+    let mut camera = initial_camera(&options, &bookmarks)?;
+
+    match &options.mode {
+        Mode::Interactive => (),
+        Mode::Still(file) => {
+            let mut renderer = headless::create_renderer(&options).await?;
+            return headless::render_still(&mut renderer, &camera, &options, file);
+        }
+        Mode::Sequence(sequence) => {
+            let mut renderer = headless::create_renderer(&options).await?;
+            return headless::render_sequence(&mut renderer, &camera, &options, sequence);
+        }
+    }
+
     let event_loop = EventLoop::new()?;
-    let window_size = winit::dpi::PhysicalSize::new(WIDTH, HEIGHT);
+    let window_size = winit::dpi::PhysicalSize::new(options.width, options.height);
     let window = WindowBuilder::new()
         .with_inner_size(window_size)
         .with_resizable(false)
-        .with_title("GPU Path Tracer".to_string())
+        .with_title(window_title(
+            &options.exposure,
+            options.tonemap,
+            options.white_point,
+            Aov::Beauty,
+            options.sampler,
+            RenderModes {
+                spectral: options.spectral,
+                restir: options.restir,
+                probes: options.probe_preview,
+            },
+            None,
+        ))
         .build(&event_loop)?;
 
     let (device, queue, surface) = connect_to_gpu(&window).await?;
-    let (mesh_buffer, triangle_count, mesh_bounds) = load::load_mesh(
-        &device,
-        Path::new("models/cube.obj")
-    );
-    
+    let scene = options.load_scene()?;
+    let mesh = load::load_mesh(&device, &options.scene);
     let mut renderer = render::PathTracer::new(
         device,
         queue,
-        WIDTH,
-        HEIGHT,
-        mesh_buffer,
-        triangle_count,
-        mesh_bounds
+        options.width,
+        options.height,
+        mesh,
+        &scene,
+        options.trace_config(),
     );
+    let mut exposure = options.exposure;
+    let mut tonemap = options.tonemap;
+    let mut white_point = options.white_point;
+    let mut denoise = options.denoise;
+    let mut aov = Aov::Beauty;
+    let mut sampler = options.sampler;
+    let mut modes = RenderModes {
+        spectral: options.spectral,
+        restir: options.restir,
+        probes: options.probe_preview,
+    };
+    renderer.set_exposure(&exposure);
+    renderer.set_tonemap(tonemap, white_point);
+    renderer.set_denoise(denoise);
+    renderer.set_adaptive_threshold(options.adaptive_threshold);
+    renderer.set_sampler(sampler);
+    renderer.set_path_length(options.max_path_length, options.roulette_depth);
+    renderer.set_sample_clamp(options.clamp_direct, options.clamp_indirect);
+    renderer.set_regularization(options.regularization);
+    renderer.set_photon_radius(options.photon_radius);
+    renderer.set_spectral(modes.spectral);
+    renderer.set_restir(modes.restir);
+    renderer.set_probe_preview(modes.probes);
 
-    let mut camera = Camera::look_at(
-        Vec3::new(0., 1.0, 2.0),
-        Vec3::new(0., 0., 0.),
-        Vec3::new(0., 1., 0.),
-    );
+    // Tracing stops when paused or once the sample budget runs out, and the window then only
+    // redraws when something changes. Zooming magnifies the image around the cursor without
+    // touching the samples.
+    let mut paused = false;
+    let mut progress = Progress::new(options.budget);
+    let mut title = String::new();
+    let mut zoom = 1.;
+    let mut zoom_center = [options.width as f32 / 2., options.height as f32 / 2.];
+    let mut cursor_position = zoom_center;
+    // When the camera last moved, until it has been still for a moment. The probe preview, if
+    // enabled, replaces path tracing meanwhile.
+    let mut camera_moved_at: Option<Instant> = None;
 
     let mut left_mouse_button_pressed = false;
     let mut right_mouse_button_pressed = false;
+    let mut modifiers = ModifiersState::empty();
 
     event_loop.run(|event, control_handle| {
-        control_handle.set_control_flow(ControlFlow::Poll);
+        // Frames are driven by redraw requests, so the loop sleeps once they stop.
+        control_handle.set_control_flow(ControlFlow::Wait);
         match event {
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::CloseRequested => control_handle.exit(),
                 WindowEvent::RedrawRequested => {
+                    if camera_moved_at.is_some_and(|at| at.elapsed() > CAMERA_SETTLE_TIME) {
+                        camera_moved_at = None;
+                        renderer.set_camera_moving(false);
+                        if modes.probes {
+                            // Path tracing starts over from the preview.
+                            progress.restart();
+                        }
+                    }
+
                     // Wait for the next available frame buffer.
                     let frame: wgpu::SurfaceTexture = surface
                         .get_current_texture()
@@ -72,10 +173,146 @@ async fn main() -> Result<()> {
                         .texture
                         .create_view(&wgpu::TextureViewDescriptor::default());
 
-                    renderer.render_frame(&camera, &render_target);
+                    if !paused && progress.stopped().is_none() {
+                        renderer.trace(&camera);
+                        if let Some(reason) = progress.update(&renderer) {
+                            println!(
+                                "rendering {} after {} samples per pixel in {:.1} s",
+                                reason,
+                                renderer.samples_per_pixel(),
+                                progress.elapsed().as_secs_f32()
+                            );
+                        }
+                    }
+                    renderer.display(&render_target);
+
+                    let new_title = window_title(
+                        &exposure,
+                        tonemap,
+                        white_point,
+                        aov,
+                        sampler,
+                        modes,
+                        progress.stopped(),
+                    );
+                    if new_title != title {
+                        window.set_title(&new_title);
+                        title = new_title;
+                    }
 
                     frame.present();
+                    // Keep redrawing until the camera settles, even if tracing has stopped.
+                    if camera_moved_at.is_some() || (!paused && progress.stopped().is_none()) {
+                        window.request_redraw();
+                    }
+                }
+                WindowEvent::ModifiersChanged(new_modifiers) => modifiers = new_modifiers.state(),
+                WindowEvent::CursorMoved { position, .. } => {
+                    cursor_position = [position.x as f32, position.y as f32];
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            physical_key: PhysicalKey::Code(key),
+                            state: ElementState::Pressed,
+                            repeat,
+                            ..
+                        },
+                    ..
+                } => {
+                    // Most keys change the image, which needs a redraw when tracing has stopped.
                     window.request_redraw();
+
+                    // Number keys recall a camera bookmark, Ctrl + number stores one.
+                    if let Some(slot) = bookmark_slot(key).filter(|_| !repeat) {
+                        if modifiers.control_key() {
+                            if let Err(error) = bookmarks.store(slot, &camera) {
+                                eprintln!("failed to save bookmark {}: {:#}", slot, error);
+                            }
+                        } else if let Some(bookmark) = bookmarks.get(slot) {
+                            camera = bookmark.to_camera();
+                            options.configure_camera(&mut camera);
+                            renderer.reset_samples();
+                            progress.restart();
+                        }
+                    } else if adjust_exposure(&mut exposure, key, modifiers.shift_key(), repeat)
+                        || adjust_tonemap(
+                            &mut tonemap,
+                            &mut white_point,
+                            key,
+                            modifiers.shift_key(),
+                            repeat,
+                        )
+                    {
+                        // Both are applied at display time, so the samples stay valid.
+                        renderer.set_exposure(&exposure);
+                        renderer.set_tonemap(tonemap, white_point);
+                    } else if key == KeyCode::KeyA && !repeat {
+                        // Cycle through the output variables, backwards with Shift.
+                        aov = if modifiers.shift_key() {
+                            aov.previous()
+                        } else {
+                            aov.next()
+                        };
+                        renderer.set_aov(aov);
+                    } else if key == KeyCode::KeyN && !repeat {
+                        // Samples of different samplers don't mix, so this starts over.
+                        sampler = sampler.next();
+                        renderer.set_sampler(sampler);
+                        progress.restart();
+                    } else if key == KeyCode::KeyL && !repeat {
+                        // RGB and spectral samples converge to different images.
+                        modes.spectral = !modes.spectral;
+                        renderer.set_spectral(modes.spectral);
+                        progress.restart();
+                    } else if key == KeyCode::KeyR
+                        && !repeat
+                        && !matches!(options.integrator, Integrator::Bdpt | Integrator::Sppm)
+                    {
+                        // ReSTIR converges to a slightly different image.
+                        modes.restir = !modes.restir;
+                        renderer.set_restir(modes.restir);
+                        progress.restart();
+                    } else if key == KeyCode::KeyG
+                        && !repeat
+                        && !matches!(options.integrator, Integrator::Bdpt | Integrator::Sppm)
+                    {
+                        // Only changes the image while the camera moves.
+                        modes.probes = !modes.probes;
+                        renderer.set_probe_preview(modes.probes);
+                    } else if key == KeyCode::KeyD && !repeat {
+                        denoise = !denoise;
+                        renderer.set_denoise(denoise);
+                    } else if key == KeyCode::KeyE && !repeat {
+                        // Save the linear radiance and the requested AOVs for compositing.
+                        let file = PathBuf::from(format!("render_{}.exr", output::timestamp()));
+                        match output::write_exr(&file, &renderer, &options.aovs, &options.exr) {
+                            Ok(()) => println!("wrote {}", file.display()),
+                            Err(error) => eprintln!("{:#}", error),
+                        }
+                    } else if key == KeyCode::KeyS && !repeat {
+                        // Save the displayed image with the settings needed to reproduce it.
+                        match output::save_screenshot(&renderer, &camera, &options.scene) {
+                            Ok(file) => println!("wrote {}", file.display()),
+                            Err(error) => eprintln!("failed to save a screenshot: {:#}", error),
+                        }
+                    } else if key == KeyCode::Space && !repeat {
+                        paused = !paused;
+                        progress.set_paused(paused);
+                    } else if key == KeyCode::KeyZ {
+                        // Zoom in on the image pixel under the cursor, or back out with Shift.
+                        let half_size = [options.width as f32 / 2., options.height as f32 / 2.];
+                        let under_cursor = [0, 1].map(|i| {
+                            zoom_center[i] + (cursor_position[i] - half_size[i]) / zoom
+                        });
+                        zoom = if modifiers.shift_key() {
+                            (zoom / 2.).max(1.)
+                        } else {
+                            (zoom * 2.).min(64.)
+                        };
+                        zoom_center = if zoom == 1. { half_size } else { under_cursor };
+                        renderer.set_zoom(zoom, zoom_center);
+                    }
                 }
                 _ => (),
             },
@@ -86,18 +323,27 @@ async fn main() -> Result<()> {
                         MouseScrollDelta::LineDelta(_, y) => y * 0.1,
                     };
                     camera.zoom(delta);
+                    camera_moved_at = Some(Instant::now());
+                    renderer.set_camera_moving(true);
                     renderer.reset_samples();
+                    progress.restart();
+                    window.request_redraw();
                 }
                 DeviceEvent::MouseMotion { delta: (dx, dy) } => {
                     let dx = dx as f32 * 0.01;
                     let dy = dy as f32 * -0.01;
                     if left_mouse_button_pressed {
                         camera.orbit(dx, dy);
-                        renderer.reset_samples();
                     }
                     if right_mouse_button_pressed {
                         camera.pan(dx, dy);
+                    }
+                    if left_mouse_button_pressed || right_mouse_button_pressed {
+                        camera_moved_at = Some(Instant::now());
+                        renderer.set_camera_moving(true);
                         renderer.reset_samples();
+                        progress.restart();
+                        window.request_redraw();
                     }
                 }
                 DeviceEvent::Button { button, state } => {
@@ -116,7 +362,120 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn connect_to_gpu(window: &Window) -> Result<(wgpu::Device, wgpu::Queue, wgpu::Surface)> {
+fn bookmark_slot(key: KeyCode) -> Option<usize> {
+    let digits = [
+        KeyCode::Digit0,
+        KeyCode::Digit1,
+        KeyCode::Digit2,
+        KeyCode::Digit3,
+        KeyCode::Digit4,
+        KeyCode::Digit5,
+        KeyCode::Digit6,
+        KeyCode::Digit7,
+        KeyCode::Digit8,
+        KeyCode::Digit9,
+    ];
+    digits.iter().position(|&digit| digit == key)
+}
+
+/// Applies the exposure shortcut bound to `key`, if any. Shift reverses the direction of the ISO,
+/// shutter speed and aperture adjustments. Returns whether `exposure` changed.
+fn adjust_exposure(exposure: &mut Exposure, key: KeyCode, shift: bool, repeat: bool) -> bool {
+    let steps = if shift { -1. } else { 1. };
+    match key {
+        KeyCode::KeyP if !repeat => exposure.toggle_mode(),
+        KeyCode::Equal => exposure.step_compensation(1.),
+        KeyCode::Minus => exposure.step_compensation(-1.),
+        KeyCode::KeyI => exposure.step_iso(steps),
+        KeyCode::KeyT => exposure.step_shutter_speed(steps),
+        KeyCode::KeyF => exposure.step_f_stop(steps),
+        _ => return false,
+    }
+    true
+}
+
+/// Applies the tone mapping shortcut bound to `key`, if any: M cycles through the operators and
+/// W raises the white point by a third of a stop (Shift lowers it). Returns whether anything
+/// changed.
+fn adjust_tonemap(
+    tonemap: &mut Tonemap,
+    white_point: &mut f32,
+    key: KeyCode,
+    shift: bool,
+    repeat: bool,
+) -> bool {
+    match key {
+        KeyCode::KeyM if !repeat => *tonemap = tonemap.next(),
+        KeyCode::KeyW => {
+            let steps = if shift { -1. } else { 1. };
+            *white_point = (*white_point * (steps / 3f32).exp2()).clamp(0.25, 1024.);
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// The ways of tracing the image that can be toggled in the window, besides the sampler.
+#[derive(Copy, Clone)]
+struct RenderModes {
+    spectral: bool,
+    restir: bool,
+    probes: bool,
+}
+
+fn window_title(
+    exposure: &Exposure,
+    tonemap: Tonemap,
+    white_point: f32,
+    aov: Aov,
+    sampler: Sampler,
+    modes: RenderModes,
+    stopped: Option<StopReason>,
+) -> String {
+    let mut title = format!(
+        "GPU Path Tracer - {} - {} (white {:.2}) - {} sampler",
+        exposure.describe(),
+        tonemap.name(),
+        white_point,
+        sampler.name()
+    );
+    if modes.spectral {
+        title += " - spectral";
+    }
+    if modes.restir {
+        title += " - ReSTIR";
+    }
+    if modes.probes {
+        title += " - probe preview";
+    }
+    if aov != Aov::Beauty {
+        title += &format!(" - showing {}", aov.name());
+    }
+    if let Some(reason) = stopped {
+        title += &format!(" - {}", reason);
+    }
+    title
+}
+
+fn initial_camera(options: &Options, bookmarks: &Bookmarks) -> Result<Camera> {
+    let mut camera = match options.bookmark {
+        Some(slot) => bookmarks
+            .get(slot)
+            .with_context(|| format!("bookmark {} is not set", slot))?
+            .to_camera(),
+        None => Camera::look_at(
+            Vec3::new(0., 1.0, 2.0),
+            Vec3::new(0., 0., 0.),
+            Vec3::new(0., 1., 0.),
+        ),
+    };
+    options.configure_camera(&mut camera);
+    Ok(camera)
+}
+
+async fn connect_to_gpu(
+    window: &Window,
+) -> Result<(wgpu::Device, wgpu::Queue, wgpu::Surface<'_>)> {
     use wgpu::TextureFormat::{Bgra8Unorm, Rgba8Unorm};
 
     // Create an "instance" of wgpu. This is the entry-point to the API.
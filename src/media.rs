@@ -0,0 +1,380 @@
+use {
+    anyhow::{bail, Context, Result},
+    std::path::{Path, PathBuf},
+};
+
+use crate::types::{Medium, NO_GRID, NO_OBJECT};
+
+/// The number of media the trace kernels can hold. Must match shaders.wgsl.
+pub const MAX_MEDIA: usize = 4;
+
+/// The largest number of voxels of a density grid along each axis.
+pub const MAX_GRID_SIZE: u32 = 512;
+
+/// The extent of the fog unless its bounds are given.
+const DEFAULT_FOG_BOUNDS: ([f32; 3], [f32; 3]) = ([-10.; 3], [10.; 3]);
+
+const PROPERTIES: [&str; 6] = [
+    "absorption",
+    "scattering",
+    "anisotropy",
+    "bounds",
+    "rotation",
+    "grid",
+];
+
+/// The participating media of a scene and the density grids they use.
+#[derive(Default)]
+pub struct Media {
+    pub media: Vec<Medium>,
+    /// Indexed by `Medium::grid`.
+    pub grids: Vec<DensityGrid>,
+}
+
+/// A dense grid of densities stretched over the bounds of a medium.
+pub struct DensityGrid {
+    /// The number of voxels along x, y and z.
+    pub size: [u32; 3],
+    /// The densities of the voxels, x first and z last.
+    pub densities: Vec<f32>,
+}
+
+impl DensityGrid {
+    fn max_density(&self) -> f32 {
+        self.densities.iter().copied().fold(0., f32::max)
+    }
+}
+
+// Where the densities of a medium come from.
+#[derive(PartialEq)]
+enum GridSource {
+    Procedural,
+    Raw { path: PathBuf, size: [u32; 3] },
+}
+
+/// Loads a media file. Every non-empty line that isn't a comment (`#`) describes a medium that
+/// either fills the scene (`fog`) or the interior of an object (`object <id>`, with the IDs of
+/// the `object-id` AOV), followed by any of these properties:
+///
+///     absorption <r g b | value>   scattering <r g b | value>   anisotropy <g>
+///     bounds <min.x min.y min.z> <max.x max.y max.z>   rotation <axis.x axis.y axis.z> <degrees>
+///     grid [<file.raw> <width> <height> <depth>]
+///
+/// The coefficients are per unit length and default to 0, and the anisotropy of the
+/// Henyey-Greenstein phase function defaults to 0 (isotropic). The fog only exists within its
+/// bounds (by default 10 units around the origin), which may be rotated around their center,
+/// while an object's medium is bounded by its surface. `grid` scales the coefficients by a
+/// density grid stretched over the fog's bounds or over the object's box in its own space: a
+/// procedural puff of smoke, or the voxels of a raw file (see `load_raw_grid`) whose path is
+/// relative to the media file.
+pub fn load(path: &Path) -> Result<Media> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read media from {}", path.display()))?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    let mut media = Media::default();
+    let mut sources = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let location = || format!("{}:{}", path.display(), number + 1);
+        let (mut medium, source) = parse_medium(line).with_context(location)?;
+        if medium.object_id == NO_OBJECT && media.media.iter().any(is_fog) {
+            bail!("{}: there can only be one fog", location());
+        }
+        if let Some(source) = source {
+            // Media that use the same grid share it.
+            let index = match sources.iter().position(|s| *s == source) {
+                Some(index) => index,
+                None => {
+                    let grid = match &source {
+                        GridSource::Procedural => density_grid(),
+                        GridSource::Raw { path, size } => {
+                            load_raw_grid(&directory.join(path), *size)
+                                .with_context(location)?
+                        }
+                    };
+                    media.grids.push(grid);
+                    sources.push(source);
+                    sources.len() - 1
+                }
+            };
+            medium.grid = index as u32;
+            medium.majorant *= media.grids[index].max_density();
+        }
+        media.media.push(medium);
+    }
+    if media.media.len() > MAX_MEDIA {
+        bail!(
+            "{} describes {} media, at most {} are supported",
+            path.display(),
+            media.media.len(),
+            MAX_MEDIA
+        );
+    }
+    Ok(media)
+}
+
+fn is_fog(medium: &Medium) -> bool {
+    medium.object_id == NO_OBJECT
+}
+
+fn parse_medium(line: &str) -> Result<(Medium, Option<GridSource>)> {
+    let tokens = line.split_whitespace().collect::<Vec<_>>();
+    // The numbers that follow the token at `index`, which must be finite.
+    let numbers = |index: usize| {
+        let mut values = Vec::new();
+        for token in &tokens[index + 1..] {
+            let Ok(value) = token.parse::<f32>() else {
+                break;
+            };
+            if !value.is_finite() {
+                bail!(
+                    "{} expects finite numbers, found \"{}\"",
+                    tokens[index],
+                    token
+                );
+            }
+            values.push(value);
+        }
+        Ok(values)
+    };
+    let color = |index: usize| match numbers(index)?[..] {
+        [value] => Ok(([value; 3], 1)),
+        [r, g, b, ..] => Ok(([r, g, b], 3)),
+        _ => bail!("{} expects one or three numbers", tokens[index]),
+    };
+
+    let mut medium = Medium {
+        absorption: [0.; 3],
+        object_id: NO_OBJECT,
+        scattering: [0.; 3],
+        anisotropy: 0.,
+        bounds_min: DEFAULT_FOG_BOUNDS.0,
+        grid: NO_GRID,
+        bounds_max: DEFAULT_FOG_BOUNDS.1,
+        majorant: 0.,
+        rotation: [0., 0., 0., 1.],
+    };
+    let mut grid = None;
+    let mut index = match tokens[0] {
+        "fog" => 1,
+        "object" => {
+            medium.object_id = match tokens.get(1).map(|id| id.parse::<u32>()) {
+                Some(Ok(id)) if id != NO_OBJECT => id,
+                _ => bail!("object expects an object ID"),
+            };
+            2
+        }
+        other => bail!("expected fog or object, found \"{}\"", other),
+    };
+    while index < tokens.len() {
+        let consumed = match tokens[index] {
+            "absorption" => {
+                let (absorption, count) = color(index)?;
+                medium.absorption = absorption;
+                count
+            }
+            "scattering" => {
+                let (scattering, count) = color(index)?;
+                medium.scattering = scattering;
+                count
+            }
+            "anisotropy" => match numbers(index)?[..] {
+                [g, ..] if g.abs() < 1. => {
+                    medium.anisotropy = g;
+                    1
+                }
+                _ => bail!("anisotropy expects a number between -1 and 1"),
+            },
+            "bounds" => match numbers(index)?[..] {
+                [x0, y0, z0, x1, y1, z1, ..] if x0 < x1 && y0 < y1 && z0 < z1 => {
+                    medium.bounds_min = [x0, y0, z0];
+                    medium.bounds_max = [x1, y1, z1];
+                    6
+                }
+                _ => bail!("bounds expects a minimum and a larger maximum corner"),
+            },
+            "rotation" if medium.object_id != NO_OBJECT => {
+                bail!("only the fog can be rotated, objects bound their own media")
+            }
+            "rotation" => match numbers(index)?[..] {
+                [x, y, z, degrees, ..] if x != 0. || y != 0. || z != 0. => {
+                    let length = (x * x + y * y + z * z).sqrt();
+                    let (sin, cos) = (degrees.to_radians() / 2.).sin_cos();
+                    let [x, y, z] = [x, y, z].map(|c| c / length * sin);
+                    medium.rotation = [x, y, z, cos];
+                    4
+                }
+                _ => bail!("rotation expects a nonzero axis and an angle in degrees"),
+            },
+            "grid" => match tokens.get(index + 1) {
+                Some(file) if !PROPERTIES.contains(file) => {
+                    grid = Some(GridSource::Raw {
+                        path: file.into(),
+                        size: parse_grid_size(tokens.get(index + 2..index + 5))?,
+                    });
+                    4
+                }
+                _ => {
+                    grid = Some(GridSource::Procedural);
+                    0
+                }
+            },
+            other => bail!("unknown medium property \"{}\"", other),
+        };
+        index += 1 + consumed;
+    }
+    let coefficients = medium.absorption.iter().chain(&medium.scattering);
+    if coefficients.clone().any(|&c| c < 0.) {
+        bail!("the absorption and scattering coefficients can't be negative");
+    }
+    // The largest extinction coefficient bounds it everywhere, once `load` scales it by the
+    // largest density of the grid, if any.
+    medium.majorant = (0..3)
+        .map(|i| medium.absorption[i] + medium.scattering[i])
+        .fold(0., f32::max);
+    Ok((medium, grid))
+}
+
+fn parse_grid_size(tokens: Option<&[&str]>) -> Result<[u32; 3]> {
+    let size = tokens.map(|tokens| {
+        tokens
+            .iter()
+            .map(|token| token.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+    });
+    match size {
+        Some(Ok(size)) if size.iter().all(|n| (1..=MAX_GRID_SIZE).contains(n)) => {
+            Ok([size[0], size[1], size[2]])
+        }
+        _ => bail!(
+            "grid expects a file followed by its width, height and depth in voxels, each from 1 \
+             to {}",
+            MAX_GRID_SIZE
+        ),
+    }
+}
+
+/// Loads a grid of `size` voxels from a raw file, which holds nothing but the densities, x first
+/// and z last: either one byte per voxel, mapped from 0-255 to 0-1, or one little-endian 32-bit
+/// float. The file size tells the two apart. VDB files need to be converted first.
+pub fn load_raw_grid(path: &Path, size: [u32; 3]) -> Result<DensityGrid> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    if matches!(extension, Some("nvdb" | "vdb")) {
+        bail!(
+            "{} is a VDB file, only raw grids are supported",
+            path.display()
+        );
+    }
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("failed to read a density grid from {}", path.display()))?;
+    let count = size.iter().map(|&n| n as usize).product::<usize>();
+    let densities = if bytes.len() == count {
+        bytes
+            .iter()
+            .map(|&byte| byte as f32 / 255.)
+            .collect::<Vec<_>>()
+    } else if bytes.len() == 4 * count {
+        bytes
+            .chunks_exact(4)
+            .map(|float| f32::from_le_bytes([float[0], float[1], float[2], float[3]]))
+            .collect()
+    } else {
+        bail!(
+            "{} holds {} bytes, but a {}x{}x{} grid takes {} bytes at 8 bits or {} at 32 bits \
+             per voxel",
+            path.display(),
+            bytes.len(),
+            size[0],
+            size[1],
+            size[2],
+            count,
+            4 * count
+        );
+    };
+    if !densities
+        .iter()
+        .all(|density| (0. ..f32::INFINITY).contains(density))
+    {
+        bail!("{} holds negative or invalid densities", path.display());
+    }
+    Ok(DensityGrid { size, densities })
+}
+
+/// The width, height and depth of the procedural density grid.
+const DENSITY_GRID_SIZE: u32 = 64;
+
+/// Generates a puff of smoke: fractal value noise that fades out towards the edges of the grid,
+/// normalized to a maximum of 1.
+fn density_grid() -> DensityGrid {
+    let size = DENSITY_GRID_SIZE as usize;
+    let mut densities = Vec::with_capacity(size * size * size);
+    for z in 0..size {
+        for y in 0..size {
+            for x in 0..size {
+                let p = [x, y, z].map(|i| (i as f32 + 0.5) / size as f32);
+                let radius = p.iter().map(|c| (c - 0.5) * (c - 0.5)).sum::<f32>().sqrt();
+                let falloff = 1. - smoothstep(0.25, 0.5, radius);
+                let noise = fractal_noise(p.map(|c| c * 4.));
+                densities.push((noise - 0.35).max(0.) * falloff);
+            }
+        }
+    }
+    let max = densities.iter().copied().fold(0., f32::max);
+    if max > 0. {
+        densities.iter_mut().for_each(|density| *density /= max);
+    }
+    DensityGrid {
+        size: [DENSITY_GRID_SIZE; 3],
+        densities,
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
+
+// Four octaves of value noise, in [0, 1].
+fn fractal_noise(p: [f32; 3]) -> f32 {
+    let mut sum = 0.;
+    let mut amplitude = 0.5;
+    let mut frequency = 1.;
+    for octave in 0..4 {
+        sum += amplitude * value_noise(p.map(|c| c * frequency), octave);
+        amplitude *= 0.5;
+        frequency *= 2.;
+    }
+    sum / (1. - amplitude * 2.)
+}
+
+// Interpolates random values at the corners of the unit lattice cell around `p` with a smooth
+// step, which hides the lattice.
+fn value_noise(p: [f32; 3], seed: u32) -> f32 {
+    let cell = p.map(|c| c.floor());
+    let t = [0, 1, 2].map(|i| smoothstep(0., 1., p[i] - cell[i]));
+    let corner = |dx: u32, dy: u32, dz: u32| {
+        let [x, y, z] = [0, 1, 2].map(|i| cell[i] as i32 as u32);
+        let hash = hash((x + dx) ^ hash((y + dy) ^ hash((z + dz) ^ hash(seed))));
+        hash as f32 / u32::MAX as f32
+    };
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let x00 = lerp(corner(0, 0, 0), corner(1, 0, 0), t[0]);
+    let x10 = lerp(corner(0, 1, 0), corner(1, 1, 0), t[0]);
+    let x01 = lerp(corner(0, 0, 1), corner(1, 0, 1), t[0]);
+    let x11 = lerp(corner(0, 1, 1), corner(1, 1, 1), t[0]);
+    lerp(lerp(x00, x10, t[1]), lerp(x01, x11, t[1]), t[2])
+}
+
+// The integer hash of Chris Wellons' "hash prospector" (lowbias32).
+fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
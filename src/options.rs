@@ -0,0 +1,600 @@
+use {
+    anyhow::{bail, Context, Result},
+    exr::prelude::Compression,
+    std::{path::PathBuf, time::Duration},
+};
+
+use crate::{
+    aov::{Aov, AOVS},
+    camera::{Camera, StereoMode},
+    convergence::SampleBudget,
+    exposure::{Exposure, ExposureMode},
+    lights, media,
+    output::{ExrPrecision, ExrSettings, ImageFormat},
+    render::{self, Integrator, TraceConfig},
+    sampler::Sampler,
+    scene::{Motion, Scene},
+    tonemap::{self, Tonemap},
+};
+
+const USAGE: &str = "\
+usage: gpu-path-tracing [OPTIONS] [--still <file> | SEQUENCE OPTIONS]
+
+Renders interactively unless a still or a camera path is given, in which case the image is
+rendered headlessly and written as PNG or OpenEXR.
+
+options:
+    --scene <file.obj>       mesh to render (default: models/cube.obj)
+    --media <file>           fill the scene or some of its objects with the participating
+                             media described in <file> (e.g. models/fog.media)
+    --lights <file>          replace the default light with the point lights described in
+                             <file> (e.g. models/many.lights)
+    --material <id>=<name>   give object <id> (the mesh instances, then the spheres) the
+                             material mirror, diffuse, glass, flint or diamond; repeatable
+    --motion <id> <dx> <dy> <dz> [<ax> <ay> <az> <degrees>]
+                             move object <id> by this offset over the frame, and turn it by
+                             less than 180 degrees about an axis through its origin, which
+                             blurs it within the shutter interval; spheres only move;
+                             repeatable
+    --size <width>x<height>  image size in pixels (default: 1600x1200)
+    --integrator <name>      megakernel, wavefront, bdpt or sppm (default: megakernel); bdpt
+                             is bidirectional and renders caustics, but not media or stereo;
+                             sppm is photon mapping, best for caustics seen through glass
+    --photon-radius <distance>
+                             radius within which sppm first gathers photons (default: 0.05)
+    --workgroup-size <x>x<y> compute workgroup size of the trace kernels (default: 8x8)
+    --bookmark <slot>        start from a camera bookmark saved next to the scene
+    --shutter <open> <close> shutter interval as fractions of a frame (default: 0 0)
+    --stereo <layout>        mono, side-by-side, over-under or ods (default: mono)
+    --ipd <distance>         interpupillary distance in scene units (default: 0.064)
+    --convergence <distance> distance at which the eyes converge (default: orbit distance)
+    --spp <count>            stop at this many samples per pixel (default: 64 for headless
+                             renders, unlimited in the window)
+    --time-limit <seconds>   stop rendering an image after this long
+    --noise-threshold <error>
+                             stop once the noise of all but 0.1% of the pixels, as a relative
+                             standard error, is below this value (e.g. 0.02)
+    --adaptive <error>       stop tracing pixels whose noise is below this value and spend the
+                             samples on the others
+    --sampler <name>         random, sobol, lattice or blue-noise (default: sobol)
+    --max-depth <bounces>    end paths after this many bounces (default: 13)
+    --roulette-depth <bounces>
+                             let Russian roulette end paths after this many bounces, or never
+                             if it is at least the maximum depth (default: 3)
+    --clamp-direct <luminance>
+                             clamp the luminance of each sample of direct light
+    --clamp-indirect <luminance>
+                             clamp the luminance of each sample of indirect light, which
+                             removes most fireflies (e.g. 10)
+    --regularize <roughness> roughen mirrors seen after a diffuse bounce, from 0 (off, the
+                             default) to 1
+    --spectral               trace wavelengths rather than RGB colors, which splits light
+                             into its colors through glass
+    --restir                 light the first hits with ReSTIR, which resamples one light per
+                             pixel and reuses it across frames and neighbors; for scenes with
+                             many lights, not with bdpt or sppm
+    --probe-preview          while the camera moves in the window, light the first diffuse hits
+                             with a grid of irradiance probes rather than tracing the rest of
+                             their paths, for a smooth preview; not with bdpt or sppm
+    --ev <stops>             exposure compensation (default: 0)
+    --iso <sensitivity>      ISO of a physical camera exposure (default: 100)
+    --shutter-speed <time>   exposure time in seconds, e.g. 1/125 (default: 1/125)
+    --f-stop <number>        aperture f-number (default: 16)
+                             any of the last three switches to physical exposure
+    --tonemap <operator>     clamp, reinhard, filmic, aces or agx (default: clamp)
+    --white <value>          linear value that maps to white (default: 4)
+    --denoise                filter the image with the spatio-temporal denoiser
+    --aovs <list>            also write these output variables of headless renders, separated
+                             by commas, or all of them (e.g. normal,depth or all)
+    --exr-precision <type>   half or full float channels in OpenEXR files (default: full)
+    --exr-compression <type> none, zip or piz (default: zip)
+
+    --still <file>           render a single image from the starting camera, as OpenEXR if
+                             the file name ends in .exr and as PNG otherwise
+
+sequence options:
+    --turntable              orbit once around the camera center
+    --keyframes <file>       follow the camera path described in <file>
+    --frames <count>         number of frames in the sequence (default: 120)
+    --output <dir>           directory that receives the frames (default: frames)
+    --format <format>        png or exr (default: png)";
+
+pub struct Options {
+    pub scene: PathBuf,
+    /// The file that describes the participating media, if any.
+    pub media: Option<PathBuf>,
+    /// The file that describes the lights, if not the default one.
+    pub lights: Option<PathBuf>,
+    /// The materials given to objects, by object ID.
+    pub materials: Vec<(u32, String)>,
+    /// The offsets by which objects move over a frame, by object ID.
+    pub motions: Vec<(u32, Motion)>,
+    pub width: u32,
+    pub height: u32,
+    pub integrator: Integrator,
+    /// The compute workgroup dimensions used to dispatch the trace kernels over the image.
+    pub workgroup_size: [u32; 2],
+    pub bookmark: Option<usize>,
+    /// The shutter interval, as fractions of the frame duration.
+    pub shutter: (f32, f32),
+    pub stereo: StereoMode,
+    pub eye_separation: f32,
+    pub convergence: Option<f32>,
+    /// When progressive rendering stops. Headless renders stop at 64 samples per pixel unless
+    /// told otherwise.
+    pub budget: SampleBudget,
+    /// The noise below which adaptive sampling skips a pixel.
+    pub adaptive_threshold: Option<f32>,
+    pub sampler: Sampler,
+    pub max_path_length: u32,
+    pub roulette_depth: u32,
+    /// The highest luminance of a sample of direct and of indirect light.
+    pub clamp_direct: Option<f32>,
+    pub clamp_indirect: Option<f32>,
+    /// The roughness of mirrors after a diffuse bounce, 0 keeping them perfect.
+    pub regularization: f32,
+    pub spectral: bool,
+    /// Whether the first hits are lit by ReSTIR rather than by every light.
+    pub restir: bool,
+    /// Whether irradiance probes light the image while the camera moves in the window.
+    pub probe_preview: bool,
+    /// The radius within which photon mapping first gathers photons.
+    pub photon_radius: f32,
+    pub exposure: Exposure,
+    pub tonemap: Tonemap,
+    pub white_point: f32,
+    pub denoise: bool,
+    /// The output variables written next to each headless image, besides the beauty image.
+    pub aovs: Vec<Aov>,
+    pub exr: ExrSettings,
+    pub mode: Mode,
+}
+
+pub enum Mode {
+    Interactive,
+    Still(PathBuf),
+    Sequence(SequenceOptions),
+}
+
+pub enum CameraPathSource {
+    Turntable,
+    Keyframes(PathBuf),
+}
+
+pub struct SequenceOptions {
+    pub camera_path: CameraPathSource,
+    pub frames: u32,
+    pub output_dir: PathBuf,
+    pub format: ImageFormat,
+}
+
+impl Options {
+    pub fn from_args() -> Result<Options> {
+        let mut options = Options {
+            scene: PathBuf::from("models/cube.obj"),
+            media: None,
+            lights: None,
+            materials: Vec::new(),
+            motions: Vec::new(),
+            width: 1600,
+            height: 1200,
+            integrator: Integrator::Megakernel,
+            workgroup_size: [8, 8],
+            bookmark: None,
+            shutter: (0., 0.),
+            stereo: StereoMode::Mono,
+            eye_separation: 0.064,
+            convergence: None,
+            budget: SampleBudget::default(),
+            adaptive_threshold: None,
+            sampler: Sampler::Sobol,
+            max_path_length: render::DEFAULT_MAX_PATH_LENGTH,
+            roulette_depth: render::DEFAULT_ROULETTE_DEPTH,
+            clamp_direct: None,
+            clamp_indirect: None,
+            regularization: 0.,
+            spectral: false,
+            restir: false,
+            probe_preview: false,
+            photon_radius: render::DEFAULT_PHOTON_RADIUS,
+            exposure: Exposure::default(),
+            tonemap: Tonemap::Clamp,
+            white_point: tonemap::DEFAULT_WHITE_POINT,
+            denoise: false,
+            aovs: Vec::new(),
+            exr: ExrSettings::default(),
+            mode: Mode::Interactive,
+        };
+        let mut still = None;
+        let mut camera_path = None;
+        let mut frames = 120;
+        let mut output_dir = PathBuf::from("frames");
+        let mut format = ImageFormat::Png;
+
+        let mut args = std::env::args().skip(1).peekable();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("missing value for {}\n\n{}", arg, USAGE))
+            };
+            match arg.as_str() {
+                "--scene" => options.scene = value()?.into(),
+                "--media" => options.media = Some(value()?.into()),
+                "--lights" => options.lights = Some(value()?.into()),
+                "--material" => {
+                    let assignment = value()?;
+                    let object = assignment
+                        .split_once('=')
+                        .and_then(|(id, name)| Some((id.parse().ok()?, name.to_string())));
+                    let Some(object) = object else {
+                        bail!("--material expects <id>=<name>, found \"{}\"", assignment);
+                    };
+                    options.materials.push(object);
+                }
+                "--motion" => {
+                    // The rotation is optional, so the values run up to the next option.
+                    let mut values = Vec::new();
+                    while let Some(value) = args.next_if(|next| !next.starts_with("--")) {
+                        values.push(value);
+                    }
+                    options.motions.push(parse_motion(&values)?);
+                }
+                "--size" => {
+                    let size = value()?;
+                    let Some((width, height)) = size.split_once('x') else {
+                        bail!("--size expects <width>x<height>, found \"{}\"", size);
+                    };
+                    options.width = parse_count(width, "--size")?;
+                    options.height = parse_count(height, "--size")?;
+                }
+                "--integrator" => {
+                    options.integrator = match value()?.as_str() {
+                        "megakernel" => Integrator::Megakernel,
+                        "wavefront" => Integrator::Wavefront,
+                        "bdpt" => Integrator::Bdpt,
+                        "sppm" => Integrator::Sppm,
+                        other => bail!("unknown integrator \"{}\"\n\n{}", other, USAGE),
+                    }
+                }
+                "--workgroup-size" => {
+                    let size = value()?;
+                    let Some((x, y)) = size.split_once('x') else {
+                        bail!("--workgroup-size expects <x>x<y>, found \"{}\"", size);
+                    };
+                    let size = [
+                        parse_count(x, "--workgroup-size")?,
+                        parse_count(y, "--workgroup-size")?,
+                    ];
+                    // The limit that WebGPU guarantees on every device.
+                    if size[0].checked_mul(size[1]).is_none_or(|count| count > 256) {
+                        bail!("--workgroup-size allows at most 256 invocations per workgroup");
+                    }
+                    options.workgroup_size = size;
+                }
+                "--bookmark" => {
+                    let slot = value()?;
+                    match slot.parse::<usize>() {
+                        Ok(slot) if slot <= 9 => options.bookmark = Some(slot),
+                        _ => bail!("--bookmark expects a slot from 0 to 9, found \"{}\"", slot),
+                    }
+                }
+                "--shutter" => {
+                    let open = parse_fraction(&value()?, "--shutter")?;
+                    let close = parse_fraction(&value()?, "--shutter")?;
+                    if close < open {
+                        bail!("--shutter closes before it opens");
+                    }
+                    options.shutter = (open, close);
+                }
+                "--stereo" => {
+                    options.stereo = match value()?.as_str() {
+                        "mono" => StereoMode::Mono,
+                        "side-by-side" => StereoMode::SideBySide,
+                        "over-under" => StereoMode::OverUnder,
+                        "ods" => StereoMode::OmniDirectional,
+                        other => bail!("unknown stereo layout \"{}\"\n\n{}", other, USAGE),
+                    }
+                }
+                "--ipd" => options.eye_separation = parse_distance(&value()?, "--ipd")?,
+                "--convergence" => {
+                    options.convergence = Some(parse_distance(&value()?, "--convergence")?)
+                }
+                "--spp" => {
+                    options.budget.samples_per_pixel = Some(parse_count(&value()?, "--spp")?)
+                }
+                "--time-limit" => {
+                    let seconds = value()?;
+                    let time = parse_positive(&seconds, "--time-limit")?;
+                    let Ok(time) = Duration::try_from_secs_f32(time) else {
+                        bail!("--time-limit is too long, found \"{}\"", seconds);
+                    };
+                    options.budget.time = Some(time);
+                }
+                "--noise-threshold" => {
+                    options.budget.noise_threshold =
+                        Some(parse_positive(&value()?, "--noise-threshold")?)
+                }
+                "--adaptive" => {
+                    options.adaptive_threshold = Some(parse_positive(&value()?, "--adaptive")?)
+                }
+                "--max-depth" => {
+                    options.max_path_length = parse_count(&value()?, "--max-depth")?
+                }
+                "--roulette-depth" => {
+                    options.roulette_depth = parse_count(&value()?, "--roulette-depth")?
+                }
+                "--clamp-direct" => {
+                    options.clamp_direct = Some(parse_positive(&value()?, "--clamp-direct")?)
+                }
+                "--clamp-indirect" => {
+                    options.clamp_indirect =
+                        Some(parse_positive(&value()?, "--clamp-indirect")?)
+                }
+                "--regularize" => {
+                    options.regularization = parse_fraction(&value()?, "--regularize")?
+                }
+                "--spectral" => options.spectral = true,
+                "--restir" => options.restir = true,
+                "--probe-preview" => options.probe_preview = true,
+                "--photon-radius" => {
+                    options.photon_radius = parse_positive(&value()?, "--photon-radius")?
+                }
+                "--sampler" => {
+                    options.sampler = Sampler::from_name(&value()?)
+                        .map_err(|error| error.context(USAGE))?
+                }
+                "--ev" => options.exposure.compensation = parse_number(&value()?, "--ev")?,
+                "--iso" => {
+                    options.exposure.mode = ExposureMode::Physical;
+                    options.exposure.iso = parse_positive(&value()?, "--iso")?;
+                }
+                "--shutter-speed" => {
+                    options.exposure.mode = ExposureMode::Physical;
+                    let time = value()?;
+                    options.exposure.shutter_speed = match time.split_once('/') {
+                        Some((numerator, denominator)) => {
+                            parse_positive(numerator, "--shutter-speed")?
+                                / parse_positive(denominator, "--shutter-speed")?
+                        }
+                        None => parse_positive(&time, "--shutter-speed")?,
+                    };
+                }
+                "--f-stop" => {
+                    options.exposure.mode = ExposureMode::Physical;
+                    options.exposure.f_stop = parse_positive(&value()?, "--f-stop")?;
+                }
+                "--tonemap" => {
+                    options.tonemap = Tonemap::from_name(&value()?)
+                        .map_err(|error| error.context(USAGE))?
+                }
+                "--white" => options.white_point = parse_positive(&value()?, "--white")?,
+                "--denoise" => options.denoise = true,
+                "--aovs" => options.aovs = parse_aovs(&value()?)?,
+                "--exr-precision" => {
+                    options.exr.precision = match value()?.as_str() {
+                        "half" => ExrPrecision::Half,
+                        "full" => ExrPrecision::Full,
+                        other => bail!("unknown OpenEXR precision \"{}\"\n\n{}", other, USAGE),
+                    }
+                }
+                "--exr-compression" => {
+                    options.exr.compression = match value()?.as_str() {
+                        "none" => Compression::Uncompressed,
+                        "zip" => Compression::ZIP16,
+                        "piz" => Compression::PIZ,
+                        other => {
+                            bail!("unknown OpenEXR compression \"{}\"\n\n{}", other, USAGE)
+                        }
+                    }
+                }
+                "--still" => still = Some(PathBuf::from(value()?)),
+                "--turntable" => camera_path = Some(CameraPathSource::Turntable),
+                "--keyframes" => {
+                    camera_path = Some(CameraPathSource::Keyframes(value()?.into()))
+                }
+                "--frames" => frames = parse_count(&value()?, "--frames")?,
+                "--output" => output_dir = value()?.into(),
+                "--format" => {
+                    format = match value()?.as_str() {
+                        "png" => ImageFormat::Png,
+                        "exr" => ImageFormat::Exr,
+                        other => bail!("unknown image format \"{}\"\n\n{}", other, USAGE),
+                    }
+                }
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                _ => bail!("unknown argument \"{}\"\n\n{}", arg, USAGE),
+            }
+        }
+
+        // The light subpaths of the bidirectional integrator reach any pixel of the image, which
+        // doesn't go with skipping pixels or splitting the image between two eyes, and it
+        // samples the lights its own way.
+        if options.integrator == Integrator::Bdpt {
+            if options.stereo != StereoMode::Mono {
+                bail!("the bdpt integrator doesn't support --stereo");
+            }
+            if options.adaptive_threshold.is_some() {
+                bail!("the bdpt integrator doesn't support --adaptive");
+            }
+            if options.restir {
+                bail!("the bdpt integrator doesn't support --restir");
+            }
+            if options.probe_preview {
+                bail!("the bdpt integrator doesn't support --probe-preview");
+            }
+        }
+        // Photon mapping keeps its own statistics for every pixel, which must be updated on
+        // every frame.
+        if options.integrator == Integrator::Sppm {
+            if options.adaptive_threshold.is_some() {
+                bail!("the sppm integrator doesn't support --adaptive");
+            }
+            if options.restir {
+                bail!("the sppm integrator doesn't support --restir");
+            }
+            if options.probe_preview {
+                bail!("the sppm integrator doesn't support --probe-preview");
+            }
+        }
+
+        options.mode = match (still, camera_path) {
+            (Some(_), Some(_)) => bail!("--still cannot be combined with a camera path"),
+            (Some(file), None) => Mode::Still(file),
+            (None, Some(camera_path)) => Mode::Sequence(SequenceOptions {
+                camera_path,
+                frames,
+                output_dir,
+                format,
+            }),
+            (None, None) => Mode::Interactive,
+        };
+        Ok(options)
+    }
+
+    /// The budget of headless renders, which must stop at some point.
+    pub fn headless_budget(&self) -> SampleBudget {
+        SampleBudget {
+            samples_per_pixel: Some(self.budget.samples_per_pixel.unwrap_or(64)),
+            ..self.budget
+        }
+    }
+
+    /// The objects rendered alongside the mesh, with the materials and filled with the media
+    /// given on the command line.
+    pub fn load_scene(&self) -> Result<Scene> {
+        let mut scene = Scene::default();
+        for (object_id, material) in &self.materials {
+            scene.set_material(*object_id, material)?;
+        }
+        for (object_id, motion) in &self.motions {
+            scene.set_motion(*object_id, motion)?;
+        }
+        if let Some(path) = &self.media {
+            match self.integrator {
+                Integrator::Bdpt => {
+                    bail!("the bdpt integrator doesn't support participating media")
+                }
+                Integrator::Sppm => {
+                    bail!("the sppm integrator doesn't support participating media")
+                }
+                _ => {}
+            }
+            scene.set_media(media::load(path)?)?;
+        }
+        if let Some(path) = &self.lights {
+            scene.lights = lights::load(path)?;
+        }
+        Ok(scene)
+    }
+
+    pub fn trace_config(&self) -> TraceConfig {
+        TraceConfig {
+            integrator: self.integrator,
+            workgroup_size: self.workgroup_size,
+        }
+    }
+
+    /// Applies the shutter and stereo settings to a camera. They aren't part of a camera
+    /// position, so cameras restored from bookmarks or camera paths need them too.
+    pub fn configure_camera(&self, camera: &mut Camera) {
+        camera.set_shutter(self.shutter.0, self.shutter.1);
+        camera.set_stereo(self.stereo, self.eye_separation, self.convergence);
+    }
+}
+
+fn parse_fraction(value: &str, flag: &str) -> Result<f32> {
+    match value.parse::<f32>() {
+        Ok(fraction) if (0. ..=1.).contains(&fraction) => Ok(fraction),
+        _ => bail!(
+            "{} expects a number between 0 and 1, found \"{}\"",
+            flag,
+            value
+        ),
+    }
+}
+
+// Parses the values of `--motion`: an object ID and an offset, optionally followed by a rotation
+// axis and an angle in degrees.
+fn parse_motion(values: &[String]) -> Result<(u32, Motion)> {
+    const FORM: &str = "<id> <dx> <dy> <dz> [<ax> <ay> <az> <degrees>]";
+    let [id, numbers @ ..] = values else {
+        bail!("--motion expects {}", FORM);
+    };
+    let Ok(id) = id.parse() else {
+        bail!("--motion expects an object ID, found \"{}\"", id);
+    };
+    let numbers = numbers
+        .iter()
+        .map(|value| parse_number(value, "--motion"))
+        .collect::<Result<Vec<_>>>()?;
+    let (offset, axis, degrees) = match numbers[..] {
+        [dx, dy, dz] => ([dx, dy, dz], [0., 1., 0.], 0.),
+        [dx, dy, dz, ax, ay, az, degrees] => ([dx, dy, dz], [ax, ay, az], degrees),
+        _ => bail!("--motion expects {}, found {} values", FORM, values.len()),
+    };
+    let length = axis.iter().map(|c| c * c).sum::<f32>().sqrt();
+    if length == 0. || !length.is_finite() {
+        bail!("--motion expects a nonzero rotation axis");
+    }
+    if degrees.abs() >= 180. {
+        bail!(
+            "--motion turns objects by less than 180 degrees, found {}",
+            degrees
+        );
+    }
+    let motion = Motion {
+        offset,
+        axis: axis.map(|c| c / length),
+        angle: degrees.to_radians(),
+    };
+    Ok((id, motion))
+}
+
+fn parse_number(value: &str, flag: &str) -> Result<f32> {
+    match value.parse::<f32>() {
+        Ok(number) if number.is_finite() => Ok(number),
+        _ => bail!("{} expects a number, found \"{}\"", flag, value),
+    }
+}
+
+fn parse_distance(value: &str, flag: &str) -> Result<f32> {
+    match value.parse::<f32>() {
+        Ok(distance) if distance >= 0. => Ok(distance),
+        _ => bail!(
+            "{} expects a non-negative number, found \"{}\"",
+            flag,
+            value
+        ),
+    }
+}
+
+fn parse_positive(value: &str, flag: &str) -> Result<f32> {
+    match value.parse::<f32>() {
+        Ok(number) if number > 0. && number.is_finite() => Ok(number),
+        _ => bail!("{} expects a positive number, found \"{}\"", flag, value),
+    }
+}
+
+fn parse_aovs(value: &str) -> Result<Vec<Aov>> {
+    let aovs = if value == "all" {
+        AOVS.to_vec()
+    } else {
+        value
+            .split(',')
+            .map(Aov::from_name)
+            .collect::<Result<_>>()
+            .map_err(|error| error.context(USAGE))?
+    };
+    // The beauty image is always written.
+    Ok(aovs.into_iter().filter(|&aov| aov != Aov::Beauty).collect())
+}
+
+fn parse_count(value: &str, flag: &str) -> Result<u32> {
+    match value.parse::<u32>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => bail!("{} expects a positive integer, found \"{}\"", flag, value),
+    }
+}
@@ -0,0 +1,251 @@
+use {
+    anyhow::{Context, Result},
+    exr::prelude::{
+        f16, AnyChannel, AnyChannels, Compression, Encoding, FlatSamples, Image, Layer,
+        LayerAttributes, WritableImage,
+    },
+    std::{
+        fs::File,
+        io::BufWriter,
+        path::{Path, PathBuf},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+use crate::{
+    aov::{Aov, PixelAovs},
+    bookmarks::Bookmark,
+    camera::Camera,
+    render::PathTracer,
+};
+
+/// The file formats that renders can be written to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// The displayed image, tone mapped and display encoded, with one file per AOV.
+    Png,
+    /// The linear radiance and all AOVs as layers of a single file.
+    Exr,
+}
+
+impl ImageFormat {
+    /// The format implied by the extension of `path`, PNG unless it is `.exr`.
+    pub fn from_path(path: &Path) -> ImageFormat {
+        match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("exr") => ImageFormat::Exr,
+            _ => ImageFormat::Png,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Exr => "exr",
+        }
+    }
+}
+
+/// How the floating point channels of an OpenEXR file are stored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExrPrecision {
+    Half,
+    Full,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ExrSettings {
+    pub precision: ExrPrecision,
+    /// One of the lossless OpenEXR compressions: none, ZIP or PIZ.
+    pub compression: Compression,
+}
+
+impl Default for ExrSettings {
+    fn default() -> Self {
+        ExrSettings {
+            precision: ExrPrecision::Full,
+            compression: Compression::ZIP16,
+        }
+    }
+}
+
+/// The file that receives the `layer` of an image written to `path`, inserted before the
+/// extension: `still.png` becomes `still.normal.png`.
+pub fn layer_path(path: &Path, layer: &str) -> PathBuf {
+    match path.extension() {
+        Some(extension) => {
+            path.with_extension(format!("{}.{}", layer, extension.to_string_lossy()))
+        }
+        None => path.with_extension(layer),
+    }
+}
+
+/// The current UTC time as `YYYYMMDD-HHMMSS`, for naming files that are saved on request.
+pub fn timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let (days, time) = (seconds / 86400, seconds % 86400);
+
+    // Convert the days since 1970-01-01 to a date in the proleptic Gregorian calendar, with years
+    // starting in March so that leap days come last. See
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = (month_from_march + 2) % 12 + 1;
+    let year = era * 400 + year_of_era + (month <= 2) as u64;
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Writes the image that `renderer` currently displays to `screenshot_<time>.png` in the working
+/// directory. A sidecar `screenshot_<time>.txt` records the scene, the samples per pixel and the
+/// camera, as a line that can be pasted into a bookmarks file. Returns the path of the image.
+pub fn save_screenshot(
+    renderer: &PathTracer,
+    camera: &Camera,
+    scene: &Path,
+) -> Result<PathBuf> {
+    let path = PathBuf::from(format!("screenshot_{}.png", timestamp()));
+    let pixels = renderer.read_image();
+    write_png(&path, renderer.width(), renderer.height(), &pixels)?;
+
+    let sidecar = path.with_extension("txt");
+    let text = format!(
+        "scene {}\n\
+         size {}x{}\n\
+         samples-per-pixel {}\n\
+         # center.xyz  up.xyz  distance azimuth altitude fov (radians)\n\
+         camera {}\n",
+        scene.display(),
+        renderer.width(),
+        renderer.height(),
+        renderer.samples_per_pixel(),
+        Bookmark::from_camera(camera),
+    );
+    std::fs::write(&sidecar, text)
+        .with_context(|| format!("failed to write {}", sidecar.display()))?;
+    Ok(path)
+}
+
+/// Writes display-encoded 8-bit RGBA pixels, as read back from the display pass, to a PNG.
+pub fn write_png(path: &Path, width: u32, height: u32, pixels: &[[u8; 4]]) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(bytemuck::cast_slice(pixels)))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Reads back the accumulated radiance and AOVs of `renderer` and writes them to a single-part
+/// OpenEXR file. The average radiance of each pixel goes to the R, G and B channels and every
+/// AOV in `aovs` to channels prefixed with its name, e.g. `normal.X`. Nothing is tone mapped or
+/// denoised.
+pub fn write_exr(
+    path: &Path,
+    renderer: &PathTracer,
+    aovs: &[Aov],
+    settings: &ExrSettings,
+) -> Result<()> {
+    let radiance = renderer.read_radiance();
+    let pixel_aovs = renderer.read_aovs();
+
+    let mut channels = Vec::new();
+    for aov in std::iter::once(Aov::Beauty).chain(aovs.iter().copied()) {
+        let prefix = match aov {
+            Aov::Beauty => String::new(),
+            aov => format!("{}.", aov.name()),
+        };
+        for (index, name) in aov_channel_names(aov).iter().enumerate() {
+            let samples = match aov {
+                Aov::MaterialId => FlatSamples::U32(
+                    pixel_aovs.iter().map(|pixel| pixel.ids & 0xffff).collect(),
+                ),
+                Aov::ObjectId => {
+                    FlatSamples::U32(pixel_aovs.iter().map(|pixel| pixel.ids >> 16).collect())
+                }
+                aov => {
+                    let values = radiance
+                        .iter()
+                        .zip(&pixel_aovs)
+                        .map(|(sum, pixel)| aov_value(aov, sum, pixel, index));
+                    match settings.precision {
+                        ExrPrecision::Half => {
+                            FlatSamples::F16(values.map(f16::from_f32).collect())
+                        }
+                        ExrPrecision::Full => FlatSamples::F32(values.collect()),
+                    }
+                }
+            };
+            let name = format!("{}{}", prefix, name);
+            channels.push(AnyChannel::new(name.as_str(), samples));
+        }
+    }
+
+    let encoding = Encoding {
+        compression: settings.compression,
+        ..Encoding::default()
+    };
+    let layer = Layer::new(
+        (renderer.width() as usize, renderer.height() as usize),
+        LayerAttributes::default(),
+        encoding,
+        AnyChannels::sort(channels.into()),
+    );
+    Image::from_layer(layer)
+        .write()
+        .to_file(path)
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn aov_channel_names(aov: Aov) -> &'static [&'static str] {
+    match aov {
+        Aov::Beauty | Aov::Albedo | Aov::Direct | Aov::Indirect => &["R", "G", "B"],
+        Aov::Normal => &["X", "Y", "Z"],
+        Aov::Depth => &["Z"],
+        Aov::MaterialId | Aov::ObjectId => &["ID"],
+        Aov::SampleCount => &["N"],
+    }
+}
+
+// The value of channel `index` of `aov` in a pixel whose radiance samples add up to `sum`. Unlike
+// the display, the sample count is absolute and the depth of the sky is infinite.
+fn aov_value(aov: Aov, sum: &[f32; 4], pixel: &PixelAovs, index: usize) -> f32 {
+    let samples = sum[3].max(1.);
+    let hits = pixel.hit_count.max(1.);
+    match aov {
+        Aov::Normal => {
+            let length = pixel.normal.iter().map(|n| n * n).sum::<f32>().sqrt();
+            if length > 0. {
+                pixel.normal[index] / length
+            } else {
+                0.
+            }
+        }
+        Aov::Depth if pixel.hit_count == 0. => f32::INFINITY,
+        Aov::Depth => pixel.depth / hits,
+        Aov::Albedo => pixel.albedo[index] / hits,
+        Aov::Direct => (sum[index] - pixel.indirect[index]) / samples,
+        Aov::Indirect => pixel.indirect[index] / samples,
+        Aov::SampleCount => sum[3],
+        Aov::Beauty | Aov::MaterialId | Aov::ObjectId => sum[index] / samples,
+    }
+}
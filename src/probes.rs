@@ -0,0 +1,134 @@
+use crate::types::BoundingBox;
+use crate::wavefront::read_write_storage_entry;
+
+/// The number of probes. Must match probes.wgsl.
+const PROBE_COUNT: u32 = 512;
+/// The rows of the probe texture: the coefficients of each color channel, then the statistics.
+const PROBE_ROWS: u32 = 4;
+/// The margin left around the scene by the probe grid, as a fraction of its size.
+const MARGIN: f32 = 0.1;
+
+/// The grid of irradiance probes of the preview mode (see probes.wgsl), the kernel that updates
+/// them and the running averages it keeps from one frame to the next.
+pub struct Probes {
+    update: wgpu::ComputePipeline,
+    // Updated in place, then copied to the texture that the trace kernels read.
+    state: wgpu::Buffer,
+
+    // Like the megakernel's bind groups, these alternate between the two sample textures.
+    bind_groups: [wgpu::BindGroup; 2],
+}
+
+impl Probes {
+    /// `trace_entries` are the bindings shared with the megakernel, and `create_bind_groups`
+    /// builds the two alternating bind groups for a layout that adds the given entries to them.
+    pub fn new(
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        trace_entries: &[wgpu::BindGroupLayoutEntry],
+        create_bind_groups: impl FnOnce(
+            &wgpu::BindGroupLayout,
+            &[wgpu::BindGroupEntry],
+        ) -> [wgpu::BindGroup; 2],
+    ) -> Probes {
+        // One vec4 per probe and row, laid out like the texture. The probes start out dark.
+        let state = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("probe state"),
+            size: (PROBE_COUNT * PROBE_ROWS) as u64 * 16,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        let layout_entries = [trace_entries, &[read_write_storage_entry(25)]].concat();
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("probes"),
+            entries: &layout_entries,
+        });
+        let bind_groups = create_bind_groups(
+            &layout,
+            &[wgpu::BindGroupEntry {
+                binding: 25,
+                resource: state.as_entire_binding(),
+            }],
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&layout],
+            ..Default::default()
+        });
+        let update = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("probe_update_cs"),
+            layout: Some(&pipeline_layout),
+            module: shader_module,
+            entry_point: "probe_update_cs",
+        });
+
+        Probes {
+            update,
+            state,
+            bind_groups,
+        }
+    }
+
+    /// Records the kernel that traces a few more rays from every probe in frame `frame_count`,
+    /// and copies the probes to `irradiance` for the trace kernels.
+    pub fn encode(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        frame_count: u32,
+        irradiance: &wgpu::Texture,
+    ) {
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("probe pass"),
+                timestamp_writes: None,
+            });
+            pass.set_bind_group(0, &self.bind_groups[(frame_count % 2) as usize], &[]);
+            pass.set_pipeline(&self.update);
+            // One workgroup per probe.
+            pass.dispatch_workgroups(PROBE_COUNT, 1, 1);
+        }
+        encoder.copy_buffer_to_texture(
+            wgpu::ImageCopyBuffer {
+                buffer: &self.state,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(PROBE_COUNT * 16),
+                    rows_per_image: None,
+                },
+            },
+            irradiance.as_image_copy(),
+            irradiance.size(),
+        );
+    }
+}
+
+/// Returns the box that the grid of probes spans, which covers `scene_bounds` with a margin.
+pub fn grid_bounds(scene_bounds: &BoundingBox) -> BoundingBox {
+    let mut bounds = *scene_bounds;
+    for i in 0..3 {
+        let margin = (bounds.max[i] - bounds.min[i]).max(f32::EPSILON) * MARGIN;
+        bounds.min[i] -= margin;
+        bounds.max[i] += margin;
+    }
+    bounds
+}
+
+/// Creates the texture through which the trace kernels read the probes (see `probe_irradiance`
+/// in probes.wgsl).
+pub fn create_irradiance_texture(device: &wgpu::Device) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("probe irradiance"),
+        size: wgpu::Extent3d {
+            width: PROBE_COUNT,
+            height: PROBE_ROWS,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
@@ -0,0 +1,206 @@
+// Irradiance probes for fast previews while the camera moves. A grid of probes spans the scene,
+// and each keeps the light that paths leaving its center bring back from every direction, as
+// first-order spherical harmonics (four coefficients per color channel). Every frame traces a few
+// rays from each probe and folds them into a running average, so the probes improve
+// incrementally. A ray that hits a diffuse surface brings back the direct and ambient light there,
+// as the megakernel shades it, times the light of the probes around the hit, which they gathered
+// in the previous frames: the probes pick up one more bounce every frame (as in DDGI, Majercik et
+// al., "Dynamic Diffuse Global Illumination with Ray-Traced Irradiance Fields", 2019).
+//
+// The lighting doesn't depend on the camera, so the probes stay valid while it moves. In preview
+// mode, the first diffuse hit of every camera path stops there and takes the light of the probes
+// around it, averaged over the cosine lobe of its normal, instead of tracing the rest of the path.
+// That is blurrier and darker in corners than path tracing, as the probes have no visibility and
+// ignore participating media, but its noise is gone within a few frames.
+
+// The number of probes along each axis, and in all. Must match probes.rs.
+const PROBE_GRID: vec3u = vec3u(8u, 8u, 8u);
+const PROBE_COUNT: u32 = 512u;
+// The rays traced from every probe in every frame, one per invocation of the update kernel.
+const PROBE_RAYS: u32 = 64u;
+// The running average of a probe weighs its latest frame at least this much, so that it keeps
+// up with changes in the lighting.
+const PROBE_MIN_BLEND: f32 = 0.02;
+// Probes whose rays see back faces more often than this are inside an object and left out.
+const PROBE_MAX_BACKFACES: f32 = 0.25;
+// The mirrors and glass that a probe ray goes through before it gives up.
+const PROBE_MAX_SPECULAR_BOUNCES: u32 = 4u;
+const PROBE_SEED: u32 = 0x5eedb0beu;
+// The normalization constants of the first two bands of the real spherical harmonics.
+const SH_C0: f32 = 0.2820948;
+const SH_C1: f32 = 0.4886025;
+
+// The probes as the trace kernels see them, copied from `probe_state` after every update. Row
+// c < 3 holds the coefficients of color channel c of every probe, and row 3 the number of frames
+// it has averaged and the fraction of its rays that hit back faces.
+@group(0) @binding(24) var probe_irradiance: texture_2d<f32>;
+@group(0) @binding(25) var<storage, read_write> probe_state: array<vec4f>;
+
+fn probe_cell(probe: u32) -> vec3u {
+  return vec3(probe % PROBE_GRID.x, probe / PROBE_GRID.x % PROBE_GRID.y,
+    probe / (PROBE_GRID.x * PROBE_GRID.y));
+}
+
+fn probe_index(cell: vec3u) -> u32 {
+  return cell.x + PROBE_GRID.x * (cell.y + PROBE_GRID.y * cell.z);
+}
+
+// The probes sit at the centers of the cells of a grid over the probe bounds.
+fn probe_position(cell: vec3u) -> vec3f {
+  let bounds = uniforms.probe_bounds;
+  return mix(bounds.min, bounds.max, (vec3f(cell) + 0.5) / vec3f(PROBE_GRID));
+}
+
+fn sh_basis(direction: vec3f) -> vec4f {
+  return vec4(SH_C0, SH_C1 * direction.y, SH_C1 * direction.z, SH_C1 * direction.x);
+}
+
+// The light that `probe` brings back from the directions around `normal`, weighted by their
+// cosine. The convolution with the cosine lobe scales the second band by 2/3 relative to the
+// first (Ramamoorthi and Hanrahan, "An Efficient Representation for Irradiance Environment
+// Maps", 2001).
+fn probe_light(probe: u32, normal: vec3f) -> vec3f {
+  let basis = sh_basis(normal) * vec4(1., 2. / 3., 2. / 3., 2. / 3.);
+  var rgb: vec3f;
+  for (var channel = 0u; channel < 3u; channel += 1u) {
+    rgb[channel] = dot(textureLoad(probe_irradiance, vec2(probe, channel), 0), basis);
+  }
+  return max(rgb, vec3(0.));
+}
+
+// The light that the rest of a path leaving the diffuse surface at `point` would bring back,
+// interpolated between the eight probes around it. Probes behind the surface count less, and
+// those inside objects not at all. In spectral mode it tints D65, like the sky.
+fn probe_radiance(point: vec3f, normal: vec3f) -> vec3f {
+  let bounds = uniforms.probe_bounds;
+  let grid = (point - bounds.min) / (bounds.max - bounds.min) * vec3f(PROBE_GRID) - 0.5;
+  let base = vec3u(clamp(vec3i(floor(grid)), vec3i(0), vec3i(PROBE_GRID) - 2));
+  let fraction = clamp(grid - vec3f(base), vec3(0.), vec3(1.));
+  var sum = vec3(0.);
+  var weight_sum = 0.;
+  for (var corner = 0u; corner < 8u; corner += 1u) {
+    let offset = vec3(corner & 1u, (corner >> 1u) & 1u, corner >> 2u);
+    let cell = base + offset;
+    let probe = probe_index(cell);
+    let trilinear = mix(1. - fraction, fraction, vec3f(offset));
+    let to_probe = probe_position(cell) - point;
+    let facing = (dot(to_probe / max(length(to_probe), 1e-6), normal) + 1.) / 2.;
+    let backfaces = textureLoad(probe_irradiance, vec2(probe, 3u), 0).y;
+    if backfaces > PROBE_MAX_BACKFACES {
+      continue;
+    }
+    let weight = trilinear.x * trilinear.y * trilinear.z * (facing * facing + 0.2);
+    sum += weight * probe_light(probe, normal);
+    weight_sum += weight;
+  }
+  if weight_sum <= 0. {
+    return vec3(0.);
+  }
+  let rgb = sum / weight_sum;
+  if uniforms.spectral == 0u {
+    return rgb;
+  }
+  return spectrum(rgb) * d65(wavelengths.lambda);
+}
+
+// The light that the diffuse surface at `point` reflects along a path that stops there: its
+// direct and ambient light, as `scatter` applies it, times the light of the probes around it for
+// the rest of the path.
+fn probe_lit_surface(
+  point: vec3f,
+  normal: vec3f,
+  material: Material,
+  time: f32,
+  medium: u32,
+) -> vec3f {
+  let direct = direct_lighting(point, normal, time, medium);
+  return spectrum(material.color) * (direct + spectrum(AMBIENT)) * probe_radiance(point, normal);
+}
+
+struct ProbeRay {
+  direction: vec3f,
+  // 1 if the ray hit the back face of a surface first.
+  backface: f32,
+  radiance: vec3f,
+}
+var<workgroup> probe_rays: array<ProbeRay, PROBE_RAYS>;
+
+// The light that `ray` brings back to its probe, in RGB.
+fn trace_probe_ray(first_ray: Ray) -> ProbeRay {
+  var ray = first_ray;
+  var throughput = vec3(1.);
+  let medium = find_medium(NO_OBJECT);
+  for (var bounce = 0u; bounce <= PROBE_MAX_SPECULAR_BOUNCES; bounce += 1u) {
+    let hit = intersect_scene(ray);
+    if !is_intersection_valid(hit) {
+      let sky = radiance_to_rgb(throughput * sky_radiance(ray));
+      return ProbeRay(first_ray.direction, 0., sky);
+    }
+    if bounce == 0u && dot(ray.direction, hit.normal) > 0. {
+      return ProbeRay(first_ray.direction, 1., vec3(0.));
+    }
+    let material = materials[hit.material_index];
+    let point = point_on_ray(ray, hit.t);
+    if material.specular != 1u {
+      let lit = throughput * probe_lit_surface(point, hit.normal, material, ray.time, medium);
+      return ProbeRay(first_ray.direction, 0., radiance_to_rgb(lit));
+    }
+    let reflected = sample_material(ray.direction, hit.normal, material, false);
+    throughput *= spectrum(material.color) * reflected.weight;
+    ray = Ray(point, reflected.direction, ray.time);
+  }
+  return ProbeRay(first_ray.direction, 0., vec3(0.));
+}
+
+// Traces the rays of the probe of the workgroup, one per invocation, and folds them into its
+// running average.
+@compute @workgroup_size(PROBE_RAYS)
+fn probe_update_cs(
+  @builtin(workgroup_id) workgroup_id: vec3u,
+  @builtin(local_invocation_index) ray_index: u32,
+) {
+  let probe = workgroup_id.x;
+  let statistics = probe_state[3u * PROBE_COUNT + probe];
+  // The frame count starts over whenever the camera moves, so the samples follow the number of
+  // updates of the probe instead.
+  let update = u32(statistics.x);
+  let seed = jenkins_hash(probe ^ PROBE_SEED);
+  sequence = SampleSequence(vec2(ray_index, probe), seed, update, 0u);
+  rng.state = jenkins_hash((probe * PROBE_RAYS + ray_index) ^ jenkins_hash(update));
+  let time = mix(uniforms.camera.shutter_open, uniforms.camera.shutter_close, rand_f32());
+  wavelengths = path_wavelengths(rand_f32(), false);
+
+  // The rays follow a Fibonacci lattice over the sphere, shifted at random in every frame.
+  let frame_seed = jenkins_hash(seed ^ update);
+  let shift = vec2(u32_to_unit(frame_seed), u32_to_unit(jenkins_hash(frame_seed)));
+  let u = vec2(
+    (f32(ray_index) + shift.x) / f32(PROBE_RAYS),
+    fract(f32(ray_index) * 0.618034 + shift.y),
+  );
+  let ray = Ray(probe_position(probe_cell(probe)), sample_uniform_sphere(u), time);
+  probe_rays[ray_index] = trace_probe_ray(ray);
+  workgroupBarrier();
+
+  // One invocation per row: the coefficients of each color channel, then the statistics.
+  if ray_index > 3u {
+    return;
+  }
+  var estimate = vec4(0.);
+  for (var i = 0u; i < PROBE_RAYS; i += 1u) {
+    let sample = probe_rays[i];
+    if ray_index < 3u {
+      estimate += sample.radiance[ray_index] * sh_basis(sample.direction);
+    } else {
+      estimate.y += sample.backface;
+    }
+  }
+  // The rays are spread uniformly over the sphere.
+  estimate *= select(4. * PI, 1., ray_index == 3u) / f32(PROBE_RAYS);
+  let blend = max(1. / (statistics.x + 1.), PROBE_MIN_BLEND);
+  let index = ray_index * PROBE_COUNT + probe;
+  var updated = mix(probe_state[index], estimate, blend);
+  if ray_index == 3u {
+    updated.x = statistics.x + 1.;
+  }
+  probe_state[index] = updated;
+}
@@ -2,11 +2,49 @@
 // SPDX-License-Identifier: CC-BY-4.0
 
 use bytemuck::{Pod, Zeroable};
+use std::borrow::Cow;
 use wgpu::util::DeviceExt;
 
+use crate::aov::{Aov, PixelAovs};
+use crate::bdpt::Bdpt;
 use crate::camera::{Camera, CameraUniforms};
-use crate::types::BoundingBox;
-use crate::types::Light;
+use crate::convergence::NoiseEstimator;
+use crate::denoise::Denoiser;
+use crate::exposure::Exposure;
+use crate::load::Mesh;
+use crate::media::{DensityGrid, MAX_MEDIA};
+use crate::probes::{self, Probes};
+use crate::restir::Restir;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::spectrum;
+use crate::sppm::Sppm;
+use crate::tonemap::Tonemap;
+use crate::types::{BoundingBox, Medium};
+use crate::wavefront::Wavefront;
+
+/// How paths are traced on the GPU.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Integrator {
+    /// A single kernel follows each path from the camera to its end.
+    Megakernel,
+    /// Paths advance one bounce at a time through separate kernels (see wavefront.wgsl).
+    Wavefront,
+    /// Subpaths from the camera and from the lights are connected to each other (see
+    /// bdpt.wgsl).
+    Bdpt,
+    /// Photons shot from the lights are gathered around the first diffuse hits of the camera
+    /// paths, within a radius that shrinks over the frames (see sppm.wgsl).
+    Sppm,
+}
+
+/// Settings that shape the trace kernels. They are fixed once the renderer is created.
+#[derive(Debug, Copy, Clone)]
+pub struct TraceConfig {
+    pub integrator: Integrator,
+    /// The workgroup dimensions of the kernels that run over the image.
+    pub workgroup_size: [u32; 2],
+}
 
 pub struct PathTracer {
     device: wgpu::Device,
@@ -14,17 +52,74 @@ pub struct PathTracer {
 
     uniforms: Uniforms,
     uniform_buffer: wgpu::Buffer,
+    display_uniforms: DisplayUniforms,
+    display_uniform_buffer: wgpu::Buffer,
+
+    // The trace pass adds one sample per pixel to the running sum, reading the previous sum from
+    // one texture and storing the new one in the other.
+    trace_pipeline: wgpu::ComputePipeline,
+    trace_bind_groups: [wgpu::BindGroup; 2],
+    workgroup_size: [u32; 2],
+    // Replaces the single trace kernel when the wavefront integrator is selected.
+    wavefront: Option<Wavefront>,
+    // Likewise for the bidirectional integrator and photon mapping.
+    bdpt: Option<Bdpt>,
+    sppm: Option<Sppm>,
+    // Created the first time ReSTIR is enabled, from the trace module and inputs kept for it.
+    restir: Option<Restir>,
+    // Likewise for the probe preview, which is used while the camera is moving.
+    probes: Option<Probes>,
+    probe_preview: bool,
+    camera_moving: bool,
+    trace_module: wgpu::ShaderModule,
+    inputs: TraceInputs,
+    radiance_samples: [wgpu::Texture; 2],
+    outputs: TraceOutputs,
 
+    // Created the first time denoising is enabled, along with the display bind group that shows
+    // its output.
+    denoiser: Option<(Denoiser, wgpu::BindGroup)>,
+    denoise: bool,
+    noise_estimator: NoiseEstimator,
+    // The display pass turns the latest sum into an image for the render target. Its compute
+    // variant writes the same image to `display_image` for headless rendering.
     display_pipeline: wgpu::RenderPipeline,
+    display_image_pipeline: wgpu::ComputePipeline,
+    display_layout: wgpu::BindGroupLayout,
     display_bind_groups: [wgpu::BindGroup; 2],
+    display_image: wgpu::Texture,
+}
 
-    mesh_buffer: wgpu::Buffer,
-    triangle_count: usize,
+// What the trace pass writes besides the radiance: the first hit of the latest camera rays, which
+// guides the denoiser, and the AOVs accumulated over all samples.
+struct TraceOutputs {
+    albedo_guide: wgpu::Texture,
+    normal_depth_guide: wgpu::Texture,
+    aovs: wgpu::Buffer,
+}
 
-    lights_buffer: wgpu::Buffer,
-    light_count: u32,
+// What the trace kernels read besides the uniforms and samples: the storage buffers that describe
+// the scene geometry and lighting, the blue-noise tile of the sampler, the density grids of the
+// media, the table of spectral rendering and the lights picked by ReSTIR.
+struct TraceInputs {
+    mesh: wgpu::Buffer,
+    lights: wgpu::Buffer,
+    spheres: wgpu::Buffer,
+    instances: wgpu::Buffer,
+    blue_noise: wgpu::Texture,
+    density_grids: wgpu::Texture,
+    rgb_to_spectrum: wgpu::Texture,
+    resampled_lights: wgpu::Texture,
+    probe_irradiance: wgpu::Texture,
 }
 
+/// The number of bounces after which paths end, unless set otherwise.
+pub const DEFAULT_MAX_PATH_LENGTH: u32 = 13;
+/// The number of bounces after which Russian roulette may end paths, unless set otherwise.
+pub const DEFAULT_ROULETTE_DEPTH: u32 = 3;
+/// The radius within which photon mapping first gathers photons, unless set otherwise.
+pub const DEFAULT_PHOTON_RADIUS: f32 = 0.05;
+
 #[derive(Copy, Clone, Pod, Zeroable)]
 #[repr(C)]
 struct Uniforms {
@@ -34,10 +129,67 @@ struct Uniforms {
     frame_count: u32,
     triangle_count: u32,
     light_count: u32,
-    _pad0: u32,
-    _pad1: u32,
-    _pad2: u32,
+    sphere_count: u32,
+    instance_count: u32,
+    /// The relative error below which pixels are skipped, or 0 to disable adaptive sampling.
+    adaptive_threshold: f32,
     mesh_bounds: BoundingBox,
+    sampler_type: u32,
+    /// The number of bounces after which paths end.
+    max_path_length: u32,
+    /// The number of bounces after which Russian roulette may end paths early.
+    roulette_depth: u32,
+    /// The highest luminance of a sample of direct and of indirect light, or 0 not to clamp.
+    clamp_direct: f32,
+    clamp_indirect: f32,
+    /// The roughness given to mirrors after a diffuse bounce, or 0 to keep them perfect.
+    regularization: f32,
+    medium_count: u32,
+    _pad: u32,
+    /// The fog and the media inside objects, as bounded by `Scene::bounded_media`.
+    media: [Medium; MAX_MEDIA],
+    /// Where the density grids of the media lie in their texture, indexed by `Medium::grid`.
+    grid_placements: [GridPlacement; MAX_MEDIA],
+    /// 1 if paths carry wavelengths rather than RGB colors.
+    spectral: u32,
+    /// Divides spectral radiance so that a white sky looks white (see `spectrum::d65_luminance`).
+    d65_luminance: f32,
+    /// 1 if the first hits are lit by the lights picked by ReSTIR, and 1 in `restir_history`
+    /// if it also ran on the previous frame, traced with `previous_camera`.
+    restir: u32,
+    restir_history: u32,
+    previous_camera: CameraUniforms,
+    /// The radius within which photon mapping first gathers photons.
+    photon_radius: f32,
+    /// 1 if the first diffuse hits are lit by the irradiance probes, which span `probe_bounds`.
+    probe_preview: u32,
+    _pad1: [u32; 2],
+    probe_bounds: BoundingBox,
+}
+
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+struct GridPlacement {
+    size: [u32; 3],
+    /// The first layer of the grid.
+    z_offset: u32,
+}
+
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+struct DisplayUniforms {
+    frame_count: u32,
+    /// Scale applied to the averaged radiance before tone mapping.
+    exposure: f32,
+    tonemap: u32,
+    white_point: f32,
+    /// The image pixel shown at the center of the target when zoomed in.
+    zoom_center: [f32; 2],
+    zoom: f32,
+    /// 1 if the displayed texture holds denoised averages rather than sums of samples.
+    denoised: u32,
+    aov: u32,
+    _pad: [u32; 3],
 }
 
 impl PathTracer {
@@ -46,47 +198,88 @@ impl PathTracer {
         queue: wgpu::Queue,
         width: u32,
         height: u32,
-        mesh_buffer: wgpu::Buffer,
-        triangle_count: usize,
-        mesh_bounds: BoundingBox,
+        mesh: Mesh,
+        scene: &Scene,
+        config: TraceConfig,
     ) -> PathTracer {
         device.on_uncaptured_error(Box::new(|error| {
             panic!("Aborting due to an error: {}", error);
         }));
 
-        let shader_module = compile_shader_module(&device);
-        let (display_pipeline, display_layout) =
-            create_display_pipeline(&device, &shader_module);
+        // The workgroup size is a compile-time constant of the trace kernels.
+        let workgroup_size = config.workgroup_size;
+        let trace_code = format!(
+            "const WORKGROUP_SIZE_X: u32 = {}u;\nconst WORKGROUP_SIZE_Y: u32 = {}u;\n{}\n{}\n{}\n{}\n{}\n{}",
+            workgroup_size[0],
+            workgroup_size[1],
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/shaders.wgsl")),
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/wavefront.wgsl")),
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/bdpt.wgsl")),
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/restir.wgsl")),
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/sppm.wgsl")),
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/probes.wgsl")),
+        );
+        let trace_module = compile_shader_module(&device, "trace", trace_code.into());
+        let display_module = compile_shader_module(
+            &device,
+            "display",
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/display.wgsl")).into(),
+        );
+        let (trace_pipeline, trace_layout) = create_trace_pipeline(&device, &trace_module);
+        let (display_pipeline, display_image_pipeline, display_layout) =
+            create_display_pipelines(&device, &display_module);
 
         // Initialize the uniform buffer.
-        let lights = vec![
-
-
-            Light {
-                position: [2.0, 1.0, 1.0],
-                _pad0: 0,
-                color: [0.8, 0.8, 0.8],     // Lumière blanche douce
-                intensity: 1.5,              // Faible intensité pour le remplissage
-            },
-        ];
-
         let lights_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Lights Buffer"),
-            contents: bytemuck::cast_slice(&lights),
+            contents: bytemuck::cast_slice(&scene.lights),
             usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
         });
+        let (density_grids, grid_placements) =
+            create_density_grid_texture(&device, &queue, &scene.media.grids);
+        let inputs = TraceInputs {
+            mesh: mesh.buffer,
+            lights: lights_buffer,
+            spheres: create_storage_buffer(&device, "spheres", &scene.spheres),
+            instances: create_storage_buffer(&device, "mesh instances", &scene.mesh_instances),
+            blue_noise: create_blue_noise_texture(&device, &queue),
+            density_grids,
+            rgb_to_spectrum: create_rgb_to_spectrum_texture(&device, &queue),
+            resampled_lights: create_resampled_lights_texture(&device, width, height),
+            probe_irradiance: probes::create_irradiance_texture(&device),
+        };
 
+        let media = scene.bounded_media(&mesh.bounds);
         let uniforms = Uniforms {
             camera: CameraUniforms::zeroed(),
             width,
             height,
             frame_count: 0,
-            triangle_count: triangle_count as u32,
-            light_count: lights.len() as u32,
-            _pad0: 0,
-            _pad1: 0,
-            _pad2: 0,
-            mesh_bounds,
+            triangle_count: mesh.triangle_count as u32,
+            light_count: scene.lights.len() as u32,
+            sphere_count: scene.spheres.len() as u32,
+            instance_count: scene.mesh_instances.len() as u32,
+            adaptive_threshold: 0.,
+            mesh_bounds: mesh.bounds,
+            sampler_type: Sampler::Sobol as u32,
+            max_path_length: DEFAULT_MAX_PATH_LENGTH,
+            roulette_depth: DEFAULT_ROULETTE_DEPTH,
+            clamp_direct: 0.,
+            clamp_indirect: 0.,
+            regularization: 0.,
+            medium_count: media.len() as u32,
+            _pad: 0,
+            media: std::array::from_fn(|i| media.get(i).copied().unwrap_or(Medium::zeroed())),
+            grid_placements,
+            spectral: 0,
+            d65_luminance: spectrum::d65_luminance(),
+            restir: 0,
+            restir_history: 0,
+            previous_camera: CameraUniforms::zeroed(),
+            photon_radius: DEFAULT_PHOTON_RADIUS,
+            probe_preview: 0,
+            _pad1: [0; 2],
+            probe_bounds: probes::grid_bounds(&scene.bounds(&mesh.bounds)),
         };
         let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("uniforms"),
@@ -95,27 +288,135 @@ impl PathTracer {
             mapped_at_creation: false,
         });
 
+        let display_uniforms = DisplayUniforms {
+            frame_count: 0,
+            exposure: 1.,
+            tonemap: Tonemap::Clamp as u32,
+            white_point: crate::tonemap::DEFAULT_WHITE_POINT,
+            zoom_center: [width as f32 / 2., height as f32 / 2.],
+            zoom: 1.,
+            denoised: 0,
+            aov: Aov::Beauty as u32,
+            _pad: [0; 3],
+        };
+        let display_uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("display uniforms"),
+            size: std::mem::size_of::<DisplayUniforms>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
         let radiance_samples = create_sample_textures(&device, width, height);
+        let outputs = create_trace_outputs(&device, width, height);
+        let display_image = create_display_image(&device, width, height);
+        let trace_bind_groups = create_trace_bind_groups(
+            &device,
+            &trace_layout,
+            &radiance_samples,
+            &uniform_buffer,
+            &inputs,
+            &outputs,
+            &[],
+        );
+        let wavefront = (config.integrator == Integrator::Wavefront).then(|| {
+            Wavefront::new(
+                &device,
+                &trace_module,
+                width * height,
+                workgroup_size,
+                &trace_layout_entries(),
+                |layout, entries| {
+                    create_trace_bind_groups(
+                        &device,
+                        layout,
+                        &radiance_samples,
+                        &uniform_buffer,
+                        &inputs,
+                        &outputs,
+                        entries,
+                    )
+                },
+            )
+        });
+        let bdpt = (config.integrator == Integrator::Bdpt).then(|| {
+            Bdpt::new(
+                &device,
+                &trace_module,
+                width * height,
+                workgroup_size,
+                &trace_layout_entries(),
+                |layout, entries| {
+                    create_trace_bind_groups(
+                        &device,
+                        layout,
+                        &radiance_samples,
+                        &uniform_buffer,
+                        &inputs,
+                        &outputs,
+                        entries,
+                    )
+                },
+            )
+        });
+        let sppm = (config.integrator == Integrator::Sppm).then(|| {
+            Sppm::new(
+                &device,
+                &trace_module,
+                width * height,
+                workgroup_size,
+                &trace_layout_entries(),
+                |layout, entries| {
+                    create_trace_bind_groups(
+                        &device,
+                        layout,
+                        &radiance_samples,
+                        &uniform_buffer,
+                        &inputs,
+                        &outputs,
+                        entries,
+                    )
+                },
+            )
+        });
         let display_bind_groups = create_display_bind_groups(
             &device,
             &display_layout,
             &radiance_samples,
-            &uniform_buffer,
-            &mesh_buffer,
-            &lights_buffer,
+            &display_uniform_buffer,
+            &display_image,
+            &outputs.aovs,
         );
+        let noise_estimator = NoiseEstimator::new(&device, &radiance_samples, &outputs.aovs);
 
         PathTracer {
             device,
             queue,
             uniforms,
             uniform_buffer,
+            display_uniforms,
+            display_uniform_buffer,
+            trace_pipeline,
+            trace_bind_groups,
+            workgroup_size,
+            wavefront,
+            bdpt,
+            sppm,
+            restir: None,
+            probes: None,
+            probe_preview: false,
+            camera_moving: false,
+            trace_module,
+            inputs,
+            radiance_samples,
+            outputs,
+            denoiser: None,
+            denoise: false,
+            noise_estimator,
             display_pipeline,
+            display_image_pipeline,
+            display_layout,
             display_bind_groups,
-            mesh_buffer,
-            triangle_count,
-            lights_buffer,
-            light_count: lights.len() as u32,
+            display_image,
         }
     }
 
@@ -123,16 +424,515 @@ impl PathTracer {
         self.uniforms.frame_count = 0;
     }
 
-    pub fn render_frame(&mut self, camera: &Camera, target: &wgpu::TextureView) {
+    /// Sets the scale applied to linear radiance before display. The accumulated samples stay
+    /// valid, so this takes effect on the next frame without restarting the render.
+    pub fn set_exposure(&mut self, exposure: &Exposure) {
+        self.display_uniforms.exposure = exposure.scale();
+    }
+
+    /// Selects the tone mapping operator and the linear value that it maps to white. Like the
+    /// exposure, this only affects the display pass.
+    pub fn set_tonemap(&mut self, tonemap: Tonemap, white_point: f32) {
+        self.display_uniforms.tonemap = tonemap as u32;
+        self.display_uniforms.white_point = white_point;
+    }
+
+    /// Magnifies the displayed image by `zoom` around the image position `center` (in pixels),
+    /// for inspecting individual pixels. The samples are unaffected.
+    pub fn set_zoom(&mut self, zoom: f32, center: [f32; 2]) {
+        self.display_uniforms.zoom = zoom;
+        self.display_uniforms.zoom_center = center;
+    }
+
+    /// Turns the denoiser on or off. It is set up the first time it is enabled, and starts from
+    /// the samples accumulated so far, which are denoised right away.
+    pub fn set_denoise(&mut self, enabled: bool) {
+        self.denoise = enabled;
+        if !enabled {
+            return;
+        }
+        let (denoiser, _) = self.denoiser.get_or_insert_with(|| {
+            let denoiser = Denoiser::new(
+                &self.device,
+                &self.radiance_samples,
+                &self.outputs.albedo_guide,
+                &self.outputs.normal_depth_guide,
+            );
+            let bind_group = create_display_bind_group(
+                &self.device,
+                &self.display_layout,
+                denoiser.output(),
+                &self.display_uniform_buffer,
+                &self.display_image,
+                &self.outputs.aovs,
+            );
+            (denoiser, bind_group)
+        });
+        denoiser.discard_history();
+
+        let frame_count = self.display_uniforms.frame_count;
+        if frame_count > 0 {
+            let mut encoder =
+                self.device
+                    .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                        label: Some("denoise frame"),
+                    });
+            denoiser.encode(
+                &self.queue,
+                &mut encoder,
+                &self.uniforms.camera,
+                frame_count,
+                &self.outputs.normal_depth_guide,
+            );
+            self.queue.submit(Some(encoder.finish()));
+        }
+    }
+
+    /// Enables adaptive sampling, which stops tracing pixels once the relative standard error of
+    /// their mean falls below `threshold`, or disables it with `None`. Either way, the samples
+    /// accumulated so far stay valid.
+    pub fn set_adaptive_threshold(&mut self, threshold: Option<f32>) {
+        self.uniforms.adaptive_threshold = threshold.unwrap_or(0.);
+    }
+
+    /// Selects how the trace pass draws its samples. Samples from different samplers don't mix
+    /// well, so changing it starts over.
+    pub fn set_sampler(&mut self, sampler: Sampler) {
+        if self.uniforms.sampler_type != sampler as u32 {
+            self.uniforms.sampler_type = sampler as u32;
+            self.reset_samples();
+        }
+    }
+
+    /// Limits paths to `max_path_length` bounces, and lets Russian roulette end them early once
+    /// they have bounced `roulette_depth` times. Both change the image, so this starts over.
+    pub fn set_path_length(&mut self, max_path_length: u32, roulette_depth: u32) {
+        if (self.uniforms.max_path_length, self.uniforms.roulette_depth)
+            != (max_path_length, roulette_depth)
+        {
+            self.uniforms.max_path_length = max_path_length;
+            self.uniforms.roulette_depth = roulette_depth;
+            self.reset_samples();
+        }
+    }
+
+    /// Clamps the luminance of each sample of direct light (the sky seen directly or after one
+    /// bounce) and of indirect light to the given limits, or not with `None`. Clamping removes
+    /// fireflies at the cost of some energy. Changing the limits starts over.
+    pub fn set_sample_clamp(&mut self, direct: Option<f32>, indirect: Option<f32>) {
+        let limits = (direct.unwrap_or(0.), indirect.unwrap_or(0.));
+        if (self.uniforms.clamp_direct, self.uniforms.clamp_indirect) != limits {
+            (self.uniforms.clamp_direct, self.uniforms.clamp_indirect) = limits;
+            self.reset_samples();
+        }
+    }
+
+    /// Sets the roughness in [0, 1] that mirrors take on once a path has bounced off a diffuse
+    /// surface (path regularization), with 0 keeping them perfect. Changing it starts over.
+    pub fn set_regularization(&mut self, roughness: f32) {
+        if self.uniforms.regularization != roughness {
+            self.uniforms.regularization = roughness;
+            self.reset_samples();
+        }
+    }
+
+    /// Switches between tracing RGB colors and tracing wavelengths, which disperses light through
+    /// glass. The two converge to different images, so switching starts over.
+    pub fn set_spectral(&mut self, spectral: bool) {
+        if self.uniforms.spectral != spectral as u32 {
+            self.uniforms.spectral = spectral as u32;
+            self.reset_samples();
+        }
+    }
+
+    /// Sets the radius within which photon mapping first gathers photons around each visible
+    /// point, which also sizes the cells of the photon grid. It shrinks from there as photons
+    /// add up. Changing it starts over.
+    pub fn set_photon_radius(&mut self, radius: f32) {
+        if self.uniforms.photon_radius != radius {
+            self.uniforms.photon_radius = radius;
+            self.reset_samples();
+        }
+    }
+
+    /// Turns ReSTIR direct lighting on or off (see restir.wgsl), which lights the first hits
+    /// with one resampled light rather than all of them. It is set up the first time it is
+    /// enabled, and starts over, as it converges to a slightly different image. The
+    /// bidirectional integrator and photon mapping sample the lights their own way and ignore
+    /// it.
+    pub fn set_restir(&mut self, enabled: bool) {
+        if self.bdpt.is_some() || self.sppm.is_some() || self.uniforms.restir == enabled as u32
+        {
+            return;
+        }
+        self.uniforms.restir = enabled as u32;
+        // The reservoirs are only kept up to date while ReSTIR runs.
+        self.uniforms.restir_history = 0;
+        self.reset_samples();
+        if enabled && self.restir.is_none() {
+            self.restir = Some(Restir::new(
+                &self.device,
+                &self.trace_module,
+                self.width(),
+                self.height(),
+                self.workgroup_size,
+                &trace_layout_entries(),
+                |layout, entries| {
+                    create_trace_bind_groups(
+                        &self.device,
+                        layout,
+                        &self.radiance_samples,
+                        &self.uniform_buffer,
+                        &self.inputs,
+                        &self.outputs,
+                        entries,
+                    )
+                },
+            ));
+        }
+    }
+
+    /// Turns the probe preview on or off (see probes.wgsl). While the camera moves, the first
+    /// diffuse hits then take their light from a grid of irradiance probes instead of tracing
+    /// the rest of their paths, and path tracing resumes once it stops. The probes are set up
+    /// the first time it is enabled, and updated on every frame while it is. The bidirectional
+    /// integrator and photon mapping ignore it.
+    pub fn set_probe_preview(&mut self, enabled: bool) {
+        if self.bdpt.is_some() || self.sppm.is_some() {
+            return;
+        }
+        self.probe_preview = enabled;
+        if enabled && self.probes.is_none() {
+            self.probes = Some(Probes::new(
+                &self.device,
+                &self.trace_module,
+                &trace_layout_entries(),
+                |layout, entries| {
+                    create_trace_bind_groups(
+                        &self.device,
+                        layout,
+                        &self.radiance_samples,
+                        &self.uniform_buffer,
+                        &self.inputs,
+                        &self.outputs,
+                        entries,
+                    )
+                },
+            ));
+        }
+        self.update_probe_preview();
+    }
+
+    /// Tells whether the camera is moving, during which the probe preview, if enabled, replaces
+    /// path tracing. Switching between the two starts over.
+    pub fn set_camera_moving(&mut self, moving: bool) {
+        self.camera_moving = moving;
+        self.update_probe_preview();
+    }
+
+    fn update_probe_preview(&mut self) {
+        let preview = (self.probe_preview && self.camera_moving) as u32;
+        if self.uniforms.probe_preview != preview {
+            self.uniforms.probe_preview = preview;
+            self.reset_samples();
+        }
+    }
+
+    /// Selects what the display pass shows. The denoiser only applies to the beauty image.
+    pub fn set_aov(&mut self, aov: Aov) {
+        self.display_uniforms.aov = aov as u32;
+    }
+
+    pub fn width(&self) -> u32 {
+        self.uniforms.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.uniforms.height
+    }
+
+    /// The number of samples accumulated per pixel since the last reset.
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.uniforms.frame_count
+    }
+
+    /// Runs the display transform over the accumulated radiance without a render target and
+    /// returns the image as 8-bit RGBA in row major order. This blocks until all previously
+    /// submitted frames have finished rendering.
+    pub fn read_image(&self) -> Vec<[u8; 4]> {
+        // The image is read back whole, however far the window is zoomed in.
+        self.write_display_uniforms(DisplayUniforms {
+            zoom: 1.,
+            zoom_center: [self.width() as f32 / 2., self.height() as f32 / 2.],
+            ..self.display_uniforms
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("display image"),
+            });
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("display image pass"),
+            timestamp_writes: None,
+        });
+        compute_pass.set_pipeline(&self.display_image_pipeline);
+        compute_pass.set_bind_group(0, self.display_bind_group(), &[]);
+        compute_pass.dispatch_workgroups(
+            self.width().div_ceil(8),
+            self.height().div_ceil(8),
+            1,
+        );
+        drop(compute_pass);
+        self.queue.submit(Some(encoder.finish()));
+
+        bytemuck::cast_slice(&self.read_texture(&self.display_image, 4)).to_vec()
+    }
+
+    /// Returns the accumulated radiance in row major order: the sum of the samples of each pixel
+    /// in RGB and their count in A. This blocks until all previously submitted frames have
+    /// finished rendering.
+    pub fn read_radiance(&self) -> Vec<[f32; 4]> {
+        let latest = (self.uniforms.frame_count + 1) % 2;
+        let texture = &self.radiance_samples[latest as usize];
+        bytemuck::cast_slice(&self.read_texture(texture, 16)).to_vec()
+    }
+
+    /// Returns the accumulated AOVs of every pixel in row major order. This blocks until all
+    /// previously submitted frames have finished rendering.
+    pub fn read_aovs(&self) -> Vec<PixelAovs> {
+        let aovs = &self.outputs.aovs;
+        let readback_buffer = self.create_readback_buffer(aovs.size());
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("read AOVs"),
+            });
+        encoder.copy_buffer_to_buffer(aovs, 0, &readback_buffer, 0, aovs.size());
+        let data = self.map_readback(encoder, &readback_buffer);
+        bytemuck::cast_slice(&data).to_vec()
+    }
+
+    /// Returns the fraction of pixels whose relative standard error is still above `threshold`.
+    /// This blocks until all previously submitted frames have finished rendering.
+    pub fn noisy_pixel_fraction(&self, threshold: f32) -> f32 {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("count noisy pixels"),
+            });
+        self.noise_estimator.encode(
+            &self.queue,
+            &mut encoder,
+            self.uniforms.frame_count,
+            threshold,
+        );
+        let readback_buffer = self.create_readback_buffer(4);
+        encoder.copy_buffer_to_buffer(
+            self.noise_estimator.count_buffer(),
+            0,
+            &readback_buffer,
+            0,
+            4,
+        );
+        let data = self.map_readback(encoder, &readback_buffer);
+        let count: u32 = bytemuck::pod_read_unaligned(&data);
+        count as f32 / (self.width() * self.height()) as f32
+    }
+
+    /// Blocks until all previously submitted frames have finished rendering.
+    pub fn wait_for_gpu(&self) {
+        self.device.poll(wgpu::Maintain::Wait);
+    }
+
+    // The bind group that shows the denoised image or otherwise the latest sum.
+    fn display_bind_group(&self) -> &wgpu::BindGroup {
+        match &self.denoiser {
+            Some((_, bind_group)) if self.shows_denoised() => bind_group,
+            _ => {
+                let latest = (self.display_uniforms.frame_count + 1) % 2;
+                &self.display_bind_groups[latest as usize]
+            }
+        }
+    }
+
+    fn shows_denoised(&self) -> bool {
+        self.denoise && self.display_uniforms.aov == Aov::Beauty as u32
+    }
+
+    fn write_display_uniforms(&self, uniforms: DisplayUniforms) {
+        let uniforms = DisplayUniforms {
+            denoised: self.shows_denoised() as u32,
+            ..uniforms
+        };
+        self.queue.write_buffer(
+            &self.display_uniform_buffer,
+            0,
+            bytemuck::bytes_of(&uniforms),
+        );
+    }
+
+    // Returns the tightly packed contents of `texture`.
+    fn read_texture(&self, texture: &wgpu::Texture, bytes_per_pixel: u32) -> Vec<u8> {
+        let (width, height) = (texture.width(), texture.height());
+        let row_bytes = width * bytes_per_pixel;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_row_bytes = row_bytes.div_ceil(align) * align;
+
+        let readback_buffer = self.create_readback_buffer((padded_row_bytes * height) as u64);
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("read texture"),
+            });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &readback_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row_bytes),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size(),
+        );
+
+        let data = self.map_readback(encoder, &readback_buffer);
+        let mut bytes = Vec::with_capacity((row_bytes * height) as usize);
+        for row in data.chunks_exact(padded_row_bytes as usize) {
+            bytes.extend_from_slice(&row[..row_bytes as usize]);
+        }
+        bytes
+    }
+
+    fn create_readback_buffer(&self, size: u64) -> wgpu::Buffer {
+        self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        })
+    }
+
+    // Submits `encoder`, which copies into `readback_buffer`, and waits until the copy can be
+    // read.
+    fn map_readback<'a>(
+        &self,
+        encoder: wgpu::CommandEncoder,
+        readback_buffer: &'a wgpu::Buffer,
+    ) -> wgpu::BufferView<'a> {
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = readback_buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| {
+            result.expect("failed to map the readback buffer");
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        slice.get_mapped_range()
+    }
+
+    /// Adds one sample per pixel to the accumulated radiance. Nothing is presented; call
+    /// `display` to show the result.
+    pub fn trace(&mut self, camera: &Camera) {
+        self.uniforms.previous_camera = self.uniforms.camera;
         self.uniforms.camera = *camera.uniforms();
         self.uniforms.frame_count += 1;
         self.queue
             .write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&self.uniforms));
+        // The next frame can reuse the reservoirs of this one.
+        self.uniforms.restir_history = self.uniforms.restir;
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("trace frame"),
+            });
+
+        if let Some(probes) = self.probes.as_ref().filter(|_| self.probe_preview) {
+            probes.encode(
+                &mut encoder,
+                self.uniforms.frame_count,
+                &self.inputs.probe_irradiance,
+            );
+        }
+        if let Some(restir) = self.restir.as_ref().filter(|_| self.uniforms.restir == 1) {
+            restir.encode(
+                &mut encoder,
+                self.uniforms.frame_count,
+                &self.inputs.resampled_lights,
+            );
+        }
+        // Frame N reads the sum from texture N % 2 and writes the new sum to texture (N + 1) % 2.
+        if let Some(wavefront) = &self.wavefront {
+            wavefront.encode(
+                &mut encoder,
+                self.uniforms.frame_count,
+                self.uniforms.max_path_length,
+                self.width(),
+                self.height(),
+            );
+        } else if let Some(bdpt) = &self.bdpt {
+            bdpt.encode(
+                &mut encoder,
+                self.uniforms.frame_count,
+                self.width(),
+                self.height(),
+            );
+        } else if let Some(sppm) = &self.sppm {
+            sppm.encode(
+                &mut encoder,
+                self.uniforms.frame_count,
+                self.width(),
+                self.height(),
+            );
+        } else {
+            let mut trace_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("trace pass"),
+                timestamp_writes: None,
+            });
+            trace_pass.set_pipeline(&self.trace_pipeline);
+            trace_pass.set_bind_group(
+                0,
+                &self.trace_bind_groups[(self.uniforms.frame_count % 2) as usize],
+                &[],
+            );
+            let [x, y] = self.workgroup_size;
+            trace_pass.dispatch_workgroups(
+                self.width().div_ceil(x),
+                self.height().div_ceil(y),
+                1,
+            );
+        }
+        if let Some((denoiser, _)) = &mut self.denoiser {
+            if self.denoise {
+                denoiser.encode(
+                    &self.queue,
+                    &mut encoder,
+                    &self.uniforms.camera,
+                    self.uniforms.frame_count,
+                    &self.outputs.normal_depth_guide,
+                );
+            }
+        }
+
+        self.queue.submit(Some(encoder.finish()));
+
+        // The display shows the latest completed sum. Resetting the samples doesn't change what
+        // is on screen until the next frame has been traced.
+        self.display_uniforms.frame_count = self.uniforms.frame_count;
+    }
+
+    /// Draws the accumulated radiance into `target`. This only reads the samples, so it can run
+    /// as often as needed (e.g. after changing the exposure) without tracing.
+    pub fn display(&self, target: &wgpu::TextureView) {
+        self.write_display_uniforms(self.display_uniforms);
 
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("render frame"),
+                label: Some("display frame"),
             });
 
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
@@ -149,11 +949,7 @@ impl PathTracer {
         });
 
         render_pass.set_pipeline(&self.display_pipeline);
-        render_pass.set_bind_group(
-            0,
-            &self.display_bind_groups[(self.uniforms.frame_count % 2) as usize],
-            &[],
-        );
+        render_pass.set_bind_group(0, self.display_bind_group(), &[]);
 
         // Draw 1 instance of a polygon with 6 vertices
         render_pass.draw(0..6, 0..1);
@@ -166,26 +962,210 @@ impl PathTracer {
     }
 }
 
-fn compile_shader_module(device: &wgpu::Device) -> wgpu::ShaderModule {
-    use std::borrow::Cow;
-
-    let code = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/shaders.wgsl"));
+fn compile_shader_module(
+    device: &wgpu::Device,
+    label: &str,
+    code: Cow<'_, str>,
+) -> wgpu::ShaderModule {
     device.create_shader_module(wgpu::ShaderModuleDescriptor {
-        label: None,
-        source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(code)),
+        label: Some(label),
+        source: wgpu::ShaderSource::Wgsl(code),
     })
 }
 
-fn create_display_pipeline(
+// The bindings shared by every trace kernel: the uniforms, the sample textures, the scene, the
+// denoiser guides, the AOVs, the blue-noise tile and the density grids.
+fn trace_layout_entries() -> Vec<wgpu::BindGroupLayoutEntry> {
+    vec![
+        wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 1,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::StorageTexture {
+                access: wgpu::StorageTextureAccess::WriteOnly,
+                format: wgpu::TextureFormat::Rgba32Float,
+                view_dimension: wgpu::TextureViewDimension::D2,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 4,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 5,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 6,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 7,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::StorageTexture {
+                access: wgpu::StorageTextureAccess::WriteOnly,
+                format: wgpu::TextureFormat::Rgba16Float,
+                view_dimension: wgpu::TextureViewDimension::D2,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 8,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::StorageTexture {
+                access: wgpu::StorageTextureAccess::WriteOnly,
+                format: wgpu::TextureFormat::Rgba32Float,
+                view_dimension: wgpu::TextureViewDimension::D2,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 9,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 10,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 11,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D3,
+                multisampled: false,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 12,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D3,
+                multisampled: false,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 17,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 24,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        },
+    ]
+}
+
+fn create_trace_pipeline(
     device: &wgpu::Device,
     shader_module: &wgpu::ShaderModule,
-) -> (wgpu::RenderPipeline, wgpu::BindGroupLayout) {
+) -> (wgpu::ComputePipeline, wgpu::BindGroupLayout) {
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: None,
+        entries: &trace_layout_entries(),
+    });
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("trace"),
+        layout: Some(
+            &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&bind_group_layout],
+                ..Default::default()
+            }),
+        ),
+        module: shader_module,
+        entry_point: "trace_cs",
+    });
+    (pipeline, bind_group_layout)
+}
+
+fn create_display_pipelines(
+    device: &wgpu::Device,
+    shader_module: &wgpu::ShaderModule,
+) -> (
+    wgpu::RenderPipeline,
+    wgpu::ComputePipeline,
+    wgpu::BindGroupLayout,
+) {
     let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
         label: None,
         entries: &[
             wgpu::BindGroupLayoutEntry {
                 binding: 0,
-                visibility: wgpu::ShaderStages::FRAGMENT,
+                visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
                 ty: wgpu::BindingType::Buffer {
                     ty: wgpu::BufferBindingType::Uniform,
                     has_dynamic_offset: false,
@@ -195,7 +1175,7 @@ fn create_display_pipeline(
             },
             wgpu::BindGroupLayoutEntry {
                 binding: 1,
-                visibility: wgpu::ShaderStages::FRAGMENT,
+                visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
                 ty: wgpu::BindingType::Texture {
                     sample_type: wgpu::TextureSampleType::Float { filterable: false },
                     view_dimension: wgpu::TextureViewDimension::D2,
@@ -205,27 +1185,17 @@ fn create_display_pipeline(
             },
             wgpu::BindGroupLayoutEntry {
                 binding: 2,
-                visibility: wgpu::ShaderStages::FRAGMENT,
+                visibility: wgpu::ShaderStages::COMPUTE,
                 ty: wgpu::BindingType::StorageTexture {
                     access: wgpu::StorageTextureAccess::WriteOnly,
-                    format: wgpu::TextureFormat::Rgba32Float,
+                    format: wgpu::TextureFormat::Rgba8Unorm,
                     view_dimension: wgpu::TextureViewDimension::D2,
                 },
                 count: None,
             },
             wgpu::BindGroupLayoutEntry {
                 binding: 3,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: true },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            wgpu::BindGroupLayoutEntry {
-                binding: 4,
-                visibility: wgpu::ShaderStages::FRAGMENT,
+                visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
                 ty: wgpu::BindingType::Buffer {
                     ty: wgpu::BufferBindingType::Storage { read_only: true },
                     has_dynamic_offset: false,
@@ -235,14 +1205,13 @@ fn create_display_pipeline(
             },
         ],
     });
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        bind_group_layouts: &[&bind_group_layout],
+        ..Default::default()
+    });
     let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
         label: Some("display"),
-        layout: Some(
-            &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                bind_group_layouts: &[&bind_group_layout],
-                ..Default::default()
-            }),
-        ),
+        layout: Some(&pipeline_layout),
         primitive: wgpu::PrimitiveState {
             topology: wgpu::PrimitiveTopology::TriangleList,
             front_face: wgpu::FrontFace::Ccw,
@@ -267,7 +1236,32 @@ fn create_display_pipeline(
         multisample: wgpu::MultisampleState::default(),
         multiview: None,
     });
-    (pipeline, bind_group_layout)
+    let image_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("display image"),
+        layout: Some(&pipeline_layout),
+        module: shader_module,
+        entry_point: "display_cs",
+    });
+    (pipeline, image_pipeline, bind_group_layout)
+}
+
+// Creates a read-only storage buffer holding `items`. WebGPU does not allow empty bindings, so an
+// empty slice still gets a buffer with room for one (ignored) element.
+fn create_storage_buffer<T: Pod>(
+    device: &wgpu::Device,
+    label: &str,
+    items: &[T],
+) -> wgpu::Buffer {
+    let contents = if items.is_empty() {
+        vec![0u8; std::mem::size_of::<T>()]
+    } else {
+        bytemuck::cast_slice(items).to_vec()
+    };
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(label),
+        contents: &contents,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+    })
 }
 
 fn create_sample_textures(
@@ -286,87 +1280,330 @@ fn create_sample_textures(
         sample_count: 1,
         dimension: wgpu::TextureDimension::D2,
         format: wgpu::TextureFormat::Rgba32Float,
-        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::STORAGE_BINDING
+            | wgpu::TextureUsages::COPY_SRC,
         view_formats: &[],
     };
     // Create two textures with the same parameters.
     [device.create_texture(&desc), device.create_texture(&desc)]
 }
 
-fn create_display_bind_groups(
+fn create_trace_outputs(device: &wgpu::Device, width: u32, height: u32) -> TraceOutputs {
+    let texture = |label, format| {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::STORAGE_BINDING
+                | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        })
+    };
+    TraceOutputs {
+        albedo_guide: texture("albedo guide", wgpu::TextureFormat::Rgba16Float),
+        normal_depth_guide: texture("normal and depth guide", wgpu::TextureFormat::Rgba32Float),
+        aovs: device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("AOVs"),
+            size: (width * height) as u64 * std::mem::size_of::<PixelAovs>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        }),
+    }
+}
+
+fn create_blue_noise_texture(device: &wgpu::Device, queue: &wgpu::Queue) -> wgpu::Texture {
+    let size = crate::sampler::BLUE_NOISE_SIZE;
+    device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("blue noise"),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(&crate::sampler::blue_noise()),
+    )
+}
+
+// The density grids of the media are stacked along z in a single texture, which is a single voxel
+// if there are none. Returns the texture and where each grid lies in it.
+fn create_density_grid_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    grids: &[DensityGrid],
+) -> (wgpu::Texture, [GridPlacement; MAX_MEDIA]) {
+    let width = grids.iter().map(|grid| grid.size[0]).max().unwrap_or(1);
+    let height = grids.iter().map(|grid| grid.size[1]).max().unwrap_or(1);
+    let depth = grids.iter().map(|grid| grid.size[2]).sum::<u32>().max(1);
+    let mut densities = vec![0.; (width * height * depth) as usize];
+    let mut placements = [GridPlacement::zeroed(); MAX_MEDIA];
+    let mut z_offset = 0;
+    for (grid, placement) in grids.iter().zip(&mut placements) {
+        let [grid_width, grid_height, grid_depth] = grid.size;
+        for z in 0..grid_depth {
+            for y in 0..grid_height {
+                let source = ((z * grid_height + y) * grid_width) as usize;
+                let target = (((z_offset + z) * height + y) * width) as usize;
+                densities[target..target + grid_width as usize]
+                    .copy_from_slice(&grid.densities[source..source + grid_width as usize]);
+            }
+        }
+        *placement = GridPlacement {
+            size: grid.size,
+            z_offset,
+        };
+        z_offset += grid_depth;
+    }
+    let texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("density grids"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: depth,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(&densities),
+    );
+    (texture, placements)
+}
+
+// The coefficients of `spectrum::rgb_to_spectrum_table`, one layer per depth entry.
+fn create_rgb_to_spectrum_texture(device: &wgpu::Device, queue: &wgpu::Queue) -> wgpu::Texture {
+    let size = spectrum::TABLE_SIZE as u32;
+    device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("rgb to spectrum"),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 3 * size,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(&spectrum::rgb_to_spectrum_table()),
+    )
+}
+
+// The light that ReSTIR picked for the first hit of each pixel (see `resampled_lights` in
+// restir.wgsl).
+fn create_resampled_lights_texture(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("resampled lights"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
+
+fn create_display_image(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("display image"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    })
+}
+
+fn create_trace_bind_groups(
     device: &wgpu::Device,
     layout: &wgpu::BindGroupLayout,
     textures: &[wgpu::Texture; 2],
     uniform_buffer: &wgpu::Buffer,
-    mesh_buffer: &wgpu::Buffer,
-    lights_buffer: &wgpu::Buffer,
+    inputs: &TraceInputs,
+    outputs: &TraceOutputs,
+    extra_entries: &[wgpu::BindGroupEntry],
 ) -> [wgpu::BindGroup; 2] {
-    let views = [
-        textures[0].create_view(&wgpu::TextureViewDescriptor::default()),
-        textures[1].create_view(&wgpu::TextureViewDescriptor::default()),
-    ];
-    [
-        // Bind group with view[0] assigned to binding 1 and view[1] assigned to binding 2.
+    let views = textures
+        .each_ref()
+        .map(|t| t.create_view(&Default::default()));
+    let albedo_view = outputs.albedo_guide.create_view(&Default::default());
+    let normal_depth_view = outputs.normal_depth_guide.create_view(&Default::default());
+    let blue_noise_view = inputs.blue_noise.create_view(&Default::default());
+    let density_grid_view = inputs.density_grids.create_view(&Default::default());
+    let rgb_to_spectrum_view = inputs.rgb_to_spectrum.create_view(&Default::default());
+    let resampled_lights_view = inputs.resampled_lights.create_view(&Default::default());
+    let probe_irradiance_view = inputs.probe_irradiance.create_view(&Default::default());
+    let bind_group = |old_samples: &wgpu::TextureView, new_samples: &wgpu::TextureView| {
+        let entries = [
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: uniform_buffer,
+                    offset: 0,
+                    size: None,
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(old_samples),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(new_samples),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: inputs.mesh.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: inputs.lights.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: inputs.spheres.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: inputs.instances.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 7,
+                resource: wgpu::BindingResource::TextureView(&albedo_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 8,
+                resource: wgpu::BindingResource::TextureView(&normal_depth_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 9,
+                resource: outputs.aovs.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 10,
+                resource: wgpu::BindingResource::TextureView(&blue_noise_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 11,
+                resource: wgpu::BindingResource::TextureView(&density_grid_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 12,
+                resource: wgpu::BindingResource::TextureView(&rgb_to_spectrum_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 17,
+                resource: wgpu::BindingResource::TextureView(&resampled_lights_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 24,
+                resource: wgpu::BindingResource::TextureView(&probe_irradiance_view),
+            },
+        ];
         device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
             layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: uniform_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::TextureView(&views[0]),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&views[1]),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: mesh_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 4,
-                    resource: lights_buffer.as_entire_binding(),
-                },
-            ],
-        }),
+            entries: &[&entries, extra_entries].concat(),
+        })
+    };
+    [
+        // Bind group with view[0] assigned to binding 1 and view[1] assigned to binding 2.
+        bind_group(&views[0], &views[1]),
         // Bind group with view[1] assigned to binding 1 and view[0] assigned to binding 2.
-        device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: uniform_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::TextureView(&views[1]),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&views[0]),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: mesh_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 4,
-                    resource: lights_buffer.as_entire_binding(),
-                },
-            ],
-        }),
+        bind_group(&views[1], &views[0]),
     ]
 }
+
+fn create_display_bind_groups(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    textures: &[wgpu::Texture; 2],
+    uniform_buffer: &wgpu::Buffer,
+    display_image: &wgpu::Texture,
+    aovs: &wgpu::Buffer,
+) -> [wgpu::BindGroup; 2] {
+    // Bind group N displays the sum held in texture N.
+    textures.each_ref().map(|samples| {
+        create_display_bind_group(device, layout, samples, uniform_buffer, display_image, aovs)
+    })
+}
+
+fn create_display_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    radiance: &wgpu::Texture,
+    uniform_buffer: &wgpu::Buffer,
+    display_image: &wgpu::Texture,
+    aovs: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(
+                    &radiance.create_view(&Default::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(
+                    &display_image.create_view(&Default::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: aovs.as_entire_binding(),
+            },
+        ],
+    })
+}
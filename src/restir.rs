@@ -0,0 +1,149 @@
+use crate::wavefront::read_write_storage_entry;
+
+/// The passes of ReSTIR direct lighting (see restir.wgsl) and the reservoirs they keep from one
+/// frame to the next.
+pub struct Restir {
+    initial: wgpu::ComputePipeline,
+    spatial: wgpu::ComputePipeline,
+    // Written by the spatial pass and copied to the texture that the trace kernels read.
+    output: wgpu::Texture,
+
+    // Like the megakernel's bind groups, these alternate between the two sample textures.
+    bind_groups: [wgpu::BindGroup; 2],
+    workgroup_size: [u32; 2],
+}
+
+impl Restir {
+    /// `trace_entries` are the bindings shared with the megakernel, and `create_bind_groups`
+    /// builds the two alternating bind groups for a layout that adds the given entries to them.
+    pub fn new(
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        width: u32,
+        height: u32,
+        workgroup_size: [u32; 2],
+        trace_entries: &[wgpu::BindGroupLayoutEntry],
+        create_bind_groups: impl FnOnce(
+            &wgpu::BindGroupLayout,
+            &[wgpu::BindGroupEntry],
+        ) -> [wgpu::BindGroup; 2],
+    ) -> Restir {
+        // One `Reservoir` (32 bytes) per pixel in each.
+        let reservoir_buffer = |label| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: (width * height) as u64 * 32,
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        };
+        let reservoirs = reservoir_buffer("restir reservoirs");
+        let candidates = reservoir_buffer("restir candidates");
+        let output = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("resampled lights output"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+
+        let output_entry = wgpu::BindGroupLayoutEntry {
+            binding: 20,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::StorageTexture {
+                access: wgpu::StorageTextureAccess::WriteOnly,
+                format: wgpu::TextureFormat::Rgba32Float,
+                view_dimension: wgpu::TextureViewDimension::D2,
+            },
+            count: None,
+        };
+        let layout_entries = [
+            trace_entries,
+            &[
+                read_write_storage_entry(18),
+                read_write_storage_entry(19),
+                output_entry,
+            ],
+        ]
+        .concat();
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("restir"),
+            entries: &layout_entries,
+        });
+        let output_view = output.create_view(&Default::default());
+        let bind_groups = create_bind_groups(
+            &layout,
+            &[
+                wgpu::BindGroupEntry {
+                    binding: 18,
+                    resource: reservoirs.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 19,
+                    resource: candidates.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 20,
+                    resource: wgpu::BindingResource::TextureView(&output_view),
+                },
+            ],
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&layout],
+            ..Default::default()
+        });
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: shader_module,
+                entry_point,
+            })
+        };
+
+        Restir {
+            initial: pipeline("restir_initial_cs"),
+            spatial: pipeline("restir_spatial_cs"),
+            output,
+            bind_groups,
+            workgroup_size,
+        }
+    }
+
+    /// Records the passes that pick a light for the first hit of every pixel in frame
+    /// `frame_count`, and copies their picks to `resampled_lights` for the trace kernels.
+    pub fn encode(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        frame_count: u32,
+        resampled_lights: &wgpu::Texture,
+    ) {
+        let size = self.output.size();
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("restir pass"),
+                timestamp_writes: None,
+            });
+            pass.set_bind_group(0, &self.bind_groups[(frame_count % 2) as usize], &[]);
+            let [x, y] = self.workgroup_size;
+            // The spatial pass reads the reservoirs of the neighbors once all are done.
+            for pipeline in [&self.initial, &self.spatial] {
+                pass.set_pipeline(pipeline);
+                pass.dispatch_workgroups(size.width.div_ceil(x), size.height.div_ceil(y), 1);
+            }
+        }
+        encoder.copy_texture_to_texture(
+            self.output.as_image_copy(),
+            resampled_lights.as_image_copy(),
+            size,
+        );
+    }
+}
@@ -0,0 +1,287 @@
+// ReSTIR direct lighting (Bitterli et al., "Spatiotemporal Reservoir Resampling for Real-Time Ray
+// Tracing with Dynamic Direct Lighting", 2020). Tracing a shadow ray towards every light at every
+// bounce gets slow with hundreds of lights, so in this mode the first hit of each camera path is
+// lit by a single light, picked by resampled importance sampling (RIS) and weighted so that the
+// estimate stays close to the sum over all lights. Two passes run before the trace kernels:
+//
+//   initial  streams a few uniformly chosen lights through a reservoir per pixel, weighted by
+//            their unshadowed contribution, drops the pick if it is occluded (visibility reuse)
+//            and merges the reservoir the pixel ended up with in the previous frame, found by
+//            reprojecting the hit through the previous camera (temporal reuse)
+//   spatial  merges the reservoirs of a few nearby pixels that see a similar surface (spatial
+//            reuse), and keeps the result for the next frame
+//
+// The reservoirs are merged with the biased 1/M weights of the paper, and a neighbor whose light
+// is occluded for itself passes on a zero weight even where it isn't, so the image comes out a
+// little darker near shadow edges than with the sum over all lights. Temporal reuse needs a mono
+// camera. Deeper bounces, and first hits that the passes didn't see the same way (e.g. through a
+// medium), resample a few lights on the spot instead. This module is compiled together with
+// shaders.wgsl.
+
+// The lights picked for a surface and what is needed to merge them with other reservoirs.
+struct Reservoir {
+  // The first hit of the camera ray of the pixel, which the light was picked for.
+  point: vec3f,
+  // The picked light, or NO_LIGHT.
+  light: u32,
+  normal: u32,  // pack4x8snorm
+  // The number of candidates seen so far (M), 0 if the camera ray found no lit surface.
+  sample_count: f32,
+  // The unbiased contribution weight of the light (W), which divides its contribution by the
+  // density it was effectively picked with.
+  weight: f32,
+  _pad0: u32,
+}
+
+const NO_LIGHT: u32 = 0xffffffffu;
+
+// The light picked for the first hit of each pixel: its index (or -1), its weight and the
+// distance from the camera to the hit, which the trace kernels check they found the same hit by.
+@group(0) @binding(17) var resampled_lights: texture_2d<f32>;
+// The final reservoirs, kept from one frame to the next, and those of the initial pass.
+@group(0) @binding(18) var<storage, read_write> reservoirs: array<Reservoir>;
+@group(0) @binding(19) var<storage, read_write> candidates: array<Reservoir>;
+// Copied to `resampled_lights` once the spatial pass is done, as a texture can't be read and
+// written in the same bind group.
+@group(0) @binding(20) var resampled_lights_output: texture_storage_2d<rgba32float, write>;
+
+// The number of uniformly chosen lights that each first hit and each deeper bounce start from.
+const RESTIR_CANDIDATES: u32 = 32u;
+const RESTIR_BOUNCE_CANDIDATES: u32 = 8u;
+// The history of a reservoir is capped to this many times the candidates of the current frame,
+// so that it keeps up with changes.
+const RESTIR_MAX_HISTORY: f32 = 20.;
+const RESTIR_NEIGHBORS: u32 = 5u;
+const RESTIR_RADIUS: f32 = 30.;
+// Decorrelates the random numbers of the passes from those of the trace kernels.
+const RESTIR_INITIAL_SEED: u32 = 0x9e3779b9u;
+const RESTIR_SPATIAL_SEED: u32 = 0x85ebca6bu;
+
+// A reservoir being filled by streaming RIS: `weight_sum` sums the weights of the candidates,
+// each of which replaces the pick with a probability of its weight over the sum.
+struct Candidates {
+  light: u32,
+  // The target function of the picked light (p̂).
+  target_value: f32,
+  weight_sum: f32,
+  sample_count: f32,
+}
+
+fn no_candidates() -> Candidates {
+  return Candidates(NO_LIGHT, 0., 0., 0.);
+}
+
+fn add_candidate(
+  c: ptr<function, Candidates>,
+  light: u32,
+  weight: f32,
+  target_value: f32,
+  sample_count: f32,
+) {
+  (*c).weight_sum += weight;
+  (*c).sample_count += sample_count;
+  if weight > 0. && rand_f32() * (*c).weight_sum < weight {
+    (*c).light = light;
+    (*c).target_value = target_value;
+  }
+}
+
+// The unbiased contribution weight of the picked light.
+fn contribution_weight(c: Candidates) -> f32 {
+  if c.target_value <= 0. {
+    return 0.;
+  }
+  return c.weight_sum / (c.sample_count * c.target_value);
+}
+
+// The function that the lights are resampled by: the luminance of the unshadowed light they
+// shed on `point`.
+fn restir_target(light_index: u32, point: vec3f, normal: vec3f) -> f32 {
+  let light = lights[light_index];
+  let luminance = dot(light.color, vec3(0.2126, 0.7152, 0.0722)) * light.intensity;
+  return luminance * light_falloff(light, point, normal);
+}
+
+// Streams `count` lights, chosen uniformly, into a reservoir for `point`.
+fn sample_light_candidates(point: vec3f, normal: vec3f, count: u32) -> Candidates {
+  var c = no_candidates();
+  let light_count = uniforms.light_count;
+  if light_count == 0u {
+    return c;
+  }
+  for (var i = 0u; i < count; i += 1u) {
+    let light = min(u32(rand_f32() * f32(light_count)), light_count - 1u);
+    let target_value = restir_target(light, point, normal);
+    add_candidate(&c, light, target_value * f32(light_count), target_value, 1.);
+  }
+  return c;
+}
+
+// Whether `reservoir` was built for a surface close enough to `point` for its light to be
+// reused there.
+fn is_similar_surface(reservoir: Reservoir, point: vec3f, normal: vec3f) -> bool {
+  if reservoir.light == NO_LIGHT {
+    return false;
+  }
+  let distance = length(point - uniforms.camera.origin);
+  return dot(unpack4x8snorm(reservoir.normal).xyz, normal) > 0.9
+    && length(reservoir.point - point) < 0.05 * distance;
+}
+
+// Merges `reservoir` into `c`, re-evaluating its light at `point`.
+fn merge_reservoir(
+  c: ptr<function, Candidates>,
+  reservoir: Reservoir,
+  sample_count: f32,
+  point: vec3f,
+  normal: vec3f,
+) {
+  let target_value = restir_target(reservoir.light, point, normal);
+  let weight = target_value * reservoir.weight * sample_count;
+  add_candidate(c, reservoir.light, weight, target_value, sample_count);
+}
+
+fn pixel_index(pixel: vec2u) -> u32 {
+  return pixel.x + pixel.y * uniforms.width;
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE_X, WORKGROUP_SIZE_Y)
+fn restir_initial_cs(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= uniforms.width || id.y >= uniforms.height {
+    return;
+  }
+  let pixel = id.xy;
+  let index = pixel_index(pixel);
+
+  // Follow the same camera ray as the trace kernels.
+  init_sampler(pixel);
+  let offset = sample_2d() - 0.5;
+  let time = mix(uniforms.camera.shutter_open, uniforms.camera.shutter_close, sample_1d());
+  let ray = generate_camera_ray(vec2f(pixel) + 0.5 + offset, time);
+  let hit = intersect_scene(ray);
+  rng.state = jenkins_hash(rng.state ^ RESTIR_INITIAL_SEED);
+
+  var reservoir = Reservoir(vec3(0.), NO_LIGHT, 0u, 0., 0., 0u);
+  if !is_intersection_valid(hit) || is_dielectric(materials[hit.material_index]) {
+    candidates[index] = reservoir;
+    return;
+  }
+  let point = point_on_ray(ray, hit.t);
+  let normal = hit.normal;
+  var c = sample_light_candidates(point, normal, RESTIR_CANDIDATES);
+
+  // Visibility reuse: an occluded light contributes nothing here, and isn't worth passing on.
+  if c.light != NO_LIGHT {
+    let light = lights[c.light];
+    let to_light = light.position - point;
+    let shadow_ray = Ray(point + normal * EPSILON, normalize(to_light), time);
+    let medium = find_medium(NO_OBJECT);
+    if all(shadow_transmittance(shadow_ray, length(to_light), medium) == vec3(0.)) {
+      c.weight_sum = 0.;
+    }
+  }
+
+  // Temporal reuse: the reservoir of the pixel that saw the same point in the previous frame.
+  let previous_camera = uniforms.previous_camera;
+  if uniforms.restir_history == 1u && previous_camera.stereo_mode == STEREO_MONO {
+    let image = image_point(previous_camera, point - previous_camera.origin);
+    if image.cos_theta > 0. {
+      let previous = reservoirs[pixel_index(vec2u(image.position))];
+      if is_similar_surface(previous, point, normal) {
+        let history = min(previous.sample_count, RESTIR_MAX_HISTORY * c.sample_count);
+        merge_reservoir(&c, previous, history, point, normal);
+      }
+    }
+  }
+
+  reservoir = Reservoir(
+    point, c.light, pack4x8snorm(vec4(normal, 0.)), c.sample_count, contribution_weight(c), 0u,
+  );
+  candidates[index] = reservoir;
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE_X, WORKGROUP_SIZE_Y)
+fn restir_spatial_cs(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= uniforms.width || id.y >= uniforms.height {
+    return;
+  }
+  let pixel = id.xy;
+  let index = pixel_index(pixel);
+  let center = candidates[index];
+  if center.sample_count == 0. {
+    reservoirs[index] = center;
+    textureStore(resampled_lights_output, pixel, vec4(-1., 0., 0., 0.));
+    return;
+  }
+  init_rng(pixel);
+  rng.state = jenkins_hash(rng.state ^ RESTIR_SPATIAL_SEED);
+
+  let point = center.point;
+  let normal = unpack4x8snorm(center.normal).xyz;
+  var c = no_candidates();
+  if center.light != NO_LIGHT {
+    merge_reservoir(&c, center, center.sample_count, point, normal);
+  } else {
+    c.sample_count = center.sample_count;
+  }
+
+  // Spatial reuse: the reservoirs of a few random pixels within a disk around this one.
+  let size = vec2i(i32(uniforms.width), i32(uniforms.height));
+  for (var i = 0u; i < RESTIR_NEIGHBORS; i += 1u) {
+    let u = vec2(rand_f32(), rand_f32());
+    let r = RESTIR_RADIUS * sqrt(u.x);
+    let phi = TWO_PI * u.y;
+    let neighbor = vec2i(pixel) + vec2i(round(r * vec2(cos(phi), sin(phi))));
+    if any(neighbor < vec2(0)) || any(neighbor >= size) || all(neighbor == vec2i(pixel)) {
+      continue;
+    }
+    let reservoir = candidates[pixel_index(vec2u(neighbor))];
+    if is_similar_surface(reservoir, point, normal) {
+      merge_reservoir(&c, reservoir, reservoir.sample_count, point, normal);
+    }
+  }
+
+  let weight = contribution_weight(c);
+  reservoirs[index] = Reservoir(point, c.light, center.normal, c.sample_count, weight, 0u);
+  let light = select(f32(c.light), -1., c.light == NO_LIGHT);
+  let distance = length(point - uniforms.camera.origin);
+  textureStore(resampled_lights_output, pixel, vec4(light, weight, distance, 0.));
+}
+
+// The light resampled for the first hit of a camera path and its contribution weight.
+struct ResampledLight {
+  light: u32,
+  weight: f32,
+}
+
+// Set by the trace kernels for the first hit of a camera path, and used up by the next call to
+// `resampled_direct_lighting`.
+var<private> primary_light: ResampledLight = ResampledLight(NO_LIGHT, 0.);
+
+// The light that the passes picked for `pixel`, if its camera ray first hit `point` for them too.
+fn primary_resampled_light(pixel: vec2u, point: vec3f) -> ResampledLight {
+  if uniforms.restir == 0u {
+    return ResampledLight(NO_LIGHT, 0.);
+  }
+  let texel = textureLoad(resampled_lights, pixel, 0);
+  let distance = length(point - uniforms.camera.origin);
+  if texel.x < 0. || abs(distance - texel.z) > 1e-3 * distance {
+    return ResampledLight(NO_LIGHT, 0.);
+  }
+  return ResampledLight(u32(texel.x), texel.y);
+}
+
+// The direct lighting of a surface point from a single resampled light, weighted by its
+// contribution weight.
+fn resampled_direct_lighting(point: vec3f, normal: vec3f, time: f32, medium: u32) -> vec3f {
+  var picked = primary_light;
+  primary_light = ResampledLight(NO_LIGHT, 0.);
+  if picked.light == NO_LIGHT {
+    let c = sample_light_candidates(point, normal, RESTIR_BOUNCE_CANDIDATES);
+    picked = ResampledLight(c.light, contribution_weight(c));
+  }
+  if picked.light == NO_LIGHT || picked.weight == 0. {
+    return vec3(0.);
+  }
+  return light_contribution(lights[picked.light], point, normal, time, medium) * picked.weight;
+}
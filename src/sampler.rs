@@ -0,0 +1,165 @@
+use anyhow::{bail, Result};
+
+/// How the trace pass draws the random numbers of its samples. The values must match the
+/// `SAMPLER_*` constants in shaders.wgsl.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Sampler {
+    /// Independent xorshift random numbers (white noise).
+    Random = 0,
+    /// The Sobol sequence with Owen scrambling, shuffled independently for each dimension.
+    Sobol = 1,
+    /// A rank-1 lattice (the R2 sequence), randomly shifted for each pixel and dimension.
+    Lattice = 2,
+    /// A blue-noise tile spread across the pixels, advanced by the golden ratio every frame.
+    BlueNoise = 3,
+}
+
+const SAMPLERS: [Sampler; 4] = [
+    Sampler::Random,
+    Sampler::Sobol,
+    Sampler::Lattice,
+    Sampler::BlueNoise,
+];
+
+impl Sampler {
+    pub fn from_name(name: &str) -> Result<Sampler> {
+        match SAMPLERS.iter().find(|sampler| sampler.name() == name) {
+            Some(&sampler) => Ok(sampler),
+            None => bail!("unknown sampler \"{}\"", name),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Sampler::Random => "random",
+            Sampler::Sobol => "sobol",
+            Sampler::Lattice => "lattice",
+            Sampler::BlueNoise => "blue-noise",
+        }
+    }
+
+    /// The sampler that follows this one, wrapping around after the last.
+    pub fn next(self) -> Sampler {
+        SAMPLERS[(self as usize + 1) % SAMPLERS.len()]
+    }
+}
+
+/// The width and height of the blue-noise tile, which repeats across the image.
+pub const BLUE_NOISE_SIZE: u32 = 64;
+
+/// Generates a tileable blue-noise texture with the void-and-cluster method of Ulichney, "The
+/// void-and-cluster method for dither array generation" (1993). Returns one value in [0, 1) per
+/// texel, in row major order, each value appearing once.
+pub fn blue_noise() -> Vec<f32> {
+    let size = BLUE_NOISE_SIZE as usize;
+    let count = size * size;
+    let mut pattern = BinaryPattern::new(size);
+
+    // Start from a random tenth of the texels and move points from the tightest cluster to the
+    // largest void until that no longer changes anything.
+    let mut state = 0x2545f491u32;
+    while pattern.ones < count / 10 {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let texel = state as usize % count;
+        if !pattern.bits[texel] {
+            pattern.set(texel, true);
+        }
+    }
+    loop {
+        let cluster = pattern.tightest_cluster();
+        pattern.set(cluster, false);
+        let void = pattern.largest_void();
+        pattern.set(void, true);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // The points of the initial pattern are ranked by removing them, tightest cluster first.
+    // The remaining texels are ranked by filling the largest void, one at a time.
+    let mut ranks = vec![0; count];
+    let initial = pattern.clone();
+    let initial_ones = initial.ones;
+    for rank in (0..initial_ones).rev() {
+        let cluster = pattern.tightest_cluster();
+        pattern.set(cluster, false);
+        ranks[cluster] = rank;
+    }
+    pattern = initial;
+    for rank in initial_ones..count {
+        let void = pattern.largest_void();
+        pattern.set(void, true);
+        ranks[void] = rank;
+    }
+
+    ranks
+        .into_iter()
+        .map(|rank| (rank as f32 + 0.5) / count as f32)
+        .collect()
+}
+
+// A toroidal binary pattern along with the energy of each texel: the sum of a Gaussian of the
+// distance to every point that is set.
+#[derive(Clone)]
+struct BinaryPattern {
+    size: usize,
+    bits: Vec<bool>,
+    energy: Vec<f32>,
+    ones: usize,
+}
+
+// The standard deviation of the Gaussian recommended by Ulichney. It is negligible past
+// `ENERGY_RADIUS` texels.
+const ENERGY_SIGMA: f32 = 1.5;
+const ENERGY_RADIUS: isize = 6;
+
+impl BinaryPattern {
+    fn new(size: usize) -> BinaryPattern {
+        BinaryPattern {
+            size,
+            bits: vec![false; size * size],
+            energy: vec![0.; size * size],
+            ones: 0,
+        }
+    }
+
+    fn set(&mut self, texel: usize, value: bool) {
+        self.bits[texel] = value;
+        self.ones = if value { self.ones + 1 } else { self.ones - 1 };
+        let sign = if value { 1. } else { -1. };
+
+        let size = self.size as isize;
+        let (x, y) = ((texel % self.size) as isize, (texel / self.size) as isize);
+        for dy in -ENERGY_RADIUS..=ENERGY_RADIUS {
+            for dx in -ENERGY_RADIUS..=ENERGY_RADIUS {
+                let distance_squared = (dx * dx + dy * dy) as f32;
+                let weight = (-distance_squared / (2. * ENERGY_SIGMA * ENERGY_SIGMA)).exp();
+                let neighbor = (x + dx).rem_euclid(size) + (y + dy).rem_euclid(size) * size;
+                self.energy[neighbor as usize] += sign * weight;
+            }
+        }
+    }
+
+    // The set texel with the highest energy.
+    fn tightest_cluster(&self) -> usize {
+        self.extreme_texel(true, |a, b| a > b)
+    }
+
+    // The unset texel with the lowest energy.
+    fn largest_void(&self) -> usize {
+        self.extreme_texel(false, |a, b| a < b)
+    }
+
+    fn extreme_texel(&self, bit: bool, better: impl Fn(f32, f32) -> bool) -> usize {
+        let mut best = None;
+        for (texel, &energy) in self.energy.iter().enumerate() {
+            if self.bits[texel] == bit && best.is_none_or(|(_, e)| better(energy, e)) {
+                best = Some((texel, energy));
+            }
+        }
+        best.expect("the pattern is neither empty nor full").0
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{bail, Result};
+
+use crate::media::Media;
+use crate::types::{
+    BoundingBox, Light, Medium, MeshInstance, Sphere, Transform, MESH_MATERIALS, NO_OBJECT,
+};
+
+/// The names of the materials, in the order of their indices. Must match `materials` in
+/// shaders.wgsl.
+pub const MATERIALS: [&str; 5] = ["mirror", "diffuse", "glass", "flint", "diamond"];
+
+/// How an object moves over the frame, from wherever it starts: it is translated by `offset` and
+/// turned by `angle` radians about `axis`, around its own origin. Spheres look the same however
+/// they are turned, so they only move.
+#[derive(Debug, Copy, Clone)]
+pub struct Motion {
+    pub offset: [f32; 3],
+    /// A unit vector.
+    pub axis: [f32; 3],
+    /// Less than π in magnitude, since rotations are interpolated along the shortest arc.
+    pub angle: f32,
+}
+
+/// The objects rendered alongside the loaded mesh. Motion is expressed over the frame: everything
+/// is at its "0" state at the start of the frame and at its "1" state at its end, and the shutter
+/// interval selects the part of that motion that blurs the image.
+pub struct Scene {
+    pub spheres: Vec<Sphere>,
+    pub mesh_instances: Vec<MeshInstance>,
+    pub lights: Vec<Light>,
+    /// The fog and the media inside objects, which turn the surfaces of those objects into
+    /// invisible boundaries, and their density grids.
+    pub media: Media,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        let sphere = |center: [f32; 3], radius, material_index| Sphere {
+            center0: center,
+            radius,
+            center1: center,
+            material_index,
+        };
+        Scene {
+            spheres: vec![
+                sphere([-0.6, 0.5, 0.], 0.5, 0),
+                sphere([0.6, 0.5, 0.], 0.5, 1),
+            ],
+            mesh_instances: vec![MeshInstance {
+                transform0: Transform::IDENTITY,
+                transform1: Transform::IDENTITY,
+                material_index: MESH_MATERIALS,
+                _pad: [0; 3],
+            }],
+            lights: vec![Light {
+                position: [2.0, 1.0, 1.0],
+                _pad0: 0,
+                color: [0.8, 0.8, 0.8], // Lumière blanche douce
+                intensity: 1.5,         // Faible intensité pour le remplissage
+            }],
+            media: Media::default(),
+        }
+    }
+}
+
+impl Scene {
+    /// Adds `media` to the scene, checking that the objects they fill exist. Object IDs number
+    /// the mesh instances first, followed by the spheres.
+    pub fn set_media(&mut self, media: Media) -> Result<()> {
+        let object_count = (self.mesh_instances.len() + self.spheres.len()) as u32;
+        for medium in &media.media {
+            if medium.object_id != NO_OBJECT && medium.object_id >= object_count {
+                bail!(
+                    "there is no object {} to fill with a medium, the scene has {} objects",
+                    medium.object_id,
+                    object_count
+                );
+            }
+        }
+        self.media = media;
+        Ok(())
+    }
+
+    /// Gives the object `object_id` the material called `name` (see `MATERIALS`). Object IDs
+    /// number the mesh instances first, followed by the spheres.
+    pub fn set_material(&mut self, object_id: u32, name: &str) -> Result<()> {
+        let Some(index) = MATERIALS.iter().position(|&material| material == name) else {
+            bail!(
+                "unknown material {:?}, expected one of {}",
+                name,
+                MATERIALS.join(", ")
+            );
+        };
+        let instance_count = self.mesh_instances.len();
+        let id = object_id as usize;
+        if let Some(instance) = self.mesh_instances.get_mut(id) {
+            instance.material_index = index as u32;
+        } else if let Some(sphere) = self.spheres.get_mut(id - instance_count) {
+            sphere.material_index = index as u32;
+        } else {
+            bail!(
+                "there is no object {} to give a material, the scene has {} objects",
+                object_id,
+                instance_count + self.spheres.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Applies `motion` to the object `object_id` over the frame. Object IDs number the mesh
+    /// instances first, followed by the spheres.
+    pub fn set_motion(&mut self, object_id: u32, motion: &Motion) -> Result<()> {
+        let instance_count = self.mesh_instances.len();
+        let id = object_id as usize;
+        let moved = |start: [f32; 3]| [0, 1, 2].map(|i| start[i] + motion.offset[i]);
+        if let Some(instance) = self.mesh_instances.get_mut(id) {
+            let (sin, cos) = (0.5 * motion.angle).sin_cos();
+            let [x, y, z] = motion.axis.map(|c| c * sin);
+            let start = instance.transform0;
+            instance.transform1 = Transform {
+                rotation: quaternion_product([x, y, z, cos], start.rotation),
+                translation: moved(start.translation),
+                ..start
+            };
+        } else if let Some(sphere) = self.spheres.get_mut(id - instance_count) {
+            sphere.center1 = moved(sphere.center0);
+        } else {
+            bail!(
+                "there is no object {} to move, the scene has {} objects",
+                object_id,
+                instance_count + self.spheres.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the media with the bounds of each object's medium set to a box around the object
+    /// in its own space, where the trace kernels follow it through its motion. `mesh_bounds` are
+    /// the object-space bounds of the mesh.
+    pub fn bounded_media(&self, mesh_bounds: &BoundingBox) -> Vec<Medium> {
+        let mut media = self.media.media.clone();
+        for medium in media.iter_mut().filter(|m| m.object_id != NO_OBJECT) {
+            let id = medium.object_id as usize;
+            let (min, max) = if id < self.mesh_instances.len() {
+                (mesh_bounds.min, mesh_bounds.max)
+            } else {
+                // Sphere space is centered on the sphere.
+                let radius = self.spheres[id - self.mesh_instances.len()].radius;
+                ([-radius; 3], [radius; 3])
+            };
+            medium.bounds_min = min;
+            medium.bounds_max = max;
+        }
+        media
+    }
+
+    /// Returns a box around every object over the whole shutter interval.
+    pub fn bounds(&self, mesh_bounds: &BoundingBox) -> BoundingBox {
+        let object_count = self.mesh_instances.len() + self.spheres.len();
+        let spheres = (0..object_count)
+            .flat_map(|id| self.bounding_spheres(id, mesh_bounds))
+            .collect::<Vec<_>>();
+        sphere_bounds(&spheres)
+    }
+
+    /// Returns a sphere around object `id` at each end of the shutter interval, as its center
+    /// and radius.
+    fn bounding_spheres(&self, id: usize, mesh_bounds: &BoundingBox) -> [([f32; 3], f32); 2] {
+        match self.mesh_instances.get(id) {
+            Some(instance) => [instance.transform0, instance.transform1].map(|transform| {
+                let (min, max) = (mesh_bounds.min, mesh_bounds.max);
+                let center = [0, 1, 2].map(|i| (min[i] + max[i]) / 2.);
+                let diagonal = (0..3).map(|i| (max[i] - min[i]).powi(2)).sum::<f32>();
+                (
+                    transform.apply(center),
+                    diagonal.sqrt() / 2. * transform.scale,
+                )
+            }),
+            None => {
+                let sphere = &self.spheres[id - self.mesh_instances.len()];
+                [sphere.center0, sphere.center1].map(|center| (center, sphere.radius))
+            }
+        }
+    }
+}
+
+/// Returns the box that covers all of `spheres`.
+fn sphere_bounds(spheres: &[([f32; 3], f32)]) -> BoundingBox {
+    let bound = |i: usize, side: f32, fold: fn(f32, f32) -> f32| {
+        spheres
+            .iter()
+            .map(|(center, radius)| center[i] + side * radius)
+            .fold(-side * f32::INFINITY, fold)
+    };
+    BoundingBox {
+        min: [0, 1, 2].map(|i| bound(i, -1., f32::min)),
+        _pad0: 0,
+        max: [0, 1, 2].map(|i| bound(i, 1., f32::max)),
+        _pad1: 0,
+    }
+}
+
+// The Hamilton product of two (x, y, z, w) quaternions: the rotation `b` followed by `a`.
+fn quaternion_product(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    let [ax, ay, az, aw] = a;
+    let [bx, by, bz, bw] = b;
+    [
+        aw * bx + ax * bw + ay * bz - az * by,
+        aw * by - ax * bz + ay * bw + az * bx,
+        aw * bz + ax * by - ay * bx + az * bw,
+        aw * bw - ax * bx - ay * by - az * bz,
+    ]
+}
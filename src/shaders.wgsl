@@ -3,9 +3,9 @@
 
 const FLT_MAX: f32 = 3.40282346638528859812e+38;
 const EPSILON: f32 = 1e-3;
+const PI: f32 = 3.1415927;
 const TWO_PI: f32 = 6.2831853;
 
-const MAX_PATH_LENGTH: u32 = 13u;
 const MAX_TRIANGLES: u32 = 1000u;
 var<private> triangle_count: u32;  // Nombre réel de triangles
 
@@ -60,7 +60,7 @@ fn intersect_triangle(ray : Ray , triangle : Triangle) -> Intersection {
 
   let t = f * dot(edge2, q);
   if t > EPSILON {
-      return Intersection(triangle.normal, t, triangle.material_index);
+      return Intersection(triangle.normal, t, triangle.material_index, 0u);
   }
 
   return no_intersection();
@@ -75,31 +75,342 @@ struct Uniforms {
   frame_count: u32,
   triangle_count: u32,
   light_count: u32,
-  _pad0: u32,
-  _pad1: u32,
-  _pad2: u32,
+  sphere_count: u32,
+  instance_count: u32,
+  // The relative error below which a pixel has converged and is skipped, or 0 to sample every
+  // pixel in every frame.
+  adaptive_threshold: f32,
   mesh_bounds: BoundingBox,
+  // How samples are drawn, one of the `SAMPLER_*` constants.
+  sampler_type: u32,
+  // Paths end after this many bounces.
+  max_path_length: u32,
+  // Past this many bounces, paths are terminated by Russian roulette.
+  roulette_depth: u32,
+  // The highest luminance of a sample of direct and of indirect light, or 0 not to clamp it.
+  clamp_direct: f32,
+  clamp_indirect: f32,
+  // The roughness given to mirrors after a diffuse bounce, or 0 to keep them perfect.
+  regularization: f32,
+  medium_count: u32,
+  media: array<Medium, MAX_MEDIA>,
+  // Indexed by `Medium.grid`.
+  grid_placements: array<GridPlacement, MAX_MEDIA>,
+  // 1 if paths carry wavelengths rather than RGB colors (see `Wavelengths`).
+  spectral: u32,
+  // The luminance of D65 over the sampled wavelengths, which spectral radiance is divided by.
+  d65_luminance: f32,
+  // 1 if the first hits are lit by the lights picked by ReSTIR (see restir.wgsl), and 1 in
+  // `restir_history` if it also ran on the previous frame, seen from `previous_camera`.
+  restir: u32,
+  restir_history: u32,
+  previous_camera: CameraUniforms,
+  // The radius within which photons are first gathered (see sppm.wgsl).
+  photon_radius: f32,
+  // 1 if the first diffuse hits take the light of the irradiance probes instead of tracing the
+  // rest of their path, and the box that the grid of probes spans (see probes.wgsl).
+  probe_preview: u32,
+  probe_bounds: BoundingBox,
 }
 @group(0) @binding(0) var<uniform> uniforms: Uniforms;
 
 struct CameraUniforms {
   origin: vec3f,
+  shutter_open: f32,
   u: vec3f,
+  shutter_close: f32,
   v: vec3f,
   w: vec3f,
+  tan_half_fov: f32,
+  stereo_mode: u32,
+  eye_separation: f32,
+  convergence: f32,
 }
 
+const STEREO_MONO: u32 = 0u;
+const STEREO_SIDE_BY_SIDE: u32 = 1u;
+const STEREO_OVER_UNDER: u32 = 2u;
+const STEREO_OMNIDIRECTIONAL: u32 = 3u;
+
+// Generates the primary ray through the viewport position `pixel`. In the stereo modes the image
+// is split in two halves, one per eye: left/right for side-by-side and top/bottom for over-under
+// and omni-directional stereo. The left eye is always on the left or on top.
+fn generate_camera_ray(pixel: vec2f, time: f32) -> Ray {
+  let camera = uniforms.camera;
+  var size = vec2f(f32(uniforms.width), f32(uniforms.height));
+  var view_pixel = pixel;
+
+  // -1 for the left eye, +1 for the right eye and 0 for a mono camera.
+  var eye = 0.;
+  if camera.stereo_mode == STEREO_SIDE_BY_SIDE {
+    size.x *= 0.5;
+    let right = pixel.x >= size.x;
+    eye = select(-1., 1., right);
+    view_pixel.x -= select(0., size.x, right);
+  } else if camera.stereo_mode != STEREO_MONO {
+    size.y *= 0.5;
+    let right = pixel.y >= size.y;
+    eye = select(-1., 1., right);
+    view_pixel.y -= select(0., size.y, right);
+  }
+
+  let camera_rotation = mat3x3(camera.u, camera.v, camera.w);
+  let eye_offset = 0.5 * eye * camera.eye_separation;
+
+  if camera.stereo_mode == STEREO_OMNIDIRECTIONAL {
+    // Each half of the image is an equirectangular panorama. Every ray starts on the circle with
+    // the eye separation as its diameter, tangent to the viewing direction.
+    let theta = (2. * view_pixel.x / size.x - 1.) * PI;
+    let phi = (0.5 - view_pixel.y / size.y) * PI;
+    let direction = vec3(sin(theta) * cos(phi), sin(phi), cos(theta) * cos(phi));
+    let offset = eye_offset * vec3(cos(theta), 0., -sin(theta));
+
+    // The panorama keeps the horizon level: only the camera azimuth turns it, never the altitude.
+    let up = vec3(0., 1., 0.);
+    let horizontal = vec3(camera.u.x, 0., camera.u.z);
+    let right = select(vec3(1., 0., 0.), normalize(horizontal), dot(horizontal, horizontal) > 1e-8);
+    let level_rotation = mat3x3(right, up, cross(up, right));
+    return Ray(camera.origin + level_rotation * offset, level_rotation * direction, time);
+  }
+
+  // Normalize the viewport coordinates of the ray.
+  let focus_distance = 1.;
+  let aspect_ratio = size.x / size.y;
+  var uv = view_pixel / (size - vec2(1.));
+
+  // Map `uv` from y-down (normalized) viewport coordinates to camera coordinates.
+  uv = (2. * uv - vec2(1.)) * vec2(aspect_ratio, -1.) * camera.tan_half_fov;
+
+  // Compute the scene-space ray direction by rotating the camera-space vector into a new
+  // basis.
+  let direction = camera_rotation * vec3(uv, focus_distance);
+  if eye == 0. {
+    return Ray(camera.origin, direction, time);
+  }
+
+  // Shift each eye sideways and aim it at the point the mono ray reaches at the convergence
+  // distance (an off-axis frustum), so that objects at that distance have zero parallax.
+  let eye_origin = camera.origin + eye_offset * camera.u;
+  let converged = camera.origin + camera.convergence * direction;
+  return Ray(eye_origin, converged - eye_origin, time);
+}
+
+// The point where the direction `direction` from a mono `camera` crosses the image, in viewport
+// coordinates, along with the cosine of its angle to the viewing direction. The cosine is
+// negative if the direction misses the image.
+struct ImagePoint {
+  position: vec2f,
+  cos_theta: f32,
+}
+
+fn image_point(camera: CameraUniforms, direction: vec3f) -> ImagePoint {
+  let d = normalize(direction);
+  let cos_theta = dot(d, camera.w);
+  if cos_theta <= 0. {
+    return ImagePoint(vec2(0.), -1.);
+  }
+  // Invert the mapping of `generate_camera_ray`.
+  let size = vec2f(f32(uniforms.width), f32(uniforms.height));
+  let aspect_ratio = size.x / size.y;
+  let uv = vec2(dot(d, camera.u), dot(d, camera.v)) / (cos_theta * camera.tan_half_fov);
+  let position = 0.5 * (uv / vec2(aspect_ratio, -1.) + 1.) * (size - vec2(1.));
+  if any(position < vec2(0.)) || any(position >= size) {
+    return ImagePoint(position, -1.);
+  }
+  return ImagePoint(position, cos_theta);
+}
+
+// The values of `Uniforms.sampler_type`. Must match `Sampler` in sampler.rs.
+const SAMPLER_RANDOM: u32 = 0u;
+const SAMPLER_SOBOL: u32 = 1u;
+const SAMPLER_LATTICE: u32 = 2u;
+const SAMPLER_BLUE_NOISE: u32 = 3u;
+
+// Every sample of a pixel is a point in a high-dimensional space, whose coordinates (dimensions)
+// are handed out in a fixed order: the camera takes the first ones (the offset within the pixel,
+// the time and the hero wavelength), then each bounce takes the same number, whether it uses them
+// all or not. This way the same dimension always drives the same decision, which is what makes
+// the low-discrepancy sequences pay off.
+const CAMERA_DIMENSIONS: u32 = 4u;
+const BOUNCE_DIMENSIONS: u32 = 4u;
+// The dimension of each bounce that decides Russian roulette, after the two of the scattered
+// direction.
+const ROULETTE_DIMENSION: u32 = 2u;
+
+// The blue-noise tile of the blue-noise sampler (see `blue_noise` in sampler.rs).
+@group(0) @binding(10) var blue_noise: texture_2d<f32>;
+const BLUE_NOISE_SIZE: u32 = 64u;
+
 struct Rng {
   state: u32,
 }
 var<private> rng: Rng;
 
+// The sample drawn by the current invocation, for the samplers other than random.
+struct SampleSequence {
+  pixel: vec2u,
+  // Decorrelates the sequences of the pixels.
+  seed: u32,
+  // The index of the sample within the sequence of the pixel.
+  index: u32,
+  // The next dimension to hand out.
+  dimension: u32,
+}
+var<private> sequence: SampleSequence;
+
+// Prepares the sampler to draw the next sample of `pixel`, starting with the camera dimensions.
+fn init_sampler(pixel: vec2u) {
+  init_rng(pixel);
+  // Pixels that adaptive sampling skipped have fewer samples than there were frames, so the
+  // index comes from their own sample count. That keeps their sequence free of gaps.
+  var sample_count = 0.;
+  if uniforms.frame_count > 1u {
+    sample_count = textureLoad(radiance_samples_old, pixel, 0).a;
+  }
+  let seed = jenkins_hash(pixel.x + pixel.y * uniforms.width);
+  sequence = SampleSequence(pixel, seed, u32(sample_count), 0u);
+}
+
 fn init_rng(pixel: vec2u) {
   // Seed the PRNG using the scalar index of the pixel and the current frame count.
   let seed = (pixel.x + pixel.y * uniforms.width) ^ jenkins_hash(uniforms.frame_count);
   rng.state = jenkins_hash(seed);
 }
 
+// Moves on to the dimensions of the bounce that follows `path_length` segments.
+fn start_bounce(path_length: u32) {
+  sequence.dimension = CAMERA_DIMENSIONS + path_length * BOUNCE_DIMENSIONS;
+}
+
+// Returns the next dimension of the sample, in [0, 1).
+fn sample_1d() -> f32 {
+  let dimension = sequence.dimension;
+  sequence.dimension += 1u;
+  switch uniforms.sampler_type {
+    case SAMPLER_SOBOL: {
+      return sobol_1d(dimension);
+    }
+    case SAMPLER_LATTICE: {
+      return lattice_1d(dimension);
+    }
+    case SAMPLER_BLUE_NOISE: {
+      return blue_noise_1d(dimension);
+    }
+    default: {
+      return rand_f32();
+    }
+  }
+}
+
+// Returns the next two dimensions of the sample, in [0, 1). They are stratified together, so
+// they should drive the two coordinates of the same 2D decision.
+fn sample_2d() -> vec2f {
+  let dimension = sequence.dimension;
+  sequence.dimension += 2u;
+  switch uniforms.sampler_type {
+    case SAMPLER_SOBOL: {
+      return sobol_2d(dimension);
+    }
+    case SAMPLER_LATTICE: {
+      return lattice_2d(dimension);
+    }
+    case SAMPLER_BLUE_NOISE: {
+      return vec2(blue_noise_1d(dimension), blue_noise_1d(dimension + 1u));
+    }
+    default: {
+      return vec2(rand_f32(), rand_f32());
+    }
+  }
+}
+
+// A seed for `dimension` of the current pixel. See Burley, "Practical Hash-based Owen
+// Scrambling" (JCGT 2020).
+fn dimension_seed(dimension: u32) -> u32 {
+  let seed = sequence.seed;
+  return seed ^ (jenkins_hash(dimension) + 0x9e3779b9u + (seed << 6u) + (seed >> 2u));
+}
+
+// Maps the 24 most significant bits of `x` to [0, 1).
+fn u32_to_unit(x: u32) -> f32 {
+  return f32(x >> 8u) * (1. / 16777216.);
+}
+
+// Burley's hash-based approximation of a random permutation of the binary digits of `x`, from
+// the least significant one up: each bit only depends on the bits below it.
+fn laine_karras_permutation(i: u32, seed: u32) -> u32 {
+  var x = i + seed;
+  x ^= x * 0x6c50b47cu;
+  x ^= x * 0xb82f1e52u;
+  x ^= x * 0xc7afe638u;
+  x ^= x * 0x8d22f6e6u;
+  return x;
+}
+
+// Owen scrambling: flips each binary digit of `x` depending on the digits above it.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+  return reverseBits(laine_karras_permutation(reverseBits(x), seed));
+}
+
+// The second dimension of the Sobol sequence, whose direction numbers are v[k + 1] = v[k] ^
+// (v[k] >> 1). The first dimension is simply `reverseBits(index)`.
+fn sobol_second_dimension(index: u32) -> u32 {
+  var x = 0u;
+  var direction = 1u << 31u;
+  for (var i = index; i != 0u; i >>= 1u) {
+    if (i & 1u) != 0u {
+      x ^= direction;
+    }
+    direction ^= direction >> 1u;
+  }
+  return x;
+}
+
+// The first two dimensions of the Sobol sequence with Owen scrambling. The index is shuffled
+// (itself Owen scrambled) differently for every pair of dimensions, so that each pair follows
+// its own well-stratified sequence and the pairs stay uncorrelated.
+fn sobol_2d(dimension: u32) -> vec2f {
+  let seed = dimension_seed(dimension);
+  let index = nested_uniform_scramble(sequence.index, seed);
+  let x = nested_uniform_scramble(reverseBits(index), jenkins_hash(seed));
+  let y = nested_uniform_scramble(sobol_second_dimension(index), jenkins_hash(seed + 1u));
+  return vec2(u32_to_unit(x), u32_to_unit(y));
+}
+
+fn sobol_1d(dimension: u32) -> f32 {
+  let seed = dimension_seed(dimension);
+  let index = nested_uniform_scramble(sequence.index, seed);
+  return u32_to_unit(nested_uniform_scramble(reverseBits(index), jenkins_hash(seed)));
+}
+
+// The generators of the rank-1 lattices, in 0.32 fixed point so that the products wrap around
+// exactly: 1 / φ in 1D, and the R2 sequence (1 / g, 1 / g²), where g is the plastic number, in
+// 2D. Each pixel and dimension shifts its lattice by a random offset (a Cranley-Patterson
+// rotation).
+const LATTICE_1D: u32 = 2654435769u;
+const LATTICE_2D: vec2u = vec2(3242174889u, 2447445413u);
+
+fn lattice_1d(dimension: u32) -> f32 {
+  let shift = jenkins_hash(dimension_seed(dimension));
+  return u32_to_unit(shift + sequence.index * LATTICE_1D);
+}
+
+fn lattice_2d(dimension: u32) -> vec2f {
+  let seed = dimension_seed(dimension);
+  let shift = vec2(jenkins_hash(seed), jenkins_hash(seed + 1u));
+  let x = shift + sequence.index * LATTICE_2D;
+  return vec2(u32_to_unit(x.x), u32_to_unit(x.y));
+}
+
+// Neighboring pixels read neighboring texels of the blue-noise tile, so the error of a sample is
+// spread over high frequencies across the image. Each dimension reads the tile at its own random
+// offset, and every sample of a pixel advances its value by 1 / φ, which stratifies it over time.
+fn blue_noise_1d(dimension: u32) -> f32 {
+  let offset = jenkins_hash(dimension + 1u);
+  let texel = (sequence.pixel + vec2(offset, offset >> 16u)) % BLUE_NOISE_SIZE;
+  let value = textureLoad(blue_noise, texel, 0).r;
+  return u32_to_unit(u32(value * 4294967296.) + sequence.index * LATTICE_1D);
+}
+
 // A slightly modified version of the "One-at-a-Time Hash" function by Bob Jenkins.
 // See https://www.burtleburtle.net/bob/hash/doobs.html
 fn jenkins_hash(i: u32) -> u32 {
@@ -130,43 +441,96 @@ fn rand_f32() -> f32 {
   return bitcast<f32>(0x3f800000u | (xorshift32() >> 9u)) - 1.;
 }
 
-// Uniformly sample a unit sphere centered at the origin
-fn sample_sphere() -> vec3f {
-  let r0 = rand_f32();
-  let r1 = rand_f32();
+struct DirectionSample {
+  direction: vec3f,
+  // The probability density of `direction`, per unit solid angle.
+  pdf: f32,
+}
+
+// Samples the hemisphere around the unit vector `normal` proportionally to the cosine of the
+// angle to the normal: uniform points on the disk are projected up onto the hemisphere (Malley's
+// method). The concentric mapping of Shirley and Chiu keeps the square of `sample_2d` stratified
+// on the disk.
+fn sample_cosine_hemisphere(normal: vec3f) -> DirectionSample {
+  let u = 2. * sample_2d() - 1.;
+  var disk = vec2(0.);
+  if u.x != 0. || u.y != 0. {
+    var radius: f32;
+    var phi: f32;
+    if abs(u.x) > abs(u.y) {
+      radius = u.x;
+      phi = 0.25 * PI * (u.y / u.x);
+    } else {
+      radius = u.y;
+      phi = 0.5 * PI - 0.25 * PI * (u.x / u.y);
+    }
+    disk = radius * vec2(cos(phi), sin(phi));
+  }
+  let cos_theta = sqrt(max(1. - dot(disk, disk), 0.));
+  let direction = tangent_frame(normal) * vec3(disk, cos_theta);
+  return DirectionSample(direction, cos_theta / PI);
+}
 
-  // Map r0 to [-1, 1]
-  let y = 1. - 2. * r0;
+// An orthonormal basis whose third axis is the unit vector `n`. See Duff et al., "Building an
+// Orthonormal Basis, Revisited" (JCGT 2017).
+fn tangent_frame(n: vec3f) -> mat3x3f {
+  let sign = select(-1., 1., n.z >= 0.);
+  let a = -1. / (sign + n.z);
+  let b = n.x * n.y * a;
+  let tangent = vec3(1. + sign * n.x * n.x * a, sign * b, -sign * n.x);
+  let bitangent = vec3(b, sign + n.y * n.y * a, -n.y);
+  return mat3x3(tangent, bitangent, n);
+}
 
-  // Compute the projected radius on the xz-plane using Pythagorean theorem
-  let xz_r = sqrt(1. - y * y);
+// Russian roulette: once a path has bounced `uniforms.roulette_depth` times, it survives each
+// further bounce with a probability that follows its throughput, so that paths carrying little
+// light stop early. `bounce` is the path length before the bounce that was just scattered.
+// Returns the weight that keeps the survivors unbiased, or 0 if the path ends.
+fn russian_roulette(bounce: u32, throughput: vec3f) -> f32 {
+  return russian_roulette_at(bounce, bounce, throughput);
+}
 
-  let phi = TWO_PI * r1;
-  return vec3(xz_r * cos(phi), y, xz_r * sin(phi));
+// Like `russian_roulette`, but draws the decision from the dimensions of bounce `sample_bounce`,
+// for paths whose bounces don't take the dimensions in order (see bdpt.wgsl).
+fn russian_roulette_at(bounce: u32, sample_bounce: u32, throughput: vec3f) -> f32 {
+  if bounce + 1u < uniforms.roulette_depth {
+    return 1.;
+  }
+  sequence.dimension = CAMERA_DIMENSIONS + sample_bounce * BOUNCE_DIMENSIONS + ROULETTE_DIMENSION;
+  let survival = min(max(throughput.r, max(throughput.g, throughput.b)), 1.);
+  if sample_1d() >= survival {
+    return 0.;
+  }
+  return 1. / survival;
 }
 
 struct Intersection {
   normal: vec3f,
   t: f32,
   material_index: u32,
+  // Mesh instances are numbered first, followed by the spheres.
+  object_id: u32,
 }
 
 fn no_intersection() -> Intersection {
-  return Intersection(vec3(0.), -1., 0);
+  return Intersection(vec3(0.), -1., 0, 0);
 }
 
 fn is_intersection_valid(hit: Intersection) -> bool {
   return hit.t > 0.;
 }
 
+// A sphere that moves linearly from `center0` at the start of the frame to `center1` at its end.
 struct Sphere {
-  center: vec3f,
+  center0: vec3f,
   radius: f32,
+  center1: vec3f,
   material_index: u32,
 }
 
 fn intersect_sphere(ray: Ray, sphere: Sphere) -> Intersection {
-  let v = ray.origin - sphere.center;
+  let center = mix(sphere.center0, sphere.center1, ray.time);
+  let v = ray.origin - center;
   let a = dot(ray.direction, ray.direction);
   let b = dot(v, ray.direction);
   let c = dot(v, v) - sphere.radius * sphere.radius;
@@ -187,8 +551,8 @@ fn intersect_sphere(ray: Ray, sphere: Sphere) -> Intersection {
   }
 
   let p = point_on_ray(ray, t);
-  let N = (p - sphere.center) / sphere.radius;
-  return Intersection(N, t, sphere.material_index);
+  let N = (p - center) / sphere.radius;
+  return Intersection(N, t, sphere.material_index, 0u);
 }
 
 fn intersect_bounding_box(ray: Ray, bbox: BoundingBox) -> bool {
@@ -204,27 +568,96 @@ fn intersect_bounding_box(ray: Ray, bbox: BoundingBox) -> bool {
     return tmax_min >= tmin_max && tmax_min >= 0.;
 }
 
+// A similarity transform: `p' = rotation * (scale * p) + translation`. The rotation is a unit
+// quaternion stored as (x, y, z, w).
+struct Transform {
+  rotation: vec4f,
+  translation: vec3f,
+  scale: f32,
+}
+
+// An instance of the mesh placed by `transform0` at the start of the frame and `transform1` at
+// its end.
+struct MeshInstance {
+  transform0: Transform,
+  transform1: Transform,
+  // The material of the whole instance, or MESH_MATERIALS to keep those of the triangles.
+  material_index: u32,
+}
+const MESH_MATERIALS: u32 = 0xffffffffu;
+
+fn rotate(q: vec4f, v: vec3f) -> vec3f {
+  let t = 2. * cross(q.xyz, v);
+  return v + q.w * t + cross(q.xyz, t);
+}
+
+// Interpolates translation and scale linearly and the rotation along the shortest arc
+// (normalized lerp).
+fn transform_at(instance: MeshInstance, time: f32) -> Transform {
+  let a = instance.transform0;
+  let b = instance.transform1;
+  let q1 = select(b.rotation, -b.rotation, dot(a.rotation, b.rotation) < 0.);
+  return Transform(
+    normalize(mix(a.rotation, q1, time)),
+    mix(a.translation, b.translation, time),
+    mix(a.scale, b.scale, time),
+  );
+}
+
+fn intersect_mesh_instance(world_ray: Ray, instance: MeshInstance) -> Intersection {
+  // Move the ray into the object space of the instance at the time of the ray. The direction is
+  // not renormalized so that distances along the ray stay the same in both spaces.
+  let xform = transform_at(instance, world_ray.time);
+  let inv_rotation = vec4(-xform.rotation.xyz, xform.rotation.w);
+  let inv_scale = 1. / xform.scale;
+  let ray = Ray(
+    rotate(inv_rotation, world_ray.origin - xform.translation) * inv_scale,
+    rotate(inv_rotation, world_ray.direction) * inv_scale,
+    world_ray.time,
+  );
+
+  var closest_hit = no_intersection();
+  closest_hit.t = FLT_MAX;
+
+  // The bounds are tested in object space, which makes them follow the motion of the instance.
+  if intersect_bounding_box(ray, uniforms.mesh_bounds) {
+    for (var i = 0u; i < uniforms.triangle_count; i += 1u) {
+      let triangle = mesh_data[i];
+      let hit = intersect_triangle(ray, triangle);
+      if hit.t > 0. && hit.t < closest_hit.t {
+        closest_hit = hit;
+      }
+    }
+  }
+  if closest_hit.t == FLT_MAX {
+    return no_intersection();
+  }
+  closest_hit.normal = rotate(xform.rotation, closest_hit.normal);
+  if instance.material_index != MESH_MATERIALS {
+    closest_hit.material_index = instance.material_index;
+  }
+  return closest_hit;
+}
+
 fn intersect_scene(ray: Ray) -> Intersection {
     var closest_hit = no_intersection();
     closest_hit.t = FLT_MAX;
 
-    // Test de la bounding box avant d'itérer sur les triangles
-    if intersect_bounding_box(ray, uniforms.mesh_bounds) {
-        for (var i = 0u; i < uniforms.triangle_count; i += 1u) {
-            let triangle = mesh_data[i];
-            let hit = intersect_triangle(ray, triangle);
-            if hit.t > 0. && hit.t < closest_hit.t {
-                closest_hit = hit;
-            }
+    for (var i = 0u; i < uniforms.instance_count; i += 1u) {
+        let hit = intersect_mesh_instance(ray, instances[i]);
+        if hit.t > 0. && hit.t < closest_hit.t {
+            closest_hit = hit;
+            closest_hit.object_id = i;
         }
     }
     
     // Pour les sphères
-    for (var i = 0u; i < OBJECT_COUNT; i += 1u) {
-        let sphere = scene[i];
+    for (var i = 0u; i < uniforms.sphere_count; i += 1u) {
+        let sphere = spheres[i];
         let hit = intersect_sphere(ray, sphere);
         if hit.t > 0. && hit.t < closest_hit.t {
             closest_hit = hit;
+            closest_hit.object_id = uniforms.instance_count + i;
         }
     }
 
@@ -243,8 +676,119 @@ fn sample_perfectly_specular(input_dir: vec3f, normal: vec3f) -> vec3f {
   return reflect(input_dir, normal);
 }
 
-fn sample_lambertian(input_dir: vec3f, normal: vec3f) -> vec3f {
-  return normal + sample_sphere() * (1. - EPSILON);
+// Samples a mirror. With `regularize` set and path regularization enabled, the mirror is
+// roughened instead: the reflection spreads uniformly over a cone around the mirror direction,
+// whose half-angle goes from 0 to 90 degrees as `uniforms.regularization` goes from 0 to 1. Paths
+// that have already bounced off a diffuse surface can then find small, bright features through
+// the mirror, which would otherwise show up as fireflies. This blurs their reflections, so it
+// trades a bias for less noise.
+fn sample_specular(input_dir: vec3f, normal: vec3f, regularize: bool) -> BsdfSample {
+  let mirror = sample_perfectly_specular(input_dir, normal);
+  if !regularize || uniforms.regularization <= 0. {
+    return BsdfSample(mirror, vec3(1.));
+  }
+  let axis = normalize(mirror);
+  let cos_max = cos(0.5 * PI * min(uniforms.regularization, 1.));
+  let u = sample_2d();
+  let cos_theta = mix(1., cos_max, u.x);
+  let sin_theta = sqrt(max(1. - cos_theta * cos_theta, 0.));
+  let phi = TWO_PI * u.y;
+  let local = vec3(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+  let direction = tangent_frame(axis) * local;
+  // Directions that end up on the other side of the surface are absorbed.
+  let weight = select(0., 1., dot(direction, normal) * dot(axis, normal) > 0.);
+  return BsdfSample(direction, vec3(weight));
+}
+
+struct BsdfSample {
+  direction: vec3f,
+  // The BSDF times the cosine of the sampled direction, divided by its pdf, per color channel or
+  // wavelength.
+  weight: vec3f,
+}
+
+// Samples the Lambertian BRDF (1 / π) by its cosine lobe, which makes the weight 1 wherever the
+// pdf doesn't vanish.
+fn sample_lambertian(input_dir: vec3f, normal: vec3f) -> BsdfSample {
+  let sample = sample_cosine_hemisphere(normal);
+  let cos_theta = dot(sample.direction, normal);
+  var weight = 0.;
+  if sample.pdf > 0. {
+    weight = cos_theta / (PI * sample.pdf);
+  }
+  return BsdfSample(sample.direction, vec3(weight));
+}
+
+// The fraction of unpolarized light that a smooth dielectric reflects, for light arriving at an
+// angle whose cosine is `cos_i`. `eta` is the refractive index of the side the light comes from
+// over that of the other side.
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+  let sin2_t = eta * eta * max(1. - cos_i * cos_i, 0.);
+  if sin2_t >= 1. {
+    // Total internal reflection.
+    return 1.;
+  }
+  let cos_t = sqrt(1. - sin2_t);
+  let r_s = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+  let r_p = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+  return 0.5 * (r_s * r_s + r_p * r_p);
+}
+
+// The refractive index of `material` at `lambda` nanometers, from Cauchy's equation n = A + B / λ²
+// fitted to its index at the d line and its Abbe number, which compares its indices at the F and
+// C lines.
+fn cauchy_ior(material: Material, lambda: f32) -> f32 {
+  // The Fraunhofer lines, in micrometers.
+  let d = 0.5876;
+  let f = 0.4861;
+  let c = 0.6563;
+  let b = (material.ior - 1.) / (material.abbe * (1. / (f * f) - 1. / (c * c)));
+  let a = material.ior - b / (d * d);
+  let l = lambda / 1000.;
+  return a + b / (l * l);
+}
+
+// Samples a smooth dielectric, which reflects or refracts the ray with the probability given by
+// the Fresnel reflectance. That makes the weight 1, except in spectral mode with a dispersive
+// material: each wavelength would refract in its own direction, so the hero wavelength picks it
+// and the others are dropped.
+fn sample_dielectric(input_dir: vec3f, normal: vec3f, material: Material) -> BsdfSample {
+  let direction = normalize(input_dir);
+  var ior = material.ior;
+  var weight = vec3(1.);
+  if uniforms.spectral == 1u && material.abbe > 0. {
+    ior = cauchy_ior(material, wavelengths.lambda.x);
+    weight = terminate_secondary_wavelengths();
+  }
+  let entering = dot(direction, normal) < 0.;
+  let facing_normal = select(-normal, normal, entering);
+  let eta = select(ior, 1. / ior, entering);
+  let reflectance = fresnel_dielectric(-dot(direction, facing_normal), eta);
+  if sample_1d() < reflectance {
+    return BsdfSample(reflect(direction, facing_normal), weight);
+  }
+  return BsdfSample(refract(direction, facing_normal, eta), weight);
+}
+
+fn is_dielectric(material: Material) -> bool {
+  return material.ior > 0.;
+}
+
+// Samples the direction in which a ray leaves a surface of `material`. `regularize` is set once
+// the path has bounced off a diffuse surface (see `sample_specular`).
+fn sample_material(
+  input_dir: vec3f,
+  normal: vec3f,
+  material: Material,
+  regularize: bool,
+) -> BsdfSample {
+  if is_dielectric(material) {
+    return sample_dielectric(input_dir, normal, material);
+  }
+  if material.specular == 1u {
+    return sample_specular(input_dir, normal, regularize);
+  }
+  return sample_lambertian(input_dir, normal);
 }
 
 struct Light {
@@ -254,51 +798,455 @@ struct Light {
     intensity: f32,
 }
 
-@group(0) @binding(4) var<storage> lights: array<Light, 4>;  // Support jusqu'à 4 lumières
+@group(0) @binding(4) var<storage> lights: array<Light>;
 
-// Modifions la fonction scatter pour inclure l'éclairage direct
-fn scatter(input_ray: Ray, hit: Intersection, material: Material) -> Scatter {
+const AMBIENT: vec3f = vec3f(0.15);  // Lumière ambiante légèrement plus forte
+
+// Contribution directe des lumières au point `hit_point`, en tenant compte des ombres. `medium` is
+// the medium around the point, which the shadow rays start in. In ReSTIR mode a single resampled
+// light stands in for all of them (see restir.wgsl).
+fn direct_lighting(hit_point: vec3f, normal: vec3f, time: f32, medium: u32) -> vec3f {
+    if uniforms.restir == 1u {
+        return resampled_direct_lighting(hit_point, normal, time, medium);
+    }
     var radiance = vec3f(0.0);
-    let hit_point = point_on_ray(input_ray, hit.t);
-    
-    // Contribution directe des lumières
     for(var i = 0u; i < uniforms.light_count; i++) {
-        let light = lights[i];
-        let to_light = normalize(light.position - hit_point);
-        let dist_to_light = length(light.position - hit_point);
-        
-        // Vérifier si le point est dans l'ombre
-        let shadow_ray = Ray(hit_point + hit.normal * EPSILON, to_light);
-        let shadow_hit = intersect_scene(shadow_ray);
-        
-        if !is_intersection_valid(shadow_hit) || shadow_hit.t > dist_to_light {
-            // Pas d'ombre, calculer l'éclairage
-            let n_dot_l = max(dot(hit.normal, to_light), 0.0);
-            // Ajout d'une atténuation avec la distance
-            let attenuation = 1.0 / (1.0 + 0.1 * dist_to_light * dist_to_light);
-            let light_contribution = light.color * light.intensity * n_dot_l * attenuation;
-            radiance += light_contribution;
-        }
+        radiance += light_contribution(lights[i], hit_point, normal, time, medium);
+    }
+    return radiance;
+}
+
+// La lumière que `light` apporte au point `hit_point`, ombres comprises.
+fn light_contribution(
+    light: Light,
+    hit_point: vec3f,
+    normal: vec3f,
+    time: f32,
+    medium: u32,
+) -> vec3f {
+    let to_light = normalize(light.position - hit_point);
+    let dist_to_light = length(light.position - hit_point);
+    
+    // Vérifier si le point est dans l'ombre
+    let shadow_ray = Ray(hit_point + normal * EPSILON, to_light, time);
+    let transmittance = shadow_transmittance(shadow_ray, dist_to_light, medium);
+    
+    if any(transmittance > vec3(0.)) {
+        // Pas d'ombre, calculer l'éclairage
+        let light_color = spectrum(light.color) * light.intensity;
+        return light_color * light_falloff(light, hit_point, normal) * transmittance;
     }
+    return vec3f(0.0);
+}
+
+// L'angle d'incidence et l'atténuation avec la distance de la lumière `light` au point
+// `hit_point`, sans les ombres.
+fn light_falloff(light: Light, hit_point: vec3f, normal: vec3f) -> f32 {
+    let to_light = normalize(light.position - hit_point);
+    let dist_to_light = length(light.position - hit_point);
+    let n_dot_l = max(dot(normal, to_light), 0.0);
+    // Ajout d'une atténuation avec la distance
+    let attenuation = 1.0 / (1.0 + 0.1 * dist_to_light * dist_to_light);
+    return n_dot_l * attenuation;
+}
+
+// Modifions la fonction scatter pour inclure l'éclairage direct
+// `regularize` is set once the path has bounced off a diffuse surface (see `sample_specular`).
+// The direction is sampled before the shadow rays are traced, in the same order as the stages of
+// the wavefront integrator, so that both draw the same random numbers.
+fn scatter(
+  input_ray: Ray,
+  hit: Intersection,
+  material: Material,
+  regularize: bool,
+  medium: u32,
+) -> Scatter {
+    let hit_point = point_on_ray(input_ray, hit.t);
     
     // Réflexion diffuse ou spéculaire selon le matériau
-    var reflected: vec3f;
-    if material.specular == 1 {
-        reflected = sample_perfectly_specular(input_ray.direction, hit.normal);
-    } else {
-        reflected = sample_lambertian(input_ray.direction, hit.normal);
+    let reflected = sample_material(input_ray.direction, hit.normal, material, regularize);
+    let output_ray = Ray(hit_point, reflected.direction, input_ray.time);
+    var attenuation = spectrum(material.color) * reflected.weight;
+    // Glass lets the light through rather than being lit, and shadow rays don't cross it.
+    if !is_dielectric(material) {
+        let radiance = direct_lighting(hit_point, hit.normal, input_ray.time, medium);
+        attenuation *= radiance + spectrum(AMBIENT);
     }
     
-    let output_ray = Ray(hit_point, reflected);
-    let ambient = vec3f(0.15);  // Lumière ambiante légèrement plus forte
-    let attenuation = material.color * (radiance + ambient);
-    
     return Scatter(attenuation, output_ray);
 }
 
+// A participating medium (see `Medium` in types.rs). Its coefficients are per unit length, and
+// `majorant` bounds their sum over the whole medium. The bounds of the fog are a box rotated by
+// `rotation` around its center, and those of an object's medium are a box around the object in
+// its own space.
+struct Medium {
+  absorption: vec3f,
+  object_id: u32,
+  scattering: vec3f,
+  anisotropy: f32,
+  bounds_min: vec3f,
+  grid: u32,
+  bounds_max: vec3f,
+  majorant: f32,
+  rotation: vec4f,
+}
+
+// Must match media.rs and types.rs.
+const MAX_MEDIA: u32 = 4u;
+const NO_MEDIUM: u32 = 0xffffffffu;
+const NO_OBJECT: u32 = 0xffffffffu;
+const NO_GRID: u32 = 0xffffffffu;
+
+// The density grids of the media, stacked along z (see `create_density_grid_texture` in
+// render.rs).
+@group(0) @binding(11) var density_grids: texture_3d<f32>;
+
+struct GridPlacement {
+  size: vec3u,
+  // The first layer of the grid in `density_grids`.
+  z_offset: u32,
+}
+
+// A path gives up after crossing this many medium boundaries in one segment.
+const MAX_BOUNDARY_CROSSINGS: u32 = 8u;
+// Delta and ratio tracking give up after this many tentative collisions, which only matters in
+// media that are very dense compared to their size. The light is then taken to be absorbed: the
+// path would have had to cross so much medium that almost none of it gets through.
+const MAX_TRACKING_STEPS: u32 = 256u;
+
+// The index of the medium that fills object `object_id`, of the fog for NO_OBJECT, or NO_MEDIUM.
+fn find_medium(object_id: u32) -> u32 {
+  for (var i = 0u; i < uniforms.medium_count; i += 1u) {
+    if uniforms.media[i].object_id == object_id {
+      return i;
+    }
+  }
+  return NO_MEDIUM;
+}
+
+// The medium on the far side of `hit`, the boundary of `hit_medium`, when crossing it along
+// `direction`: the object's medium when entering it and the fog when leaving.
+fn medium_behind(hit: Intersection, hit_medium: u32, direction: vec3f) -> u32 {
+  if dot(direction, hit.normal) < 0. {
+    return hit_medium;
+  }
+  return find_medium(NO_OBJECT);
+}
+
+// `ray` in the space of the bounds of `medium`: those of the fog unrotated, or the object that the
+// medium fills where it is at the time of the ray, so that its density grid moves with it. The
+// direction is not renormalized, so the ray parameter is the same in both spaces.
+fn medium_space_ray(ray: Ray, medium: Medium) -> Ray {
+  if medium.object_id == NO_OBJECT {
+    let center = 0.5 * (medium.bounds_min + medium.bounds_max);
+    let inverse = vec4(-medium.rotation.xyz, medium.rotation.w);
+    return Ray(
+      rotate(inverse, ray.origin - center) + center,
+      rotate(inverse, ray.direction),
+      ray.time,
+    );
+  }
+  if medium.object_id < uniforms.instance_count {
+    let xform = transform_at(instances[medium.object_id], ray.time);
+    let inv_rotation = vec4(-xform.rotation.xyz, xform.rotation.w);
+    let inv_scale = 1. / xform.scale;
+    return Ray(
+      rotate(inv_rotation, ray.origin - xform.translation) * inv_scale,
+      rotate(inv_rotation, ray.direction) * inv_scale,
+      ray.time,
+    );
+  }
+  let sphere = spheres[medium.object_id - uniforms.instance_count];
+  return Ray(ray.origin - mix(sphere.center0, sphere.center1, ray.time), ray.direction, ray.time);
+}
+
+// The density that scales the coefficients of `medium` at `p`, in the space of its bounds: 0
+// outside of them, and the density grid stretched over them, interpolated trilinearly, if the
+// medium uses one.
+fn medium_density(medium: Medium, p: vec3f) -> f32 {
+  if any(p < medium.bounds_min) || any(p > medium.bounds_max) {
+    return 0.;
+  }
+  if medium.grid == NO_GRID {
+    return 1.;
+  }
+  let placement = uniforms.grid_placements[medium.grid];
+  let size = vec3i(placement.size);
+  let x = (p - medium.bounds_min) / (medium.bounds_max - medium.bounds_min) * vec3f(size) - 0.5;
+  let base = floor(x);
+  let f = x - base;
+  var density = 0.;
+  for (var corner = 0u; corner < 8u; corner += 1u) {
+    let offset = vec3u(corner, corner >> 1u, corner >> 2u) & vec3(1u);
+    let voxel = clamp(vec3i(base) + vec3i(offset), vec3i(0), size - 1);
+    let texel = voxel + vec3(0, 0, i32(placement.z_offset));
+    let weights = select(1. - f, f, offset == vec3(1u));
+    density += weights.x * weights.y * weights.z * textureLoad(density_grids, texel, 0).r;
+  }
+  return density;
+}
+
+// The interval of the ray parameter over which `ray` is inside the box.
+fn box_interval(ray: Ray, bounds_min: vec3f, bounds_max: vec3f) -> vec2f {
+  let t1 = (bounds_min - ray.origin) / ray.direction;
+  let t2 = (bounds_max - ray.origin) / ray.direction;
+  let near = min(t1, t2);
+  let far = max(t1, t2);
+  return vec2(max(near.x, max(near.y, near.z)), min(far.x, min(far.y, far.z)));
+}
+
+fn average(v: vec3f) -> f32 {
+  return (v.x + v.y + v.z) / 3.;
+}
+
+fn max_component(v: vec3f) -> f32 {
+  return max(v.x, max(v.y, v.z));
+}
+
+// The majorant of `medium` for its coefficients at the wavelengths of the path, whose sum is
+// `extinction`. Their spectra can peak above the RGB coefficients.
+fn path_majorant(medium: Medium, extinction: vec3f) -> f32 {
+  let rgb_extinction = max_component(medium.absorption + medium.scattering);
+  if uniforms.spectral == 0u || rgb_extinction <= 0. {
+    return medium.majorant;
+  }
+  return medium.majorant * max_component(extinction) / rgb_extinction;
+}
+
+// What happened to a ray inside a medium: it either made it through (EVENT_SURFACE), scattered
+// or got absorbed at `t`.
+struct MediumSample {
+  kind: u32,
+  t: f32,
+  throughput: vec3f,
+}
+
+// Delta tracking: samples where the ray scatters or gets absorbed in `medium` before `t_max`.
+// Tentative collisions are spaced by the majorant, and each one is a real collision with a
+// probability that follows the local coefficients, or a null collision that the ray goes on
+// from. The coefficients differ between color channels, so the probabilities are weighted by the
+// throughput and the throughput by the ratio of coefficient to probability. See Kutz et al.,
+// "Spectral and Decomposition Tracking for Rendering Heterogeneous Volumes" (SIGGRAPH 2017).
+fn track_medium(
+  world_ray: Ray,
+  t_max: f32,
+  medium: Medium,
+  path_throughput: vec3f,
+) -> MediumSample {
+  let ray = medium_space_ray(world_ray, medium);
+  var throughput = path_throughput;
+  let interval = box_interval(ray, medium.bounds_min, medium.bounds_max);
+  let t_end = min(interval.y, t_max);
+  let absorption_coefficient = spectrum(medium.absorption);
+  let scattering_coefficient = spectrum(medium.scattering);
+  let medium_majorant = path_majorant(medium, absorption_coefficient + scattering_coefficient);
+  // The ray direction isn't normalized, so the majorant is scaled to the ray parameter. The
+  // coefficients are per unit length in world space.
+  let majorant = medium_majorant * length(world_ray.direction);
+  var t = max(interval.x, 0.);
+  if majorant <= 0. {
+    return MediumSample(EVENT_SURFACE, t_max, throughput);
+  }
+  for (var step = 0u; step < MAX_TRACKING_STEPS; step += 1u) {
+    t -= log(1. - rand_f32()) / majorant;
+    if t >= t_end {
+      return MediumSample(EVENT_SURFACE, t_max, throughput);
+    }
+    let density = medium_density(medium, point_on_ray(ray, t));
+    let absorption = absorption_coefficient * density;
+    let scattering = scattering_coefficient * density;
+    let null_collision = max(medium_majorant - absorption - scattering, vec3(0.));
+    let p_absorb = average(absorption * throughput);
+    let p_scatter = average(scattering * throughput);
+    let p_null = average(null_collision * throughput);
+    let total = p_absorb + p_scatter + p_null;
+    if total <= 0. {
+      return MediumSample(EVENT_ABSORBED, t, vec3(0.));
+    }
+    let u = rand_f32() * total;
+    if u < p_absorb {
+      // The media don't emit light, so the path ends without a contribution.
+      return MediumSample(EVENT_ABSORBED, t, vec3(0.));
+    }
+    if u < p_absorb + p_scatter {
+      throughput *= scattering * total / (medium_majorant * p_scatter);
+      return MediumSample(EVENT_MEDIUM, t, throughput);
+    }
+    throughput *= null_collision * total / (medium_majorant * p_null);
+  }
+  return MediumSample(EVENT_ABSORBED, t, vec3(0.));
+}
+
+// Ratio tracking: estimates the fraction of light that crosses `medium` along `ray` up to
+// `t_max`. Rather than stopping at the first collision like delta tracking, it weighs every
+// tentative collision by the chance of it being a null collision.
+fn ratio_tracking(world_ray: Ray, t_max: f32, medium: Medium) -> vec3f {
+  let ray = medium_space_ray(world_ray, medium);
+  var transmittance = vec3(1.);
+  let interval = box_interval(ray, medium.bounds_min, medium.bounds_max);
+  let t_end = min(interval.y, t_max);
+  let extinction_coefficient = spectrum(medium.absorption) + spectrum(medium.scattering);
+  let medium_majorant = path_majorant(medium, extinction_coefficient);
+  let majorant = medium_majorant * length(world_ray.direction);
+  var t = max(interval.x, 0.);
+  if majorant <= 0. {
+    return transmittance;
+  }
+  for (var step = 0u; step < MAX_TRACKING_STEPS; step += 1u) {
+    t -= log(1. - rand_f32()) / majorant;
+    if t >= t_end {
+      return transmittance;
+    }
+    let density = medium_density(medium, point_on_ray(ray, t));
+    let extinction = extinction_coefficient * density;
+    transmittance *= max(1. - extinction / medium_majorant, vec3(0.));
+    if all(transmittance < vec3(1e-4)) {
+      return vec3(0.);
+    }
+  }
+  return vec3(0.);
+}
+
+// The fraction of light that travels `distance` along `shadow_ray`, which starts in `medium`.
+// Objects filled with a medium let the light through, attenuated by their medium, while other
+// objects block it.
+fn shadow_transmittance(shadow_ray: Ray, distance: f32, start_medium: u32) -> vec3f {
+  var transmittance = vec3(1.);
+  var medium = start_medium;
+  var t_start = 0.;
+  for (var crossing = 0u; crossing <= MAX_BOUNDARY_CROSSINGS; crossing += 1u) {
+    let segment = Ray(point_on_ray(shadow_ray, t_start), shadow_ray.direction, shadow_ray.time);
+    let hit = intersect_scene(segment);
+    let blocked = is_intersection_valid(hit) && hit.t < distance - t_start;
+    if medium != NO_MEDIUM {
+      let t_end = select(distance - t_start, hit.t, blocked);
+      transmittance *= ratio_tracking(segment, t_end, uniforms.media[medium]);
+    }
+    if !blocked {
+      return transmittance;
+    }
+    let hit_medium = find_medium(hit.object_id);
+    if hit_medium == NO_MEDIUM {
+      return vec3(0.);
+    }
+    medium = medium_behind(hit, hit_medium, shadow_ray.direction);
+    t_start += hit.t;
+  }
+  return vec3(0.);
+}
+
+// The kinds of `PathEvent`.
+const EVENT_ESCAPED: u32 = 0u;
+const EVENT_SURFACE: u32 = 1u;
+const EVENT_MEDIUM: u32 = 2u;
+const EVENT_ABSORBED: u32 = 3u;
+
+// Where a path segment ended: in the sky, on a surface, at a scattering event in a medium or
+// absorbed by one. `hit.t` is measured from the origin of the traced ray, and `hit` only
+// describes a surface for EVENT_SURFACE.
+struct PathEvent {
+  kind: u32,
+  hit: Intersection,
+  throughput: vec3f,
+  // The medium the path is in after the segment.
+  medium: u32,
+}
+
+// Follows `ray`, which starts in `medium`, to its next event. The surfaces of objects filled
+// with a medium are only boundaries between media: the ray crosses them and changes medium.
+// `throughput` is that of the path so far, which the media update.
+fn trace_ray(ray: Ray, start_medium: u32, path_throughput: vec3f) -> PathEvent {
+  var throughput = path_throughput;
+  var medium = start_medium;
+  var t_start = 0.;
+  for (var crossing = 0u; crossing <= MAX_BOUNDARY_CROSSINGS; crossing += 1u) {
+    let segment = Ray(point_on_ray(ray, t_start), ray.direction, ray.time);
+    var hit = intersect_scene(segment);
+    if medium != NO_MEDIUM {
+      let t_end = select(FLT_MAX, hit.t, is_intersection_valid(hit));
+      let sample = track_medium(segment, t_end, uniforms.media[medium], throughput);
+      throughput = sample.throughput;
+      if sample.kind != EVENT_SURFACE {
+        let point = Intersection(vec3(0.), t_start + sample.t, 0u, NO_OBJECT);
+        return PathEvent(sample.kind, point, throughput, medium);
+      }
+    }
+    if !is_intersection_valid(hit) {
+      return PathEvent(EVENT_ESCAPED, hit, throughput, medium);
+    }
+    let hit_medium = find_medium(hit.object_id);
+    if hit_medium == NO_MEDIUM {
+      hit.t += t_start;
+      return PathEvent(EVENT_SURFACE, hit, throughput, medium);
+    }
+    medium = medium_behind(hit, hit_medium, ray.direction);
+    t_start += hit.t;
+  }
+  return PathEvent(EVENT_ABSORBED, no_intersection(), vec3(0.), medium);
+}
+
+// The surface hit at `event`, or no intersection if the path didn't end on a surface.
+fn surface_hit(event: PathEvent) -> Intersection {
+  if event.kind == EVENT_SURFACE {
+    return event.hit;
+  }
+  return no_intersection();
+}
+
+// The Henyey-Greenstein phase function, for the cosine of the angle between the directions in
+// which light travels before and after scattering.
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+  let d = 1. + g * g - 2. * g * cos_theta;
+  return (1. - g * g) / (4. * PI * d * sqrt(d));
+}
+
+// Samples the direction after scattering off a medium with anisotropy `g`, proportionally to the
+// Henyey-Greenstein phase function, which makes the weight of the sample 1.
+fn sample_henyey_greenstein(direction: vec3f, g: f32) -> vec3f {
+  let u = sample_2d();
+  var cos_theta = 1. - 2. * u.x;
+  if abs(g) >= 1e-3 {
+    let s = (1. - g * g) / (1. - g + 2. * g * u.x);
+    cos_theta = clamp((1. + g * g - s * s) / (2. * g), -1., 1.);
+  }
+  let sin_theta = sqrt(max(1. - cos_theta * cos_theta, 0.));
+  let phi = TWO_PI * u.y;
+  let local = vec3(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+  return tangent_frame(normalize(direction)) * local;
+}
+
+// The light that reaches `point` in `medium` from the lights and scatters towards the origin of a
+// ray travelling along `direction`. The phase function is scaled by 4π, so that isotropic media
+// receive the light like a surface facing it.
+fn medium_direct_lighting(point: vec3f, direction: vec3f, medium: u32, time: f32) -> vec3f {
+  let g = uniforms.media[medium].anisotropy;
+  var radiance = vec3(0.);
+  for (var i = 0u; i < uniforms.light_count; i += 1u) {
+    let light = lights[i];
+    let to_light = normalize(light.position - point);
+    let dist_to_light = length(light.position - point);
+    let transmittance = shadow_transmittance(Ray(point, to_light, time), dist_to_light, medium);
+    let phase = 4. * PI * henyey_greenstein(dot(normalize(direction), to_light), g);
+    let attenuation = 1. / (1. + 0.1 * dist_to_light * dist_to_light);
+    radiance += spectrum(light.color) * light.intensity * phase * attenuation * transmittance;
+  }
+  return radiance;
+}
+
+// Scatters a ray off `point` in `medium`. Like at surfaces, the light that arrives at the point
+// is folded into the attenuation.
+fn scatter_medium(input_ray: Ray, point: vec3f, medium: u32) -> Scatter {
+  let direction = sample_henyey_greenstein(input_ray.direction, uniforms.media[medium].anisotropy);
+  let radiance = medium_direct_lighting(point, input_ray.direction, medium, input_ray.time);
+  return Scatter(radiance + spectrum(AMBIENT), Ray(point, direction, input_ray.time));
+}
+
 struct Ray {
   origin: vec3f,
   direction: vec3f,
+  // The time at which the ray is traced, as a fraction of the frame within the shutter interval.
+  time: f32,
 }
 
 fn point_on_ray(ray: Ray, t: f32) -> vec3<f32> {
@@ -308,6 +1256,10 @@ fn point_on_ray(ray: Ray, t: f32) -> vec3<f32> {
 struct Material {
   color: vec3f,
   specular: u32,
+  // The refractive index of a dielectric at the d line (587.6 nm), or 0 for opaque materials.
+  ior: f32,
+  // The Abbe number of a dielectric, which sets how much its index varies with the wavelength.
+  abbe: f32,
 }
 
 fn sky_color(ray: Ray) -> vec3f {
@@ -315,89 +1267,402 @@ fn sky_color(ray: Ray) -> vec3f {
   return (1. - t) * vec3(1.) + t * vec3(0.3, 0.5, 1.);
 }
 
-const OBJECT_COUNT: u32 = 2;
-alias Scene = array<Sphere, OBJECT_COUNT>;
-alias Materials = array<Material, OBJECT_COUNT>;
+// The light of the sky along `ray`. In spectral mode its color tints D65.
+fn sky_radiance(ray: Ray) -> vec3f {
+  let color = sky_color(ray);
+  if uniforms.spectral == 0u {
+    return color;
+  }
+  return spectrum(color) * d65(wavelengths.lambda);
+}
 
-var<private> materials: Materials = Materials(
-  Material(/*color*/ vec3(0.7, 0.5, 0.5), /*specular*/1),
-  Material(/*color*/ vec3(0.5, 0.5, 0.9), /*specular*/0),
+// In spectral mode, the three channels of the throughput and radiance of a path hold the values
+// at three wavelengths instead of RGB: a hero wavelength and two more, whose samples are spread
+// evenly from that of the hero (Wilkie et al., "Hero Wavelength Spectral Sampling", 2014). The
+// wavelengths the eye is most sensitive to are drawn more often. The RGB colors of the scene are
+// turned into spectra on the way, and the light that reaches the sky goes back to RGB before it
+// is added to the pixel. Must match spectrum.rs.
+const LAMBDA_MIN: f32 = 380.;
+const LAMBDA_MAX: f32 = 780.;
+
+struct Wavelengths {
+  // In nanometers, the hero wavelength first.
+  lambda: vec3f,
+  // Set once a dispersive material has sent the wavelengths their own ways, after which only the
+  // hero wavelength carries light.
+  secondary_terminated: bool,
+}
+var<private> wavelengths: Wavelengths;
+
+// The wavelengths are drawn with a density proportional to 1 / cosh²(k (λ - center)), which
+// roughly follows the sensitivity of the eye (see pbrt-v4's `SampleVisibleWavelengths`).
+const VISIBLE_CENTER: f32 = 538.;
+const VISIBLE_K: f32 = 0.0072;
+
+// The wavelengths of the path whose hero wavelength was drawn from the sample `u`.
+fn path_wavelengths(u: f32, secondary_terminated: bool) -> Wavelengths {
+  let lower = tanh(VISIBLE_K * (LAMBDA_MIN - VISIBLE_CENTER));
+  let upper = tanh(VISIBLE_K * (LAMBDA_MAX - VISIBLE_CENTER));
+  let samples = fract(u + vec3(0., 1., 2.) / 3.);
+  let lambda = VISIBLE_CENTER + atanh(mix(vec3(lower), vec3(upper), samples)) / VISIBLE_K;
+  return Wavelengths(clamp(lambda, vec3(LAMBDA_MIN), vec3(LAMBDA_MAX)), secondary_terminated);
+}
+
+fn wavelength_pdf(lambda: vec3f) -> vec3f {
+  let lower = tanh(VISIBLE_K * (LAMBDA_MIN - VISIBLE_CENTER));
+  let upper = tanh(VISIBLE_K * (LAMBDA_MAX - VISIBLE_CENTER));
+  let c = cosh(VISIBLE_K * (lambda - VISIBLE_CENTER));
+  return VISIBLE_K / ((upper - lower) * c * c);
+}
+
+// Drops the secondary wavelengths of the path. Returns the factor that the throughput takes: the
+// hero wavelength now stands for all three.
+fn terminate_secondary_wavelengths() -> vec3f {
+  if wavelengths.secondary_terminated {
+    return vec3(1., 0., 0.);
+  }
+  wavelengths.secondary_terminated = true;
+  return vec3(3., 0., 0.);
+}
+
+// The coefficients of the spectra of RGB reflectances (see `rgb_to_spectrum_table` in
+// spectrum.rs), with the three parts of the table stacked along z.
+@group(0) @binding(12) var rgb_to_spectrum: texture_3d<f32>;
+const SPECTRUM_TABLE_SIZE: u32 = 16u;
+
+// The spectrum of the RGB color `rgb` at the wavelengths of the path, or `rgb` itself in RGB mode.
+// Colors brighter than 1, like the coefficients of media, are scaled into the range of the table
+// and back.
+fn spectrum(rgb: vec3f) -> vec3f {
+  if uniforms.spectral == 0u || (rgb.x == rgb.y && rgb.y == rgb.z) {
+    // Grays are flat.
+    return rgb;
+  }
+  let brightest = max_component(rgb);
+  if brightest <= 1. {
+    return reflectance_spectrum(rgb);
+  }
+  return 2. * brightest * reflectance_spectrum(rgb / (2. * brightest));
+}
+
+fn reflectance_spectrum(rgb: vec3f) -> vec3f {
+  // The brightest channel selects the part of the table, indexed by that channel and the two
+  // others relative to it.
+  var channel = 2u;
+  if rgb.x >= rgb.y && rgb.x >= rgb.z {
+    channel = 0u;
+  } else if rgb.y >= rgb.z {
+    channel = 1u;
+  }
+  let brightness = rgb[channel];
+  let others = vec2(rgb[(channel + 1u) % 3u], rgb[(channel + 2u) % 3u]) / brightness;
+  // The brightness of the entries follows two nested smoothsteps, which are undone here.
+  let depth = inverse_smoothstep(inverse_smoothstep(brightness));
+  let position = vec3(others, depth) * f32(SPECTRUM_TABLE_SIZE - 1u);
+  let base = min(vec3u(position), vec3(SPECTRUM_TABLE_SIZE - 2u));
+  let f = position - vec3f(base);
+  // Trilinear interpolation of the coefficients.
+  var coefficients = vec3(0.);
+  for (var corner = 0u; corner < 8u; corner += 1u) {
+    let offset = vec3(corner & 1u, (corner >> 1u) & 1u, corner >> 2u);
+    let weights = select(1. - f, f, offset == vec3(1u));
+    let texel = base + offset + vec3(0u, 0u, channel * SPECTRUM_TABLE_SIZE);
+    let entry = textureLoad(rgb_to_spectrum, texel, 0).xyz;
+    coefficients += weights.x * weights.y * weights.z * entry;
+  }
+  let x = (wavelengths.lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN);
+  let polynomial = (coefficients.x * x + coefficients.y) * x + coefficients.z;
+  return 0.5 + polynomial / (2. * sqrt(1. + polynomial * polynomial));
+}
+
+fn inverse_smoothstep(y: f32) -> f32 {
+  return 0.5 - sin(asin(1. - 2. * y) / 3.);
+}
+
+// The relative spectral power of the D65 illuminant from 380 to 780 nm in steps of 10 nm. Must
+// match spectrum.rs.
+var<private> d65_table: array<f32, 41> = array<f32, 41>(
+  49.9755, 54.6482, 82.7549, 91.486, 93.4318, 86.6823, 104.865, 117.008, 117.812, 114.861,
+  115.923, 108.811, 109.354, 107.802, 104.790, 107.689, 104.405, 104.046, 100.000, 96.3342,
+  95.788, 88.6856, 90.0062, 89.5991, 87.6987, 83.2886, 83.6992, 80.0268, 80.2146, 82.2778,
+  78.2842, 69.7213, 71.6091, 74.349, 61.604, 69.8856, 75.087, 63.5927, 46.4182, 66.8054,
+  63.3828,
 );
 
-var<private> scene: Scene = Scene(
-  Sphere(/*center*/ vec3(-0.6, 0.5, 0.), /*radius*/ 0.5, /*material_index*/ 0),
-  Sphere(/*center*/ vec3(0.6, 0.5, 0.), /*radius*/ 0.5, /*material_index*/ 1),
+fn d65(lambda: vec3f) -> vec3f {
+  let x = clamp((lambda - LAMBDA_MIN) / 10., vec3(0.), vec3(40.));
+  let i = min(vec3u(x), vec3(39u));
+  let t = x - vec3f(i);
+  var power = vec3(0.);
+  for (var k = 0u; k < 3u; k += 1u) {
+    power[k] = mix(d65_table[i[k]], d65_table[i[k] + 1u], t[k]);
+  }
+  return power;
+}
+
+// One lobe of the fit of the CIE 1931 color matching functions by Wyman et al., "Simple Analytic
+// Approximations to the CIE XYZ Color Matching Functions" (JCGT 2013): a Gaussian with a
+// different width on each side of its peak.
+fn cie_lobe(lambda: vec3f, peak: f32, width_below: f32, width_above: f32) -> vec3f {
+  let t = (lambda - peak) / select(vec3(width_above), vec3(width_below), lambda < vec3(peak));
+  return exp(-0.5 * t * t);
+}
+
+const XYZ_TO_SRGB: mat3x3f = mat3x3f(
+  3.2404542, -0.9692660, 0.0556434,
+  -1.5371385, 1.8760108, -0.2040259,
+  -0.4985314, 0.0415560, 1.0572252,
 );
 
-@group(0) @binding(1) var radiance_samples_old: texture_2d<f32>;
-@group(0) @binding(2) var radiance_samples_new: texture_storage_2d<rgba32float, write>;
-@group(0) @binding(3) var<storage> mesh_data: array<Triangle, MAX_TRIANGLES>;
+// Converts radiance at the wavelengths of the path to linear sRGB, or leaves RGB radiance as it
+// is. Each wavelength gives an estimate of the color matching functions integrated against the
+// spectrum: their product at the wavelength, divided by its density.
+fn radiance_to_rgb(radiance: vec3f) -> vec3f {
+  if uniforms.spectral == 0u {
+    return radiance;
+  }
+  let lambda = wavelengths.lambda;
+  let x = 1.056 * cie_lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * cie_lobe(lambda, 442.0, 16.0, 26.7)
+    - 0.065 * cie_lobe(lambda, 501.1, 20.4, 26.2);
+  let y = 0.821 * cie_lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * cie_lobe(lambda, 530.9, 16.3, 31.1);
+  let z = 1.217 * cie_lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * cie_lobe(lambda, 459.0, 26.0, 13.8);
+  let weighted = radiance / wavelength_pdf(lambda);
+  let xyz = vec3(dot(weighted, x), dot(weighted, y), dot(weighted, z));
+  return XYZ_TO_SRGB * xyz / (3. * uniforms.d65_luminance);
+}
 
-alias TriangleVertices = array<vec2f, 6>;
-var<private> vertices: TriangleVertices = TriangleVertices(
-  vec2f(-1.0,  1.0),
-  vec2f(-1.0, -1.0),
-  vec2f( 1.0,  1.0),
-  vec2f( 1.0,  1.0),
-  vec2f(-1.0, -1.0),
-  vec2f( 1.0, -1.0),
+// Must match `MATERIALS` in scene.rs.
+const MATERIAL_COUNT: u32 = 5;
+alias Materials = array<Material, MATERIAL_COUNT>;
+
+var<private> materials: Materials = Materials(
+  Material(/*color*/ vec3(0.7, 0.5, 0.5), /*specular*/1, /*ior*/0., /*abbe*/0.),
+  Material(/*color*/ vec3(0.5, 0.5, 0.9), /*specular*/0, /*ior*/0., /*abbe*/0.),
+  // Crown glass (N-BK7).
+  Material(/*color*/ vec3(1.), /*specular*/1, /*ior*/1.5168, /*abbe*/64.17),
+  // Dense flint glass (N-SF11), which spreads the colors much further, as in prisms.
+  Material(/*color*/ vec3(1.), /*specular*/1, /*ior*/1.78472, /*abbe*/25.68),
+  // Diamond.
+  Material(/*color*/ vec3(1.), /*specular*/1, /*ior*/2.4175, /*abbe*/55.3),
 );
 
-@vertex fn display_vs(@builtin(vertex_index) vid: u32) -> @builtin(position) vec4f {
-  return vec4f(vertices[vid], 0.0, 1.0);
+@group(0) @binding(1) var radiance_samples_old: texture_2d<f32>;
+@group(0) @binding(2) var radiance_samples_new: texture_storage_2d<rgba32float, write>;
+@group(0) @binding(3) var<storage> mesh_data: array<Triangle, MAX_TRIANGLES>;
+@group(0) @binding(5) var<storage> spheres: array<Sphere>;
+@group(0) @binding(6) var<storage> instances: array<MeshInstance>;
+
+// Guides for the denoiser (see denoise.wgsl), taken from the first hit of the camera ray of the
+// latest frame.
+@group(0) @binding(7) var albedo_guide: texture_storage_2d<rgba16float, write>;
+@group(0) @binding(8) var normal_depth_guide: texture_storage_2d<rgba32float, write>;
+
+// Arbitrary output variables, accumulated over the samples of every pixel like the radiance. The
+// first hit is averaged over the samples that hit a surface, while the IDs are those of the latest
+// sample. Light that reached the camera after more than one bounce counts as indirect.
+struct PixelAovs {
+  normal: vec3f,
+  depth: f32,
+  albedo: vec3f,
+  // The material index in the low 16 bits and the object ID in the high 16 bits, or NO_IDS if the
+  // sample hit the sky.
+  ids: u32,
+  indirect: vec3f,
+  hit_count: f32,
+  // The sum of the squared luminance of the radiance samples, from which the noise of the pixel is
+  // estimated (see convergence.wgsl).
+  luminance_squared: f32,
+}
+const NO_IDS: u32 = 0xffffffffu;
+@group(0) @binding(9) var<storage, read_write> aovs: array<PixelAovs>;
+
+// Records the first hit of the camera ray through `pixel`: the denoiser guides of this frame and
+// the geometric AOVs. The AOVs start over on the first frame.
+fn store_first_hit(pixel: vec2u, ray: Ray, hit: Intersection) {
+  store_guides(pixel, ray, hit);
+
+  let index = pixel.x + pixel.y * uniforms.width;
+  var aov = aovs[index];
+  if uniforms.frame_count == 1u {
+    aov = PixelAovs(vec3(0.), 0., vec3(0.), NO_IDS, vec3(0.), 0., 0.);
+  }
+  if is_intersection_valid(hit) {
+    aov.normal += hit.normal;
+    aov.depth += hit.t * dot(ray.direction, uniforms.camera.w);
+    aov.albedo += materials[hit.material_index].color;
+    aov.ids = hit.material_index | (hit.object_id << 16u);
+    aov.hit_count += 1.;
+  } else {
+    aov.ids = NO_IDS;
+  }
+  aovs[index] = aov;
 }
 
-@fragment fn display_fs(@builtin(position) pos: vec4f) -> @location(0) vec4f {
-  init_rng(vec2u(pos.xy));
+// Clamps the luminance of light that escaped to the sky after `bounces` surface hits to the limit
+// for direct or indirect light, scaling all channels alike to keep its hue. The clamped energy is
+// lost, so this darkens the image a little in exchange for getting rid of fireflies.
+fn clamp_escaped_light(bounces: u32, radiance: vec3f) -> vec3f {
+  let limit = select(uniforms.clamp_direct, uniforms.clamp_indirect, bounces > 1u);
+  let luminance = dot(radiance, vec3(0.2126, 0.7152, 0.0722));
+  if limit <= 0. || luminance <= limit {
+    return radiance;
+  }
+  return radiance * (limit / luminance);
+}
 
-  let origin = uniforms.camera.origin;
-  let focus_distance = 1.;
-  let aspect_ratio = f32(uniforms.width) / f32(uniforms.height);
+// Adds light that escaped to the sky after `bounces` surface hits to the AOVs of path `index`.
+fn add_escaped_light(index: u32, bounces: u32, radiance: vec3f) {
+  if bounces > 1u {
+    aovs[index].indirect += radiance;
+  }
+}
 
-  // Offset and normalize the viewport coordinates of the ray.
-  let offset = vec2(rand_f32() - 0.5, rand_f32() - 0.5);
-  var uv = (pos.xy + offset) / vec2f(f32(uniforms.width - 1u), f32(uniforms.height - 1u));
+fn store_guides(pixel: vec2u, ray: Ray, hit: Intersection) {
+  if !is_intersection_valid(hit) {
+    // The sky has no surface. A zero normal tells it apart from geometry.
+    textureStore(albedo_guide, pixel, vec4(1.));
+    textureStore(normal_depth_guide, pixel, vec4(0.));
+    return;
+  }
+  // The depth is measured along the viewing direction rather than along the ray.
+  let depth = hit.t * dot(ray.direction, uniforms.camera.w);
+  textureStore(albedo_guide, pixel, vec4(materials[hit.material_index].color, 1.));
+  textureStore(normal_depth_guide, pixel, vec4(hit.normal, depth));
+}
 
-  // Map `uv` from y-down (normalized) viewport coordinates to camera coordinates.
-  uv = (2. * uv - vec2(1.)) * vec2(aspect_ratio, -1.);
+// WORKGROUP_SIZE_X and WORKGROUP_SIZE_Y are prepended by render.rs when compiling this module.
+@compute @workgroup_size(WORKGROUP_SIZE_X, WORKGROUP_SIZE_Y)
+fn trace_cs(@builtin(global_invocation_id) id: vec3u) {
+  // The dispatch is rounded up to whole workgroups.
+  if id.x >= uniforms.width || id.y >= uniforms.height {
+    return;
+  }
+  let pixel = id.xy;
+  if is_converged(pixel) {
+    skip_pixel(pixel);
+    return;
+  }
+  init_sampler(pixel);
 
-  // Compute the scene-space ray direction by rotating the camera-space vector into a new
-  // basis.
-  let camera_rotation = mat3x3(uniforms.camera.u, uniforms.camera.v, uniforms.camera.w);
-  let direction = camera_rotation * vec3(uv, focus_distance);
-  var ray = Ray(origin, direction);
+  // Offset the viewport coordinates of the ray within the pixel.
+  let offset = sample_2d() - 0.5;
+  let time = mix(uniforms.camera.shutter_open, uniforms.camera.shutter_close, sample_1d());
+  wavelengths = path_wavelengths(sample_1d(), false);
+  var ray = generate_camera_ray(vec2f(pixel) + 0.5 + offset, time);
   var throughput = vec3f(1.);
   var radiance_sample = vec3(0.);
+  var after_diffuse = false;
+  // The camera is assumed to be outside of the objects filled with a medium.
+  var medium = find_medium(NO_OBJECT);
 
   var path_length = 0u;
-  while path_length < MAX_PATH_LENGTH {
-    let hit = intersect_scene(ray);
-    if !is_intersection_valid(hit) {
+  while path_length < uniforms.max_path_length {
+    let event = trace_ray(ray, medium, throughput);
+    throughput = event.throughput;
+    medium = event.medium;
+    if path_length == 0u {
+      store_first_hit(pixel, ray, surface_hit(event));
+    }
+    if event.kind == EVENT_ESCAPED {
       // If no intersection was found, return the color of the sky and terminate the path.
-      radiance_sample += throughput * sky_color(ray);
+      let sky = radiance_to_rgb(throughput * sky_radiance(ray));
+      let escaped = clamp_escaped_light(path_length, sky);
+      radiance_sample += escaped;
+      add_escaped_light(pixel.x + pixel.y * uniforms.width, path_length, escaped);
+      break;
+    }
+    if event.kind == EVENT_ABSORBED {
       break;
     }
 
-    let material = materials[hit.material_index];
-    let scattered = scatter(ray, hit, material);
+    start_bounce(path_length);
+    var scattered: Scatter;
+    if event.kind == EVENT_MEDIUM {
+      scattered = scatter_medium(ray, point_on_ray(ray, event.hit.t), medium);
+      after_diffuse = true;
+    } else {
+      if path_length == 0u {
+        primary_light = primary_resampled_light(pixel, point_on_ray(ray, event.hit.t));
+      }
+      let material = materials[event.hit.material_index];
+      if uniforms.probe_preview == 1u && !after_diffuse && material.specular != 1u {
+        // The probes stand in for the rest of the path.
+        let hit_point = point_on_ray(ray, event.hit.t);
+        let lit = probe_lit_surface(hit_point, event.hit.normal, material, ray.time, medium);
+        let from_probes = clamp_escaped_light(path_length + 2u, radiance_to_rgb(throughput * lit));
+        radiance_sample += from_probes;
+        add_escaped_light(pixel.x + pixel.y * uniforms.width, path_length + 2u, from_probes);
+        break;
+      }
+      scattered = scatter(ray, event.hit, material, after_diffuse, medium);
+      after_diffuse = after_diffuse || material.specular != 1u;
+    }
     throughput *= scattered.attenuation;
     ray = scattered.ray;
+    let survival_weight = russian_roulette(path_length, throughput);
+    if survival_weight == 0. {
+      break;
+    }
+    throughput *= survival_weight;
     path_length += 1u;
   }
 
+  add_sample(pixel, radiance_sample);
+}
+
+// Adds a radiance sample to the sum of `pixel`. The alpha channel counts the samples.
+fn add_sample(pixel: vec2u, radiance_sample: vec3f) {
   // Fetch the old sum of samples.
-  var old_sum: vec3f;
+  var old_sum: vec4f;
   if uniforms.frame_count > 1 {
-    old_sum = textureLoad(radiance_samples_old, vec2u(pos.xy), 0).xyz;
+    old_sum = textureLoad(radiance_samples_old, pixel, 0);
   } else {
-    old_sum = vec3(0.);
+    old_sum = vec4(0.);
   }
 
   // Compute and store the new sum.
-  let new_sum = radiance_sample + old_sum;
-  textureStore(radiance_samples_new, vec2u(pos.xy), vec4(new_sum, 0.));
+  textureStore(radiance_samples_new, pixel, old_sum + vec4(radiance_sample, 1.));
+
+  // The AOVs of the pixel were reset by `store_first_hit` on the first frame.
+  let luminance = dot(radiance_sample, vec3(0.2126, 0.7152, 0.0722));
+  aovs[pixel.x + pixel.y * uniforms.width].luminance_squared += luminance * luminance;
+}
+
+// Adaptive sampling spends the samples of a frame on the pixels that are still noisy. Pixels need
+// this many samples before their variance can be trusted.
+const MIN_ADAPTIVE_SAMPLES: f32 = 16.;
+// Converged pixels still get a sample every so many frames, so that one that only looked
+// converged by chance (e.g. because it hasn't caught a highlight yet) can recover.
+const ADAPTIVE_REFRESH_INTERVAL: u32 = 16u;
+// Keeps the error of nearly black pixels from blowing up, as their noise is hardly visible.
+// Must match convergence.wgsl.
+const DARK_LUMINANCE: f32 = 0.01;
+
+// The standard error of the mean of the radiance samples summed up in `sum`, relative to that
+// mean. Must match `relative_error` in convergence.wgsl.
+fn relative_error(sum: vec4f, luminance_squared: f32) -> f32 {
+  let n = sum.a;
+  let mean = dot(sum.rgb, vec3(0.2126, 0.7152, 0.0722)) / n;
+  let variance = max(luminance_squared / n - mean * mean, 0.) * n / max(n - 1., 1.);
+  return sqrt(variance / n) / (mean + DARK_LUMINANCE);
+}
+
+// Whether adaptive sampling skips `pixel` in this frame. This only depends on the samples of
+// previous frames, so every kernel of a frame gets the same answer.
+fn is_converged(pixel: vec2u) -> bool {
+  if uniforms.adaptive_threshold <= 0. || uniforms.frame_count <= 1u ||
+      uniforms.frame_count % ADAPTIVE_REFRESH_INTERVAL == 0u {
+    return false;
+  }
+  let sum = textureLoad(radiance_samples_old, pixel, 0);
+  if sum.a < MIN_ADAPTIVE_SAMPLES {
+    return false;
+  }
+  let luminance_squared = aovs[pixel.x + pixel.y * uniforms.width].luminance_squared;
+  return relative_error(sum, luminance_squared) <= uniforms.adaptive_threshold;
+}
 
-  // Display the average after gamma correction (gamma = 2.2)
-  let color = new_sum / f32(uniforms.frame_count);
-  return vec4(pow(color, vec3(1. / 2.2)), 1.);
+// Carries the sum of a skipped pixel over to the new texture unchanged.
+fn skip_pixel(pixel: vec2u) {
+  textureStore(radiance_samples_new, pixel, textureLoad(radiance_samples_old, pixel, 0));
 }
@@ -0,0 +1,216 @@
+//! Spectral rendering support: the CIE color matching functions, the D65 illuminant and the
+//! table that turns RGB colors into smooth spectra. See Jakob and Hanika, "A Low-Dimensional
+//! Function Space for Efficient Spectral Upsampling" (Eurographics 2019).
+
+/// The range of wavelengths that paths sample in spectral mode, in nanometers. Must match
+/// shaders.wgsl.
+pub const LAMBDA_MIN: f32 = 380.;
+pub const LAMBDA_MAX: f32 = 780.;
+
+/// The number of entries of the RGB to spectrum table along each axis. Must match shaders.wgsl.
+pub const TABLE_SIZE: usize = 16;
+
+/// The spacing of the wavelengths over which spectra are integrated, in nanometers.
+const INTEGRATION_STEP: f32 = 10.;
+
+/// The relative spectral power of the CIE D65 illuminant from 380 to 780 nm in steps of 10 nm.
+/// Must match shaders.wgsl.
+const D65: [f32; 41] = [
+    49.9755, 54.6482, 82.7549, 91.486, 93.4318, 86.6823, 104.865, 117.008, 117.812, 114.861,
+    115.923, 108.811, 109.354, 107.802, 104.790, 107.689, 104.405, 104.046, 100.000, 96.3342,
+    95.788, 88.6856, 90.0062, 89.5991, 87.6987, 83.2886, 83.6992, 80.0268, 80.2146, 82.2778,
+    78.2842, 69.7213, 71.6091, 74.349, 61.604, 69.8856, 75.087, 63.5927, 46.4182, 66.8054,
+    63.3828,
+];
+
+/// Converts CIE XYZ to linear sRGB, whose white point is D65.
+const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+    [3.2404542, -1.5371385, -0.4985314],
+    [-0.969266, 1.8760108, 0.041556],
+    [0.0556434, -0.2040259, 1.0572252],
+];
+
+/// The CIE 1931 color matching functions at `lambda`, as fitted by Wyman et al., "Simple
+/// Analytic Approximations to the CIE XYZ Color Matching Functions" (JCGT 2013). Must match
+/// shaders.wgsl.
+fn cie_xyz(lambda: f32) -> [f32; 3] {
+    let g = |mu: f32, sigma1: f32, sigma2: f32| {
+        let t = (lambda - mu) / if lambda < mu { sigma1 } else { sigma2 };
+        (-0.5 * t * t).exp()
+    };
+    [
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7)
+            - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    ]
+}
+
+/// D65 at `lambda`, interpolated linearly.
+fn d65(lambda: f32) -> f32 {
+    let x = ((lambda - LAMBDA_MIN) / 10.).clamp(0., (D65.len() - 1) as f32);
+    let i = (x as usize).min(D65.len() - 2);
+    let t = x - i as f32;
+    D65[i] * (1. - t) + D65[i + 1] * t
+}
+
+/// The luminance (Y) of D65 over the sampled wavelengths, which the shaders divide spectral
+/// radiance by so that a white sky has a luminance of 1.
+pub fn d65_luminance() -> f32 {
+    integration_wavelengths()
+        .map(|(lambda, weight)| d65(lambda) * cie_xyz(lambda)[1] * weight)
+        .sum()
+}
+
+// The wavelengths and weights of the trapezoidal rule over the sampled range.
+fn integration_wavelengths() -> impl Iterator<Item = (f32, f32)> {
+    let count = ((LAMBDA_MAX - LAMBDA_MIN) / INTEGRATION_STEP) as usize + 1;
+    (0..count).map(move |i| {
+        let lambda = LAMBDA_MIN + i as f32 * INTEGRATION_STEP;
+        let end = i == 0 || i == count - 1;
+        (lambda, if end { 0.5 } else { 1. } * INTEGRATION_STEP)
+    })
+}
+
+/// The sigmoid that keeps the spectra of the table between 0 and 1.
+fn sigmoid(x: f32) -> f32 {
+    0.5 + x / (2. * (1. + x * x).sqrt())
+}
+
+/// The maximum channel of the colors at the depth entries of the table. They are packed towards
+/// 0 and 1, where the coefficients change the fastest. Must match shaders.wgsl.
+fn table_scale(k: usize) -> f32 {
+    let smoothstep = |x: f32| x * x * (3. - 2. * x);
+    smoothstep(smoothstep(k as f32 / (TABLE_SIZE - 1) as f32))
+}
+
+/// Computes the table that maps an RGB reflectance to the coefficients `c` of the spectrum
+/// `sigmoid(c[0] x² + c[1] x + c[2])`, where `x` is the wavelength mapped from the sampled range
+/// to [0, 1]. The spectrum has that RGB color when lit by D65. The table is indexed by the
+/// largest channel (red, green or blue), that channel's value (see `table_scale`), and the two
+/// other channels relative to it: entry `[((l * TABLE_SIZE + z) * TABLE_SIZE + y) * TABLE_SIZE +
+/// x]` holds the coefficients of the color whose channel `l` is `table_scale(z)` and whose
+/// channels `l + 1` and `l + 2` (modulo 3) are that times `x` and `y` over `TABLE_SIZE - 1`.
+pub fn rgb_to_spectrum_table() -> Vec<[f32; 4]> {
+    // The spectrum is only ever evaluated at these wavelengths, each of which contributes its
+    // color matching functions under D65, in sRGB.
+    let normalization = d65_luminance();
+    let samples = integration_wavelengths()
+        .map(|(lambda, weight)| {
+            let xyz = cie_xyz(lambda).map(|c| c * d65(lambda) * weight / normalization);
+            let rgb = XYZ_TO_SRGB.map(|row| (0..3).map(|i| row[i] * xyz[i]).sum::<f32>());
+            ((lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN), rgb)
+        })
+        .collect::<Vec<_>>();
+
+    let n = TABLE_SIZE;
+    let mut table = vec![[0.; 4]; 3 * n * n * n];
+    // Each fit starts from the coefficients of the previous, similar color. The walk starts at
+    // a medium brightness, where the fit converges from zero, and goes both ways from there.
+    let start = n / 5;
+    for l in 0..3 {
+        for y in 0..n {
+            for x in 0..n {
+                for range in [
+                    (start..n).collect::<Vec<_>>(),
+                    (0..start).rev().collect::<Vec<_>>(),
+                ] {
+                    let mut coefficients = [0.; 3];
+                    for z in range {
+                        let scale = table_scale(z);
+                        let mut rgb = [0.; 3];
+                        rgb[l] = scale;
+                        rgb[(l + 1) % 3] = x as f32 / (n - 1) as f32 * scale;
+                        rgb[(l + 2) % 3] = y as f32 / (n - 1) as f32 * scale;
+                        fit_coefficients(&samples, rgb, &mut coefficients);
+                        let [c0, c1, c2] = coefficients;
+                        table[((l * n + z) * n + y) * n + x] = [c0, c1, c2, 0.];
+                    }
+                }
+            }
+        }
+    }
+    table
+}
+
+// Refines `coefficients` by Gauss-Newton iterations until the spectrum they describe has the
+// color `target`. Steps that overshoot are halved until they reduce the error.
+fn fit_coefficients(
+    samples: &[(f32, [f32; 3])],
+    target: [f32; 3],
+    coefficients: &mut [f32; 3],
+) {
+    let (mut error, mut jacobian) = evaluate_fit(samples, target, *coefficients);
+    for _ in 0..30 {
+        let squared_error = |e: [f32; 3]| e.iter().map(|e| e * e).sum::<f32>();
+        if squared_error(error) < 1e-10 {
+            break;
+        }
+        let Some(step) = solve_3x3(jacobian, error) else {
+            break;
+        };
+        let mut scale = 1.;
+        let improved = loop {
+            let candidate = [0, 1, 2].map(|i| coefficients[i] - scale * step[i]);
+            let fit = evaluate_fit(samples, target, candidate);
+            if squared_error(fit.0) < squared_error(error) {
+                break Some((candidate, fit));
+            }
+            scale *= 0.5;
+            if scale < 1e-3 {
+                break None;
+            }
+        };
+        let Some((candidate, fit)) = improved else {
+            break;
+        };
+        *coefficients = candidate;
+        (error, jacobian) = fit;
+    }
+}
+
+// The difference between the color of the spectrum with `coefficients` and `target`, and its
+// derivatives with respect to the coefficients.
+fn evaluate_fit(
+    samples: &[(f32, [f32; 3])],
+    target: [f32; 3],
+    coefficients: [f32; 3],
+) -> ([f32; 3], [[f32; 3]; 3]) {
+    let [c0, c1, c2] = coefficients;
+    let mut error = target.map(|c| -c);
+    let mut jacobian = [[0.; 3]; 3];
+    for &(x, weights) in samples {
+        let polynomial = (c0 * x + c1) * x + c2;
+        let value = sigmoid(polynomial);
+        // The derivative of the sigmoid.
+        let root = (1. + polynomial * polynomial).sqrt();
+        let slope = 0.5 / (root * root * root);
+        for i in 0..3 {
+            error[i] += weights[i] * value;
+            for (j, derivative) in [x * x, x, 1.].into_iter().enumerate() {
+                jacobian[i][j] += weights[i] * slope * derivative;
+            }
+        }
+    }
+    (error, jacobian)
+}
+
+// Solves `a x = b` by Cramer's rule, unless `a` is singular.
+fn solve_3x3(a: [[f32; 3]; 3], b: [f32; 3]) -> Option<[f32; 3]> {
+    let determinant = |m: [[f32; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = determinant(a);
+    if d.abs() < 1e-20 {
+        return None;
+    }
+    Some([0, 1, 2].map(|column| {
+        let mut m = a;
+        for row in 0..3 {
+            m[row][column] = b[row];
+        }
+        determinant(m) / d
+    }))
+}
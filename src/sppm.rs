@@ -0,0 +1,134 @@
+use crate::wavefront::read_write_storage_entry;
+
+/// The number of photons shot every frame. Must match sppm.wgsl.
+const PHOTONS_PER_FRAME: u32 = 1 << 18;
+/// The most photons that a photon path stores. Must match sppm.wgsl.
+const MAX_DEPOSITS: u32 = 4;
+/// The number of cells of the photon hash grid. Must match sppm.wgsl.
+const GRID_SIZE: u32 = 1 << 20;
+/// The most workgroups that a dispatch may have along a dimension, by default.
+const MAX_WORKGROUPS: u32 = 65535;
+
+/// The kernels of stochastic progressive photon mapping (see sppm.wgsl), the photon map they
+/// rebuild every frame and the photon statistics that every pixel keeps across frames.
+pub struct Sppm {
+    clear: wgpu::ComputePipeline,
+    photons: wgpu::ComputePipeline,
+    gather: wgpu::ComputePipeline,
+
+    // Like the megakernel's bind groups, these alternate between the two sample textures.
+    bind_groups: [wgpu::BindGroup; 2],
+    workgroup_size: [u32; 2],
+}
+
+impl Sppm {
+    /// `trace_entries` are the bindings shared with the megakernel, and `create_bind_groups`
+    /// builds the two alternating bind groups for a layout that adds the given entries to them.
+    pub fn new(
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        pixel_count: u32,
+        workgroup_size: [u32; 2],
+        trace_entries: &[wgpu::BindGroupLayoutEntry],
+        create_bind_groups: impl FnOnce(
+            &wgpu::BindGroupLayout,
+            &[wgpu::BindGroupEntry],
+        ) -> [wgpu::BindGroup; 2],
+    ) -> Sppm {
+        let storage_buffer = |label, size| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        };
+        // One `SppmPixel` (32 bytes) per pixel.
+        let pixels = storage_buffer("sppm pixels", pixel_count as u64 * 32);
+        // The photon count, padded to the alignment of the photons, then every photon that the
+        // frame may store (32 bytes each).
+        let photon_map = storage_buffer(
+            "photon map",
+            16 + (PHOTONS_PER_FRAME * MAX_DEPOSITS) as u64 * 32,
+        );
+        let photon_grid = storage_buffer("photon grid", GRID_SIZE as u64 * 4);
+
+        let layout_entries = [
+            trace_entries,
+            &[
+                read_write_storage_entry(21),
+                read_write_storage_entry(22),
+                read_write_storage_entry(23),
+            ],
+        ]
+        .concat();
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("sppm"),
+            entries: &layout_entries,
+        });
+        let bind_groups = create_bind_groups(
+            &layout,
+            &[
+                wgpu::BindGroupEntry {
+                    binding: 21,
+                    resource: pixels.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 22,
+                    resource: photon_map.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 23,
+                    resource: photon_grid.as_entire_binding(),
+                },
+            ],
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&layout],
+            ..Default::default()
+        });
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: shader_module,
+                entry_point,
+            })
+        };
+
+        Sppm {
+            clear: pipeline("sppm_clear_cs"),
+            photons: pipeline("sppm_photon_cs"),
+            gather: pipeline("sppm_cs"),
+            bind_groups,
+            workgroup_size,
+        }
+    }
+
+    /// Records the kernels that shoot the photons of frame `frame_count` and gather them at the
+    /// visible point of every pixel.
+    pub fn encode(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        frame_count: u32,
+        width: u32,
+        height: u32,
+    ) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("sppm pass"),
+            timestamp_writes: None,
+        });
+        pass.set_bind_group(0, &self.bind_groups[(frame_count % 2) as usize], &[]);
+        let [x, y] = self.workgroup_size;
+        // The 1D kernels run as many invocations per workgroup as the 2D ones, and loop over
+        // their items when they would need more workgroups than a dispatch allows.
+        let workgroups = |count: u32| count.div_ceil(x * y).min(MAX_WORKGROUPS);
+        pass.set_pipeline(&self.clear);
+        pass.dispatch_workgroups(workgroups(GRID_SIZE), 1, 1);
+        pass.set_pipeline(&self.photons);
+        pass.dispatch_workgroups(workgroups(PHOTONS_PER_FRAME), 1, 1);
+        pass.set_pipeline(&self.gather);
+        pass.dispatch_workgroups(width.div_ceil(x), height.div_ceil(y), 1);
+    }
+}
@@ -0,0 +1,342 @@
+// Stochastic progressive photon mapping (Hachisuka and Jensen, "Stochastic Progressive Photon
+// Mapping", 2009, following pbrt-v3's implementation). Every frame shoots a fixed number of
+// photons from the lights and stores those that land on diffuse surfaces in a hash grid. Then
+// every pixel follows its camera path through mirrors and glass to its first diffuse hit, its
+// visible point, and gathers the photons that landed around it. The pixels keep the photons they
+// gathered over all frames, within a radius that shrinks as they add up, so that the estimate
+// converges. Light that reaches a diffuse surface through glass or off a mirror (caustics), which
+// camera paths hardly ever find, is what photons find best.
+//
+// The visible points are lit by the lights through a shadow ray, so photons are only stored from
+// their second hit on. The sky sends no photons: its light is found by carrying on the camera
+// paths past the visible points. The transport is the physically based one of bdpt.wgsl, whose
+// functions are shared, so both integrators converge to the same image. In spectral mode all the
+// paths of a frame carry the same wavelengths, so that photons and camera paths can meet.
+
+// The photons shot every frame, and the most that each stores along its path. Must match sppm.rs.
+const SPPM_PHOTONS_PER_FRAME: u32 = 262144u;
+const SPPM_MAX_DEPOSITS: u32 = 4u;
+// The number of cells of the hash grid. Must match sppm.rs.
+const SPPM_GRID_SIZE: u32 = 1048576u;
+// The fraction of the newly gathered photons that the pixels keep (α in the paper).
+const SPPM_ALPHA: f32 = 0.6666667;
+const NO_PHOTON: u32 = 0xffffffffu;
+const SPPM_PHOTON_SEED: u32 = 0x5eed9407u;
+
+struct Photon {
+  point: vec3f,
+  // The next photon in the same cell of the grid, or NO_PHOTON.
+  next: u32,
+  // The light that the photon carries, over the density of its path.
+  power: vec3f,
+  // The direction it came from, with 1 in w if its path had dropped its secondary wavelengths
+  // (pack4x8snorm).
+  incoming: u32,
+}
+
+struct PhotonMap {
+  count: atomic<u32>,
+  photons: array<Photon>,
+}
+
+// What a pixel keeps from one frame to the next.
+struct SppmPixel {
+  // The light of the photons gathered so far, scaled to the current radius (τ in the paper).
+  flux: vec3f,
+  radius: f32,
+  // The sum of the light that the camera paths found on their own: through shadow rays from the
+  // visible points and from the sky.
+  direct: vec3f,
+  // The number of photons that the flux stands for (N in the paper).
+  photon_count: f32,
+}
+
+@group(0) @binding(21) var<storage, read_write> sppm_pixels: array<SppmPixel>;
+@group(0) @binding(22) var<storage, read_write> photon_map: PhotonMap;
+// The first photon of every cell of the grid, or NO_PHOTON.
+@group(0) @binding(23) var<storage, read_write> photon_grid: array<atomic<u32>>;
+
+// The wavelengths of all the paths of the frame. They follow a golden ratio sequence over the
+// frames, which covers the spectrum evenly however many frames there are: the wavelengths that
+// the frames miss are missing from the whole image, not from a single pixel.
+fn frame_wavelengths() -> Wavelengths {
+  return path_wavelengths(u32_to_unit(uniforms.frame_count * 0x9e3779b9u), false);
+}
+
+// The cells of the grid are as wide as the initial radius, so that the photons around a point
+// all lie in the cells next to its own.
+fn photon_cell(point: vec3f) -> vec3i {
+  return vec3i(floor(point / uniforms.photon_radius));
+}
+
+fn cell_hash(cell: vec3i) -> u32 {
+  let c = bitcast<vec3u>(cell);
+  return ((c.x * 73856093u) ^ (c.y * 19349663u) ^ (c.z * 83492791u)) % SPPM_GRID_SIZE;
+}
+
+fn store_photon(point: vec3f, incoming: vec3f, power: vec3f) {
+  let index = atomicAdd(&photon_map.count, 1u);
+  if index >= arrayLength(&photon_map.photons) {
+    return;
+  }
+  let next = atomicExchange(&photon_grid[cell_hash(photon_cell(point))], index);
+  let terminated = select(0., 1., wavelengths.secondary_terminated);
+  let direction = pack4x8snorm(vec4(incoming, terminated));
+  photon_map.photons[index] = Photon(point, next, power, direction);
+}
+
+// The 1D kernels below may run on fewer invocations than they have items, which each then
+// handles every so many (small workgroups would otherwise need too many of them).
+
+// Empties the photon map of the previous frame.
+@compute @workgroup_size(QUEUE_WORKGROUP_SIZE)
+fn sppm_clear_cs(
+  @builtin(global_invocation_id) id: vec3u,
+  @builtin(num_workgroups) workgroups: vec3u,
+) {
+  if id.x == 0u {
+    atomicStore(&photon_map.count, 0u);
+  }
+  for (var cell = id.x; cell < SPPM_GRID_SIZE; cell += workgroups.x * QUEUE_WORKGROUP_SIZE) {
+    atomicStore(&photon_grid[cell], NO_PHOTON);
+  }
+}
+
+@compute @workgroup_size(QUEUE_WORKGROUP_SIZE)
+fn sppm_photon_cs(
+  @builtin(global_invocation_id) id: vec3u,
+  @builtin(num_workgroups) workgroups: vec3u,
+) {
+  let stride = workgroups.x * QUEUE_WORKGROUP_SIZE;
+  for (var photon = id.x; photon < SPPM_PHOTONS_PER_FRAME; photon += stride) {
+    shoot_photon(photon);
+  }
+}
+
+// Shoots `photon` from a light picked uniformly, which emits uniformly in all directions. Every
+// photon follows its own sample sequence, one sample per frame.
+fn shoot_photon(photon: u32) {
+  let light_count = uniforms.light_count;
+  if light_count == 0u {
+    return;
+  }
+  let seed = jenkins_hash(photon ^ SPPM_PHOTON_SEED);
+  let sequence_pixel = vec2(photon % BLUE_NOISE_SIZE, photon / BLUE_NOISE_SIZE);
+  sequence = SampleSequence(sequence_pixel, seed, uniforms.frame_count - 1u, 0u);
+  rng.state = jenkins_hash(seed ^ jenkins_hash(uniforms.frame_count));
+  wavelengths = frame_wavelengths();
+
+  // The photon takes the camera dimensions for its time and its way out of the light.
+  path_time = mix(uniforms.camera.shutter_open, uniforms.camera.shutter_close, sample_1d());
+  let choice = min(u32(sample_1d() * f32(light_count)), light_count - 1u);
+  let light = lights[choice];
+  let power = light_intensity(light) * f32(light_count) / UNIFORM_SPHERE_PDF;
+  let initial_power = max_component(power);
+  if initial_power <= 0. {
+    return;
+  }
+  var ray = Ray(light.position, sample_uniform_sphere(sample_2d()), path_time);
+  var beta = power;
+  var deposits = 0u;
+  for (var bounce = 0u; bounce < uniforms.max_path_length; bounce += 1u) {
+    let hit = intersect_scene(ray);
+    if !is_intersection_valid(hit) {
+      break;
+    }
+    let material = materials[hit.material_index];
+    let vertex = Vertex(
+      point_on_ray(ray, hit.t), VERTEX_SURFACE, normalize(hit.normal), hit.material_index, beta,
+      0., 0., is_delta(material), wavelengths.secondary_terminated,
+    );
+    // The first hits are lit by the shadow rays of the visible points.
+    if !vertex.delta && bounce > 0u {
+      store_photon(vertex.point, -ray.direction, beta);
+      deposits += 1u;
+      if deposits == SPPM_MAX_DEPOSITS {
+        break;
+      }
+    }
+
+    start_bounce(bounce);
+    let sample = sample_vertex(vertex, ray.direction);
+    beta *= sample.weight;
+    // The power of the photons stays close to the initial one.
+    let survival_weight = russian_roulette_at(
+      bounce, bounce, vec3(max_component(beta) / initial_power),
+    );
+    if survival_weight == 0. || all(beta == vec3(0.)) {
+      break;
+    }
+    beta *= survival_weight;
+    ray = Ray(vertex.point, sample.direction, path_time);
+  }
+}
+
+// The light that a light picked uniformly sends to `vertex` towards `wo`.
+fn sppm_direct_light(vertex: Vertex, wo: vec3f) -> vec3f {
+  let light_count = uniforms.light_count;
+  if light_count == 0u {
+    return vec3(0.);
+  }
+  let light = lights[min(u32(rand_f32() * f32(light_count)), light_count - 1u)];
+  let to_light = light.position - vertex.point;
+  let distance_squared = dot(to_light, to_light);
+  let wi = to_light * inverseSqrt(distance_squared);
+  let irradiance = light_intensity(light) * abs(dot(vertex.normal, wi)) * f32(light_count)
+    / distance_squared;
+  let contribution = vertex.beta * vertex_bsdf(vertex, wo, wi) * irradiance;
+  if all(contribution == vec3(0.)) || !visible(vertex.point, light.position) {
+    return vec3(0.);
+  }
+  return radiance_to_rgb(contribution);
+}
+
+// The light of the photons within `radius` of `vertex` that it reflects towards `wo`, summed
+// over the photons, and their number in w.
+fn gather_photons(vertex: Vertex, wo: vec3f, radius: f32) -> vec4f {
+  var flux = vec3(0.);
+  var count = 0.;
+  let center = photon_cell(vertex.point);
+  for (var z = -1; z <= 1; z += 1) {
+    for (var y = -1; y <= 1; y += 1) {
+      for (var x = -1; x <= 1; x += 1) {
+        let cell = center + vec3(x, y, z);
+        var index = atomicLoad(&photon_grid[cell_hash(cell)]);
+        while index != NO_PHOTON {
+          let photon = photon_map.photons[index];
+          index = photon.next;
+          // Other cells may share the hash of this one.
+          let offset = photon.point - vertex.point;
+          if any(photon_cell(photon.point) != cell) || dot(offset, offset) > radius * radius {
+            continue;
+          }
+          let incoming = unpack4x8snorm(photon.incoming);
+          var power = photon.power * vertex_bsdf(vertex, wo, normalize(incoming.xyz));
+          if incoming.w > 0.5 && vertex.terminated {
+            // Both paths made the hero wavelength stand for all three.
+            power /= 3.;
+          }
+          flux += power;
+          count += 1.;
+        }
+      }
+    }
+  }
+  return vec4(radiance_to_rgb(vertex.beta * flux), count);
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE_X, WORKGROUP_SIZE_Y)
+fn sppm_cs(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= uniforms.width || id.y >= uniforms.height {
+    return;
+  }
+  let pixel = id.xy;
+  let index = pixel.x + pixel.y * uniforms.width;
+  init_sampler(pixel);
+
+  // The camera path takes the sample dimensions of the other integrators, but the wavelengths
+  // are those of the frame.
+  let offset = sample_2d() - 0.5;
+  path_time = mix(uniforms.camera.shutter_open, uniforms.camera.shutter_close, sample_1d());
+  _ = sample_1d();
+  wavelengths = frame_wavelengths();
+  let camera_ray = generate_camera_ray(vec2f(pixel) + 0.5 + offset, path_time);
+  var ray = Ray(camera_ray.origin, normalize(camera_ray.direction), path_time);
+
+  var state = sppm_pixels[index];
+  if uniforms.frame_count == 1u {
+    state = SppmPixel(vec3(0.), uniforms.photon_radius, vec3(0.), 0.);
+  }
+
+  // Follows the camera path through mirrors and glass to its visible point.
+  var radiance = vec3(0.);
+  var beta = vec3(1.);
+  var photons = vec4(0.);
+  for (var bounce = 0u; bounce < uniforms.max_path_length; bounce += 1u) {
+    let hit = intersect_scene(ray);
+    if bounce == 0u {
+      store_first_hit(pixel, ray, hit);
+    }
+    if !is_intersection_valid(hit) {
+      radiance += pixel_light(pixel, bounce, radiance_to_rgb(beta * sky_radiance(ray)));
+      break;
+    }
+    let material = materials[hit.material_index];
+    let vertex = Vertex(
+      point_on_ray(ray, hit.t), VERTEX_SURFACE, normalize(hit.normal), hit.material_index, beta,
+      0., 0., is_delta(material), wavelengths.secondary_terminated,
+    );
+    start_bounce(bounce);
+    if !vertex.delta {
+      let wo = -ray.direction;
+      radiance += pixel_light(pixel, bounce + 1u, sppm_direct_light(vertex, wo));
+      photons = gather_photons(vertex, wo, state.radius);
+      radiance += sky_past_visible_point(pixel, vertex, ray, bounce);
+      break;
+    }
+    let sample = sample_vertex(vertex, ray.direction);
+    beta *= sample.weight;
+    let survival_weight = russian_roulette_at(bounce, bounce, beta);
+    if survival_weight == 0. || all(beta == vec3(0.)) {
+      break;
+    }
+    beta *= survival_weight;
+    ray = Ray(vertex.point, sample.direction, path_time);
+  }
+
+  // Keeps a fraction of the new photons, and shrinks the radius so that the density of photons
+  // within it stays the same.
+  if photons.w > 0. {
+    let photon_count = state.photon_count + SPPM_ALPHA * photons.w;
+    let radius = state.radius * sqrt(photon_count / (state.photon_count + photons.w));
+    let shrink = radius / state.radius;
+    state.flux = (state.flux + photons.rgb) * shrink * shrink;
+    state.radius = radius;
+    state.photon_count = photon_count;
+  }
+  state.direct += radiance;
+  sppm_pixels[index] = state;
+
+  // The pixel's estimate replaces its sum of samples, scaled by the number of frames so that the
+  // display divides it back.
+  let frames = f32(uniforms.frame_count);
+  let emitted = frames * f32(SPPM_PHOTONS_PER_FRAME);
+  let indirect = state.flux / (emitted * PI * state.radius * state.radius);
+  textureStore(radiance_samples_new, pixel, vec4(state.direct + frames * indirect, frames));
+  let luminance = dot(radiance + indirect, vec3(0.2126, 0.7152, 0.0722));
+  aovs[index].luminance_squared += luminance * luminance;
+  // The photons stand for the indirect light of every sample so far.
+  aovs[index].indirect += indirect;
+}
+
+// Carries on the camera path of `pixel` from its visible point `vertex`, which it reached along
+// `ray` after `bounce` bounces, and returns the light of the sky that it finds.
+fn sky_past_visible_point(pixel: vec2u, vertex: Vertex, ray: Ray, bounce: u32) -> vec3f {
+  var radiance = vec3(0.);
+  var current = vertex;
+  var direction = ray.direction;
+  for (var b = bounce; b + 1u < uniforms.max_path_length; b += 1u) {
+    start_bounce(b);
+    let sample = sample_vertex(current, direction);
+    var beta = current.beta * sample.weight;
+    let survival_weight = russian_roulette_at(b, b, beta);
+    if survival_weight == 0. || all(beta == vec3(0.)) {
+      break;
+    }
+    beta *= survival_weight;
+    let next_ray = Ray(current.point, sample.direction, path_time);
+    let hit = intersect_scene(next_ray);
+    if !is_intersection_valid(hit) {
+      let sky = radiance_to_rgb(beta * sky_radiance(next_ray));
+      radiance += pixel_light(pixel, b + 1u, sky);
+      break;
+    }
+    let material = materials[hit.material_index];
+    current = Vertex(
+      point_on_ray(next_ray, hit.t), VERTEX_SURFACE, normalize(hit.normal), hit.material_index,
+      beta, 0., 0., is_delta(material), wavelengths.secondary_terminated,
+    );
+    direction = next_ray.direction;
+  }
+  return radiance;
+}
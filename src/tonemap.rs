@@ -0,0 +1,50 @@
+use anyhow::{bail, Result};
+
+/// The operator that compresses exposed radiance into the displayable range. The values must
+/// match the `TONEMAP_*` constants in display.wgsl.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Tonemap {
+    /// Values above 1 are clipped.
+    Clamp = 0,
+    Reinhard = 1,
+    /// John Hable's filmic curve.
+    Filmic = 2,
+    Aces = 3,
+    AgX = 4,
+}
+
+const OPERATORS: [Tonemap; 5] = [
+    Tonemap::Clamp,
+    Tonemap::Reinhard,
+    Tonemap::Filmic,
+    Tonemap::Aces,
+    Tonemap::AgX,
+];
+
+/// The linear value that maps to display white when none is given.
+pub const DEFAULT_WHITE_POINT: f32 = 4.;
+
+impl Tonemap {
+    pub fn from_name(name: &str) -> Result<Tonemap> {
+        match OPERATORS.iter().find(|op| op.name() == name) {
+            Some(&op) => Ok(op),
+            None => bail!("unknown tone mapping operator \"{}\"", name),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tonemap::Clamp => "clamp",
+            Tonemap::Reinhard => "reinhard",
+            Tonemap::Filmic => "filmic",
+            Tonemap::Aces => "aces",
+            Tonemap::AgX => "agx",
+        }
+    }
+
+    /// The operator that follows this one, wrapping around after the last.
+    pub fn next(self) -> Tonemap {
+        OPERATORS[(self as usize + 1) % OPERATORS.len()]
+    }
+}
@@ -1,5 +1,3 @@
-use bytemuck::{Pod, Zeroable};
-
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct BoundingBox {
@@ -29,4 +27,93 @@ pub struct Light {
     pub _pad0: u32,
     pub color: [f32; 3],
     pub intensity: f32,
-} 
\ No newline at end of file
+}
+
+/// A similarity transform: `p' = rotation * (scale * p) + translation`. The rotation is a unit
+/// quaternion stored as (x, y, z, w).
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Transform {
+    pub rotation: [f32; 4],
+    pub translation: [f32; 3],
+    pub scale: f32,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        rotation: [0., 0., 0., 1.],
+        translation: [0.; 3],
+        scale: 1.,
+    };
+
+    /// Transforms the point `p`.
+    pub fn apply(&self, p: [f32; 3]) -> [f32; 3] {
+        // v + 2w (q × v) + 2 q × (q × v), with v the scaled point and q the vector part.
+        let [x, y, z, w] = self.rotation;
+        let v = p.map(|c| c * self.scale);
+        let cross = |a: [f32; 3], b: [f32; 3]| {
+            [
+                a[1] * b[2] - a[2] * b[1],
+                a[2] * b[0] - a[0] * b[2],
+                a[0] * b[1] - a[1] * b[0],
+            ]
+        };
+        let t = cross([x, y, z], v).map(|c| 2. * c);
+        let u = cross([x, y, z], t);
+        [0, 1, 2].map(|i| v[i] + w * t[i] + u[i] + self.translation[i])
+    }
+}
+
+/// A sphere that moves linearly from `center0` at the start of the frame to `center1` at its end.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Sphere {
+    pub center0: [f32; 3],
+    pub radius: f32,
+    pub center1: [f32; 3],
+    pub material_index: u32,
+}
+
+/// An instance of the mesh placed by `transform0` at the start of the frame and `transform1` at
+/// its end.
+/// Translation and scale are interpolated linearly and the rotation along the shortest arc.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MeshInstance {
+    pub transform0: Transform,
+    pub transform1: Transform,
+    /// The material of the whole instance, or `MESH_MATERIALS` to keep those of the triangles.
+    pub material_index: u32,
+    pub _pad: [u32; 3],
+}
+
+/// A participating medium (see media.rs). Its extent is a box, rotated around its center: the
+/// whole box for the fog, and the interior of the object within it for an object's medium, whose
+/// bounds it must contain.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Medium {
+    /// The absorption coefficient per unit length, per color channel.
+    pub absorption: [f32; 3],
+    /// The object whose interior the medium fills, or `NO_OBJECT` for the fog.
+    pub object_id: u32,
+    /// The scattering coefficient per unit length, per color channel.
+    pub scattering: [f32; 3],
+    /// The mean cosine of the Henyey-Greenstein phase function, in (-1, 1).
+    pub anisotropy: f32,
+    pub bounds_min: [f32; 3],
+    /// The density grid stretched over the bounds that scales the coefficients, or `NO_GRID`.
+    pub grid: u32,
+    pub bounds_max: [f32; 3],
+    /// An upper bound of the extinction coefficient (absorption + scattering) over the medium.
+    pub majorant: f32,
+    /// A unit quaternion (x, y, z, w).
+    pub rotation: [f32; 4],
+}
+
+/// The object ID of the fog, which isn't bound to any object.
+pub const NO_OBJECT: u32 = u32::MAX;
+/// The grid index of homogeneous media.
+pub const NO_GRID: u32 = u32::MAX;
+/// The material index of mesh instances that use the materials of the mesh.
+pub const MESH_MATERIALS: u32 = u32::MAX;
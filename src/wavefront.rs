@@ -0,0 +1,221 @@
+use wgpu::util::DeviceExt;
+
+// Must match the constants in wavefront.wgsl.
+const QUEUE_EXTEND: u64 = 0;
+const QUEUE_DIFFUSE: u64 = 1;
+const QUEUE_SPECULAR: u64 = 2;
+const QUEUE_CONNECT: u64 = 3;
+const QUEUE_MEDIUM: u64 = 4;
+const QUEUE_COUNT: u64 = 5;
+
+const RAY_STATE_SIZE: u64 = 32;
+const PATH_STATE_SIZE: u64 = 64;
+// The `counts` and `sizes` arrays that precede the queued path indices.
+const QUEUE_HEADER_SIZE: u64 = 2 * QUEUE_COUNT * 4;
+const DISPATCH_ARGS_SIZE: u64 = 12;
+
+/// The kernels and buffers of the wavefront integrator (see wavefront.wgsl). Each pixel owns one
+/// path, and the per-path ray and path state lives in storage buffers between the stages.
+pub struct Wavefront {
+    generate: wgpu::ComputePipeline,
+    prepare_dispatch: wgpu::ComputePipeline,
+    extend: wgpu::ComputePipeline,
+    shade_diffuse: wgpu::ComputePipeline,
+    shade_specular: wgpu::ComputePipeline,
+    shade_medium: wgpu::ComputePipeline,
+    connect: wgpu::ComputePipeline,
+    accumulate: wgpu::ComputePipeline,
+
+    // Like the megakernel's bind groups, these alternate between the two sample textures.
+    bind_groups: [wgpu::BindGroup; 2],
+    dispatch_bind_group: wgpu::BindGroup,
+    queues: wgpu::Buffer,
+    dispatch_args: wgpu::Buffer,
+    workgroup_size: [u32; 2],
+}
+
+impl Wavefront {
+    /// `trace_entries` are the bindings shared with the megakernel, and `create_bind_groups`
+    /// builds the two alternating bind groups for a layout that adds the given entries to them.
+    pub fn new(
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        path_count: u32,
+        workgroup_size: [u32; 2],
+        trace_entries: &[wgpu::BindGroupLayoutEntry],
+        create_bind_groups: impl FnOnce(
+            &wgpu::BindGroupLayout,
+            &[wgpu::BindGroupEntry],
+        ) -> [wgpu::BindGroup; 2],
+    ) -> Wavefront {
+        let path_count = path_count as u64;
+        let storage_buffer = |label, size| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        let rays = storage_buffer("wavefront rays", path_count * RAY_STATE_SIZE);
+        let paths = storage_buffer("wavefront paths", path_count * PATH_STATE_SIZE);
+        let queues = storage_buffer(
+            "wavefront queues",
+            QUEUE_HEADER_SIZE + QUEUE_COUNT * path_count * 4,
+        );
+        let dispatch_args = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("wavefront dispatch args"),
+            contents: &[0; (QUEUE_COUNT * DISPATCH_ARGS_SIZE) as usize],
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+        });
+
+        let layout_entries = [
+            trace_entries,
+            &[
+                read_write_storage_entry(13),
+                read_write_storage_entry(14),
+                read_write_storage_entry(15),
+            ],
+        ]
+        .concat();
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("wavefront"),
+            entries: &layout_entries,
+        });
+        let bind_groups = create_bind_groups(
+            &layout,
+            &[
+                wgpu::BindGroupEntry {
+                    binding: 13,
+                    resource: rays.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 14,
+                    resource: paths.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 15,
+                    resource: queues.as_entire_binding(),
+                },
+            ],
+        );
+
+        // The dispatch arguments are only bound while they are written. Every other stage reads
+        // them as indirect arguments, which can't overlap with a storage binding.
+        let dispatch_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("wavefront dispatch"),
+                entries: &[read_write_storage_entry(0)],
+            });
+        let dispatch_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("wavefront dispatch"),
+            layout: &dispatch_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: dispatch_args.as_entire_binding(),
+            }],
+        });
+
+        let stage_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&layout],
+            ..Default::default()
+        });
+        let prepare_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&layout, &dispatch_layout],
+            ..Default::default()
+        });
+        let pipeline = |entry_point, layout| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(layout),
+                module: shader_module,
+                entry_point,
+            })
+        };
+
+        Wavefront {
+            generate: pipeline("generate_cs", &stage_layout),
+            prepare_dispatch: pipeline("prepare_dispatch_cs", &prepare_layout),
+            extend: pipeline("extend_cs", &stage_layout),
+            shade_diffuse: pipeline("shade_diffuse_cs", &stage_layout),
+            shade_specular: pipeline("shade_specular_cs", &stage_layout),
+            shade_medium: pipeline("shade_medium_cs", &stage_layout),
+            connect: pipeline("connect_cs", &stage_layout),
+            accumulate: pipeline("accumulate_cs", &stage_layout),
+            bind_groups,
+            dispatch_bind_group,
+            queues,
+            dispatch_args,
+            workgroup_size,
+        }
+    }
+
+    /// Records the stages that trace one sample per pixel for frame `frame_count`, with paths of
+    /// up to `max_path_length` bounces, and add it to the accumulated radiance.
+    pub fn encode(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        frame_count: u32,
+        max_path_length: u32,
+        width: u32,
+        height: u32,
+    ) {
+        encoder.clear_buffer(&self.queues, 0, Some(QUEUE_HEADER_SIZE));
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("wavefront pass"),
+            timestamp_writes: None,
+        });
+        pass.set_bind_group(0, &self.bind_groups[(frame_count % 2) as usize], &[]);
+        let [x, y] = self.workgroup_size;
+
+        pass.set_pipeline(&self.generate);
+        pass.dispatch_workgroups(width.div_ceil(x), height.div_ceil(y), 1);
+
+        // Paths that are done simply stop being queued, so the later bounces get cheaper. Once
+        // every path has terminated the remaining dispatches are empty.
+        for _ in 0..max_path_length {
+            self.run_stage(&mut pass, &[(&self.extend, QUEUE_EXTEND)]);
+            self.run_stage(
+                &mut pass,
+                &[
+                    (&self.shade_diffuse, QUEUE_DIFFUSE),
+                    (&self.shade_specular, QUEUE_SPECULAR),
+                    (&self.shade_medium, QUEUE_MEDIUM),
+                ],
+            );
+            self.run_stage(&mut pass, &[(&self.connect, QUEUE_CONNECT)]);
+        }
+
+        pass.set_pipeline(&self.accumulate);
+        pass.dispatch_workgroups(width.div_ceil(x), height.div_ceil(y), 1);
+    }
+
+    // Hands the queues filled so far to `kernels` and dispatches each kernel over its queue.
+    fn run_stage<'a>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        kernels: &[(&'a wgpu::ComputePipeline, u64)],
+    ) {
+        pass.set_pipeline(&self.prepare_dispatch);
+        pass.set_bind_group(1, &self.dispatch_bind_group, &[]);
+        pass.dispatch_workgroups(1, 1, 1);
+        for &(pipeline, queue) in kernels {
+            pass.set_pipeline(pipeline);
+            pass.dispatch_workgroups_indirect(&self.dispatch_args, queue * DISPATCH_ARGS_SIZE);
+        }
+    }
+}
+
+pub fn read_write_storage_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
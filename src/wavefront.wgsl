@@ -0,0 +1,318 @@
+// Wavefront path tracing. Instead of following each path to the end in a single kernel, every
+// bounce is split into stages that run as separate dispatches over queues of paths:
+//
+//   generate  creates a camera ray per pixel (once per frame)
+//   extend    follows every queued ray to its next event and sorts the surface hits by material
+//   shade     samples the next direction, one kernel per kind of material and one for media
+//   connect   traces the shadow rays towards the lights and requeues the surviving paths
+//
+// Each stage compacts its output by appending path indices to a queue with an atomic counter, so
+// the threads of a dispatch all do the same kind of work. This module is compiled together with
+// shaders.wgsl and shares its intersection and shading functions.
+
+struct RayState {
+  origin: vec3f,
+  time: f32,
+  direction: vec3f,
+  _pad0: u32,
+}
+
+struct PathState {
+  throughput: vec3f,
+  rng_state: u32,
+  radiance: vec3f,
+  path_length: u32,
+  // The surface hit by the last extended ray, or a zero normal if it scattered in a medium.
+  normal: vec3f,
+  material_index: u32,
+  // 1 once the path has bounced off a diffuse surface, which regularizes the mirrors it hits.
+  after_diffuse: u32,
+  // The medium the path is in.
+  medium: u32,
+  // The sample that drew the wavelengths of the path in spectral mode (see `path_wavelengths`).
+  wavelength_sample: f32,
+  secondary_terminated: u32,
+}
+
+const QUEUE_EXTEND: u32 = 0u;
+const QUEUE_DIFFUSE: u32 = 1u;
+const QUEUE_SPECULAR: u32 = 2u;
+const QUEUE_CONNECT: u32 = 3u;
+const QUEUE_MEDIUM: u32 = 4u;
+const QUEUE_COUNT: u32 = 5u;
+
+// `counts` receives the paths appended by the running stage. `prepare_dispatch_cs` moves them to
+// `sizes`, which the next stage reads. Queue Q holds its path indices in
+// `items[Q * path_count()..]`.
+struct Queues {
+  counts: array<atomic<u32>, QUEUE_COUNT>,
+  sizes: array<u32, QUEUE_COUNT>,
+  items: array<u32>,
+}
+
+struct DispatchArgs {
+  x: u32,
+  y: u32,
+  z: u32,
+}
+
+@group(0) @binding(13) var<storage, read_write> rays: array<RayState>;
+@group(0) @binding(14) var<storage, read_write> paths: array<PathState>;
+@group(0) @binding(15) var<storage, read_write> queues: Queues;
+@group(1) @binding(0) var<storage, read_write> dispatch_args: array<DispatchArgs, QUEUE_COUNT>;
+
+// The queue stages are one-dimensional and use as many threads per workgroup as the 2D kernels.
+const QUEUE_WORKGROUP_SIZE: u32 = WORKGROUP_SIZE_X * WORKGROUP_SIZE_Y;
+const MAX_WORKGROUPS_PER_DIMENSION: u32 = 65535u;
+
+fn path_count() -> u32 {
+  return uniforms.width * uniforms.height;
+}
+
+fn push(queue: u32, path: u32) {
+  let slot = atomicAdd(&queues.counts[queue], 1u);
+  queues.items[queue * path_count() + slot] = path;
+}
+
+// Returns the path handled by a thread of a queue stage, or `path_count()` if the thread is past
+// the end of the queue. Large queues spill over into the y dimension of the dispatch.
+fn queued_path(queue: u32, workgroup_id: vec3u, workgroup_count: vec3u, local_index: u32) -> u32 {
+  let workgroup = workgroup_id.y * workgroup_count.x + workgroup_id.x;
+  let index = workgroup * QUEUE_WORKGROUP_SIZE + local_index;
+  if index >= queues.sizes[queue] {
+    return path_count();
+  }
+  return queues.items[queue * path_count() + index];
+}
+
+// Restores the state of path `path` that the kernels share through private variables.
+fn resume_path(path: u32, state: PathState) {
+  init_sampler(vec2(path % uniforms.width, path / uniforms.width));
+  rng.state = state.rng_state;
+  wavelengths = path_wavelengths(state.wavelength_sample, state.secondary_terminated == 1u);
+}
+
+fn load_ray(path: u32) -> Ray {
+  let state = rays[path];
+  return Ray(state.origin, state.direction, state.time);
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE_X, WORKGROUP_SIZE_Y)
+fn generate_cs(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= uniforms.width || id.y >= uniforms.height {
+    return;
+  }
+  let pixel = id.xy;
+  if is_converged(pixel) {
+    // The path never enters a queue, so the remaining kernels don't spend any time on it.
+    return;
+  }
+  let path = pixel.x + pixel.y * uniforms.width;
+  init_sampler(pixel);
+
+  // Offset the viewport coordinates of the ray within the pixel.
+  let offset = sample_2d() - 0.5;
+  let time = mix(uniforms.camera.shutter_open, uniforms.camera.shutter_close, sample_1d());
+  let wavelength_sample = sample_1d();
+  let ray = generate_camera_ray(vec2f(pixel) + 0.5 + offset, time);
+
+  rays[path] = RayState(ray.origin, ray.time, ray.direction, 0u);
+  let medium = find_medium(NO_OBJECT);
+  paths[path] = PathState(
+    vec3(1.), rng.state, vec3(0.), 0u, vec3(0.), 0u, 0u, medium, wavelength_sample, 0u,
+  );
+  push(QUEUE_EXTEND, path);
+}
+
+// Runs on a single thread between stages: hands the queues filled by the previous stage to the
+// next one and sizes its indirect dispatches.
+@compute @workgroup_size(1)
+fn prepare_dispatch_cs() {
+  for (var queue = 0u; queue < QUEUE_COUNT; queue += 1u) {
+    let size = atomicExchange(&queues.counts[queue], 0u);
+    queues.sizes[queue] = size;
+    let workgroups = (size + QUEUE_WORKGROUP_SIZE - 1u) / QUEUE_WORKGROUP_SIZE;
+    let x = min(workgroups, MAX_WORKGROUPS_PER_DIMENSION);
+    let y = select(0u, (workgroups + x - 1u) / x, x > 0u);
+    dispatch_args[queue] = DispatchArgs(x, y, 1u);
+  }
+}
+
+@compute @workgroup_size(QUEUE_WORKGROUP_SIZE)
+fn extend_cs(
+  @builtin(workgroup_id) workgroup_id: vec3u,
+  @builtin(num_workgroups) workgroup_count: vec3u,
+  @builtin(local_invocation_index) local_index: u32,
+) {
+  let path = queued_path(QUEUE_EXTEND, workgroup_id, workgroup_count, local_index);
+  if path >= path_count() {
+    return;
+  }
+  let ray = load_ray(path);
+  var state = paths[path];
+  resume_path(path, state);
+  let event = trace_ray(ray, state.medium, state.throughput);
+  state.rng_state = rng.state;
+  state.throughput = event.throughput;
+  state.medium = event.medium;
+  if state.path_length == 0u {
+    store_first_hit(vec2(path % uniforms.width, path / uniforms.width), ray, surface_hit(event));
+  }
+  if event.kind == EVENT_ESCAPED {
+    // If no intersection was found, add the color of the sky and terminate the path.
+    let sky = radiance_to_rgb(state.throughput * sky_radiance(ray));
+    let escaped = clamp_escaped_light(state.path_length, sky);
+    state.radiance += escaped;
+    add_escaped_light(path, state.path_length, escaped);
+  }
+  if event.kind == EVENT_ESCAPED || event.kind == EVENT_ABSORBED {
+    paths[path] = state;
+    return;
+  }
+
+  // The ray is no longer needed past the event, so it now starts there for the shading stages.
+  rays[path].origin = point_on_ray(ray, event.hit.t);
+  state.normal = event.hit.normal;
+  state.material_index = event.hit.material_index;
+  paths[path] = state;
+  if event.kind == EVENT_MEDIUM {
+    push(QUEUE_MEDIUM, path);
+  } else {
+    let specular = materials[event.hit.material_index].specular == 1u;
+    push(select(QUEUE_DIFFUSE, QUEUE_SPECULAR, specular), path);
+  }
+}
+
+fn shade(path: u32) {
+  var state = paths[path];
+  resume_path(path, state);
+  start_bounce(state.path_length);
+  let ray = load_ray(path);
+
+  let material = materials[state.material_index];
+  if uniforms.probe_preview == 1u && state.after_diffuse == 0u && material.specular != 1u {
+    // The probes stand in for the rest of the path, which ends here.
+    if state.path_length == 0u {
+      let pixel = vec2(path % uniforms.width, path / uniforms.width);
+      primary_light = primary_resampled_light(pixel, ray.origin);
+    }
+    let lit = probe_lit_surface(ray.origin, state.normal, material, ray.time, state.medium);
+    let from_probes = clamp_escaped_light(
+      state.path_length + 2u, radiance_to_rgb(state.throughput * lit),
+    );
+    state.radiance += from_probes;
+    add_escaped_light(path, state.path_length + 2u, from_probes);
+    paths[path] = state;
+    return;
+  }
+  let reflected = sample_material(ray.direction, state.normal, material, state.after_diffuse == 1u);
+  if material.specular != 1u {
+    state.after_diffuse = 1u;
+  }
+  rays[path].direction = reflected.direction;
+
+  // The light arriving at the hit is folded into the throughput by the connect stage.
+  state.throughput *= spectrum(material.color) * reflected.weight;
+  state.rng_state = rng.state;
+  state.secondary_terminated = u32(wavelengths.secondary_terminated);
+  paths[path] = state;
+  push(QUEUE_CONNECT, path);
+}
+
+@compute @workgroup_size(QUEUE_WORKGROUP_SIZE)
+fn shade_diffuse_cs(
+  @builtin(workgroup_id) workgroup_id: vec3u,
+  @builtin(num_workgroups) workgroup_count: vec3u,
+  @builtin(local_invocation_index) local_index: u32,
+) {
+  let path = queued_path(QUEUE_DIFFUSE, workgroup_id, workgroup_count, local_index);
+  if path < path_count() {
+    shade(path);
+  }
+}
+
+@compute @workgroup_size(QUEUE_WORKGROUP_SIZE)
+fn shade_specular_cs(
+  @builtin(workgroup_id) workgroup_id: vec3u,
+  @builtin(num_workgroups) workgroup_count: vec3u,
+  @builtin(local_invocation_index) local_index: u32,
+) {
+  let path = queued_path(QUEUE_SPECULAR, workgroup_id, workgroup_count, local_index);
+  if path < path_count() {
+    shade(path);
+  }
+}
+
+// Scattering in a medium needs the incoming direction for the phase function, so its direct
+// lighting is gathered here rather than in the connect stage.
+@compute @workgroup_size(QUEUE_WORKGROUP_SIZE)
+fn shade_medium_cs(
+  @builtin(workgroup_id) workgroup_id: vec3u,
+  @builtin(num_workgroups) workgroup_count: vec3u,
+  @builtin(local_invocation_index) local_index: u32,
+) {
+  let path = queued_path(QUEUE_MEDIUM, workgroup_id, workgroup_count, local_index);
+  if path >= path_count() {
+    return;
+  }
+  var state = paths[path];
+  resume_path(path, state);
+  start_bounce(state.path_length);
+  let ray = load_ray(path);
+
+  let scattered = scatter_medium(ray, ray.origin, state.medium);
+  rays[path].direction = scattered.ray.direction;
+  state.throughput *= scattered.attenuation;
+  state.after_diffuse = 1u;
+  state.rng_state = rng.state;
+  paths[path] = state;
+  push(QUEUE_CONNECT, path);
+}
+
+@compute @workgroup_size(QUEUE_WORKGROUP_SIZE)
+fn connect_cs(
+  @builtin(workgroup_id) workgroup_id: vec3u,
+  @builtin(num_workgroups) workgroup_count: vec3u,
+  @builtin(local_invocation_index) local_index: u32,
+) {
+  let path = queued_path(QUEUE_CONNECT, workgroup_id, workgroup_count, local_index);
+  if path >= path_count() {
+    return;
+  }
+  let ray = load_ray(path);
+  var state = paths[path];
+  resume_path(path, state);
+  // Points in media were lit by their shading stage, and glass isn't lit.
+  let lit = any(state.normal != vec3(0.)) && !is_dielectric(materials[state.material_index]);
+  if lit {
+    if state.path_length == 0u {
+      let pixel = vec2(path % uniforms.width, path / uniforms.width);
+      primary_light = primary_resampled_light(pixel, ray.origin);
+    }
+    let radiance = direct_lighting(ray.origin, state.normal, ray.time, state.medium);
+    state.throughput *= radiance + spectrum(AMBIENT);
+  }
+
+  let survival_weight = russian_roulette(state.path_length, state.throughput);
+  state.throughput *= survival_weight;
+  state.rng_state = rng.state;
+
+  state.path_length += 1u;
+  paths[path] = state;
+  if survival_weight > 0. && state.path_length < uniforms.max_path_length {
+    push(QUEUE_EXTEND, path);
+  }
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE_X, WORKGROUP_SIZE_Y)
+fn accumulate_cs(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= uniforms.width || id.y >= uniforms.height {
+    return;
+  }
+  let pixel = id.xy;
+  if is_converged(pixel) {
+    skip_pixel(pixel);
+  } else {
+    add_sample(pixel, paths[pixel.x + pixel.y * uniforms.width].radiance);
+  }
+}